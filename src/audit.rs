@@ -0,0 +1,68 @@
+//! ツール呼び出しの監査ログ
+//!
+//! ツールを呼び出すたびに、次の形式のJSONを1行ずつ追記します。
+//! `{"timestamp":"...","tool":"...","source":"...","arguments":{...},"success":true,"result_size":42,"duration_ms":12}`
+//! 失敗した場合は`error`にエラーの内容が入ります。
+
+use std::{io::Write, time::Duration};
+use chrono::Local;
+use serde_json::{json, Value};
+use crate::verbosity::status;
+
+
+const REDACTED: &str = "[REDACTED]";
+
+
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: String,
+    /// 値を伏せる引数のフィールド名（大文字小文字を区別しない）
+    redact_fields: Vec<String>,
+}
+
+
+impl AuditLog {
+    pub fn new(path: &str, redact_fields: &[String]) -> Self {
+        AuditLog {
+            path: path.to_string(),
+            redact_fields: redact_fields.iter().map(|field| field.to_lowercase()).collect(),
+        }
+    }
+
+    pub fn record(&self, tool: &str, source: &str, arguments: &Value, result: Result<usize, &str>, duration: Duration) {
+        let mut entry = json!({
+            "timestamp": Local::now().to_rfc3339(),
+            "tool": tool,
+            "source": source,
+            "arguments": self.redact(arguments),
+            "success": result.is_ok(),
+            "duration_ms": duration.as_millis() as u64,
+        });
+        match result {
+            Ok(result_size) => entry["result_size"] = json!(result_size),
+            Err(error) => entry["error"] = json!(error),
+        }
+
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(&self.path);
+        let result = file.and_then(|mut file| writeln!(file, "{}", entry));
+        if let Err(e) = result {
+            status!("監査ログに書き込めません: {} {}", self.path, e);
+        }
+    }
+
+    fn redact(&self, value: &Value) -> Value {
+        match value {
+            Value::Object(map) => Value::Object(map.iter()
+                .map(|(key, value)| {
+                    if self.redact_fields.contains(&key.to_lowercase()) {
+                        (key.clone(), json!(REDACTED))
+                    } else {
+                        (key.clone(), self.redact(value))
+                    }
+                })
+                .collect()),
+            Value::Array(values) => Value::Array(values.iter().map(|value| self.redact(value)).collect()),
+            _ => value.clone(),
+        }
+    }
+}
@@ -0,0 +1,227 @@
+//! 任意精度の計算
+//!
+//! 整数・小数と四則演算、剰余（`%`）、整数の累乗（`^`）、括弧のみからなる式を、
+//! 有理数として誤差なく計算します。関数（`sum`、`sin`など）や非整数の累乗を含む式は
+//! 扱わないため、呼び出し側でf64の計算にフォールバックします。
+//! 計算結果の桁数が大きくなりすぎないよう、累乗の指数は`MAX_EXPONENT`まで、累乗の結果は`MAX_POWER_BITS`ビットまでに制限しています。
+
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{Signed, ToPrimitive, Zero};
+
+
+const MAX_EXPONENT: u32 = 10000;
+/// 累乗の結果の分子・分母の最大ビット数（約30万桁）。`((9^10000)^10000)^10000`のような式で計算が終わらなくなるのを防ぐ
+const MAX_POWER_BITS: u64 = 1 << 20;
+
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(BigRational),
+    Op(char),
+    LParen,
+    RParen,
+}
+
+
+/// 式を誤差なく計算します。任意精度で扱えない式の場合は`None`を返します。
+pub fn evaluate_exact(formula: &str) -> Option<Result<String, String>> {
+    let tokens = tokenize(formula)?;
+    let mut parser = Parser { tokens, pos: 0 };
+    let value = match parser.parse_expr() {
+        Ok(value) => value?,
+        Err(e) => return Some(Err(e)),
+    };
+    if parser.pos != parser.tokens.len() {
+        return None;
+    }
+    Some(Ok(format_rational(&value)))
+}
+
+
+fn tokenize(formula: &str) -> Option<Vec<Token>> {
+    let chars: Vec<char> = formula.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() || c == '.' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.' || chars[i] == '_') {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().filter(|c| **c != '_').collect();
+            tokens.push(Token::Number(parse_decimal(&text)?));
+        } else if "+-*/%^".contains(c) {
+            tokens.push(Token::Op(c));
+            i += 1;
+        } else if c == '(' {
+            tokens.push(Token::LParen);
+            i += 1;
+        } else if c == ')' {
+            tokens.push(Token::RParen);
+            i += 1;
+        } else {
+            // 関数や変数は任意精度では扱わない
+            return None;
+        }
+    }
+    Some(tokens)
+}
+
+
+fn parse_decimal(text: &str) -> Option<BigRational> {
+    let (integer, fraction) = text.split_once('.').unwrap_or((text, ""));
+    if fraction.contains('.') || (integer.is_empty() && fraction.is_empty()) {
+        return None;
+    }
+    let digits = format!("{}{}", integer, fraction);
+    let numerator: BigInt = digits.parse().ok()?;
+    let denominator = num_traits::pow(BigInt::from(10), fraction.len());
+    Some(BigRational::new(numerator, denominator))
+}
+
+
+/// 演算子の優先順位: `+ -` < `* / %` < 単項`-` < `^`（右結合）
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+/// 外側のResultは計算エラー（ゼロ除算など）、内側のOptionは任意精度で扱えない式を表す
+type ParseResult = Result<Option<BigRational>, String>;
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn parse_expr(&mut self) -> ParseResult {
+        let Some(mut value) = self.parse_term()? else {
+            return Ok(None);
+        };
+        while let Some(Token::Op(op @ ('+' | '-'))) = self.peek().cloned() {
+            self.pos += 1;
+            let Some(rhs) = self.parse_term()? else {
+                return Ok(None);
+            };
+            value = if op == '+' { value + rhs } else { value - rhs };
+        }
+        Ok(Some(value))
+    }
+
+    fn parse_term(&mut self) -> ParseResult {
+        let Some(mut value) = self.parse_unary()? else {
+            return Ok(None);
+        };
+        while let Some(Token::Op(op @ ('*' | '/' | '%'))) = self.peek().cloned() {
+            self.pos += 1;
+            let Some(rhs) = self.parse_unary()? else {
+                return Ok(None);
+            };
+            if op != '*' && rhs.is_zero() {
+                return Err("ゼロで除算することはできません".to_string());
+            }
+            value = match op {
+                '*' => value * rhs,
+                '/' => value / rhs,
+                _ => value % rhs,
+            };
+        }
+        Ok(Some(value))
+    }
+
+    fn parse_unary(&mut self) -> ParseResult {
+        match self.peek() {
+            Some(Token::Op('-')) => {
+                self.pos += 1;
+                Ok(self.parse_unary()?.map(|value| -value))
+            }
+            Some(Token::Op('+')) => {
+                self.pos += 1;
+                self.parse_unary()
+            }
+            _ => self.parse_power(),
+        }
+    }
+
+    fn parse_power(&mut self) -> ParseResult {
+        let Some(base) = self.parse_primary()? else {
+            return Ok(None);
+        };
+        if self.peek() != Some(&Token::Op('^')) {
+            return Ok(Some(base));
+        }
+        self.pos += 1;
+        let Some(exponent) = self.parse_unary()? else {
+            return Ok(None);
+        };
+
+        // 整数の指数のみ任意精度で計算する
+        if !exponent.is_integer() {
+            return Ok(None);
+        }
+        let negative = exponent.is_negative();
+        let Some(exponent) = exponent.to_integer().abs().to_u32().filter(|e| *e <= MAX_EXPONENT) else {
+            return Ok(None);
+        };
+        // 計算する前に結果の大きさを見積もり、大きすぎる場合はf64の計算に任せる
+        let base_bits = base.numer().bits().max(base.denom().bits());
+        if base_bits.saturating_mul(exponent as u64) > MAX_POWER_BITS {
+            return Ok(None);
+        }
+        let value = num_traits::pow(base, exponent as usize);
+        if negative {
+            if value.is_zero() {
+                return Err("ゼロで除算することはできません".to_string());
+            }
+            return Ok(Some(value.recip()));
+        }
+        Ok(Some(value))
+    }
+
+    fn parse_primary(&mut self) -> ParseResult {
+        match self.peek().cloned() {
+            Some(Token::Number(value)) => {
+                self.pos += 1;
+                Ok(Some(value))
+            }
+            Some(Token::LParen) => {
+                self.pos += 1;
+                let value = self.parse_expr()?;
+                if self.peek() != Some(&Token::RParen) {
+                    return Ok(None);
+                }
+                self.pos += 1;
+                Ok(value)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+
+/// 整数はそのまま、分数は近似値を添えて表示する
+fn format_rational(value: &BigRational) -> String {
+    if value.is_integer() {
+        return value.to_integer().to_string();
+    }
+    match value.to_f64() {
+        Some(approx) => format!("{} (≈ {})", value, approx),
+        None => value.to_string(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn huge_power_falls_back() {
+        assert_eq!(evaluate_exact("((9^10000)^10000)^10000"), None);
+        assert_eq!(evaluate_exact("2^10000").map(|result| result.unwrap().len()), Some(3011));
+    }
+}
@@ -1,139 +1,2149 @@
+use std::{collections::HashMap, path::Path, sync::Arc, time::Duration};
+use base64::{prelude::BASE64_STANDARD, Engine};
 use fasteval::Evaler;
-use ollama_rs::{coordinator::Coordinator, generation::chat::{request::ChatMessageRequest, ChatMessage}, Ollama};
+use ollama_rs::{generation::{images::Image, chat::{ChatMessage, ChatMessageResponse, MessageRole}, completion::request::GenerationRequest, parameters::KeepAlive, tools::Tool}, models::ModelOptions, Ollama};
 use regex::Regex;
 use chrono::Local;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
+use crate::audit::AuditLog;
+use crate::client::{cancellable, is_tools_unsupported, ApiFlavor, Cancelled, OllamaClient, PullProgress};
+use crate::external::ExternalTool;
+use crate::mcp::McpTool;
+use crate::memory::MemoryStore;
+use crate::render::{OutputRenderer, StringRenderer, TerminalRenderer, ThinkTags, ThinkingSplitter, TOOL_PROGRESS_INTERVAL};
+use crate::tools::{BuiltinTool, ToolDefinition, ToolOutput, ToolPolicy, ToolRegistry};
+use crate::transcript::{Transcript, TranscriptMessage, TranscriptToolCall};
+
+/// 組み込みツールの名前
+pub const BUILTIN_TOOL_NAMES: &[&str] = &["get_datetime_now", "calculator", "get_conversation_summary", "fetch_url", "remember", "recall"];
+
+/// 応答中のフェンス付きコードブロック
+#[derive(Debug, Clone)]
+pub struct CodeBlock {
+    pub lang: String,
+    pub content: String,
+}
+
+/// 応答の生成にかかった時間とトークン数
+///
+/// ツールを呼び出した場合は、1回の応答のために送信した全てのリクエストの合計です。
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResponseStats {
+    pub prompt_tokens: u64,
+    pub eval_tokens: u64,
+    pub eval_duration: Duration,
+    pub total_duration: Duration,
+}
+
+impl ResponseStats {
+    /// 1秒あたりの生成トークン数
+    pub fn tokens_per_second(&self) -> Option<f64> {
+        let seconds = self.eval_duration.as_secs_f64();
+        (seconds > 0.0).then(|| self.eval_tokens as f64 / seconds)
+    }
+
+    fn add(&mut self, response: &ChatMessageResponse) {
+        if let Some(final_data) = response.final_data.as_ref() {
+            self.prompt_tokens += final_data.prompt_eval_count;
+            self.eval_tokens += final_data.eval_count;
+            self.eval_duration += Duration::from_nanos(final_data.eval_duration);
+            self.total_duration += Duration::from_nanos(final_data.total_duration);
+        }
+    }
+}
+
+/// 履歴のメッセージに付随する、モデルへ送信しない情報
+#[derive(Debug, Clone, Default)]
+struct MessageDetails {
+    /// thinkingモデルの思考過程
+    thinking: Option<String>,
+    /// メッセージを追加した日時（RFC 3339）。ファイルから読み込んだ履歴など、分からない場合はNone
+    timestamp: Option<String>,
+    /// 応答を生成する間に呼び出したツール
+    tool_calls: Vec<TranscriptToolCall>,
+}
+
+impl MessageDetails {
+    fn now(thinking: Option<String>) -> Self {
+        MessageDetails { thinking, timestamp: Some(Local::now().to_rfc3339()), tool_calls: Vec::new() }
+    }
+}
 
 pub struct Chat {
     context: Ollama,
+    client: OllamaClient,
     history: Vec<ChatMessage>,
+    /// 履歴の各メッセージに対応する、送信しない情報（思考過程、日時、呼び出したツール）
+    ///
+    /// コンテキスト長を節約するため、送信する履歴からは思考過程を取り除き、ここに別に保持する。
+    /// 要素は`history`と同じ位置のメッセージに対応する。
+    details: Vec<MessageDetails>,
     tool_model: String,
     vision_model: String,
     thinking_regex: Regex,
+    think_tags: ThinkTags,
+    num_thread: Option<u32>,
+    num_gpu: Option<u32>,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    top_k: Option<u32>,
+    title: Option<String>,
+    /// タイトルの生成に使用するモデル。未設定の場合はtool_modelを使用する
+    title_model: Option<String>,
+    /// 生成するタイトルの最大文字数
+    title_max_len: usize,
+    seed: i32,
+    external_tools: Vec<ExternalTool>,
+    mcp_tools: Vec<McpTool>,
+    auto_compact: bool,
+    compact_budget: usize,
+    /// 送信する会話の上限（推定トークン数）
+    context_budget: Option<usize>,
+    /// モデルのコンテキストウィンドウのトークン数
+    num_ctx: Option<u64>,
+    renderer: Box<dyn OutputRenderer>,
+    system_prompt: Option<String>,
+    strip_system_echo: bool,
+    echo_prompt: bool,
+    precise_calculator: bool,
+    /// 登録する組み込みツールの名前。Noneの場合は全て登録する
+    enabled_tools: Option<Vec<String>>,
+    /// `fetch_url`ツールで共有するHTTPクライアント
+    http: reqwest::Client,
+    /// `fetch_url`ツールが返す本文の最大バイト数
+    fetch_max_bytes: usize,
+    /// `fetch_url`ツールでlocalhostやプライベートアドレスへの接続を許可する
+    fetch_allow_private: bool,
+    /// `remember`と`recall`ツールで共有する記憶
+    memory: Arc<MemoryStore>,
+    audit_log: Option<AuditLog>,
+    /// モデルが呼び出したツールの実行を待つ最大時間
+    tool_timeout: Duration,
+    /// 1回の応答でツールを呼び出せる回数（モデルの応答からツールを実行するまでを1回とする）
+    max_tool_rounds: usize,
+    /// ツールごとの、呼び出された時の扱い（`--tool-policy`）
+    tool_policies: HashMap<String, ToolPolicy>,
+    /// ツールの実行をユーザーに確認できるか
+    interactive: bool,
+    stream: bool,
+    hide_thinking: bool,
+    /// 生成中の応答。生成が中断された場合に履歴へ残すために保持する
+    partial_response: String,
+    /// 取り消すと生成中の応答を中断する
+    cancel: CancellationToken,
+    /// 本文が空の応答を1回だけ再生成する
+    retry_empty: bool,
+    /// 応答を同時に生成させて比較するモデル。空の場合は比較しない
+    compare_models: Vec<String>,
+    /// 生成中の応答で呼び出したツール。応答を履歴に追加する際に`details`へ移す
+    turn_tool_calls: Vec<TranscriptToolCall>,
+    /// サーバーへ送信せず、リクエストを表示する
+    dry_run: bool,
+    /// モデルごとの、ツールに対応しているかの記録。ツールを渡して失敗したモデルにはツールを渡さない
+    supports_tools: HashMap<String, bool>,
+    /// 最後の応答の統計情報。サーバーが統計情報を返さない場合（OpenAI互換のAPIなど）はNone
+    last_stats: Option<ResponseStats>,
 }
 
 impl Chat {
-    pub fn new(host: &str, port: u16, tool_model: &str, vision_model: &str) -> Self {
-        let url = format!("http://{}", host);
-        let thinking_regex = Regex::new(r"(?s)<think>\s*(.*?)\s*(?:</think>|\z)").unwrap();
+    /// `base_url`はOllamaのURL（例: `http://localhost:11434/`）で、末尾は`/`で終わる必要があります。
+    pub fn new(base_url: &reqwest::Url, tool_model: &str, vision_model: &str) -> Self {
+        let think_tags = ThinkTags::default();
+        let thinking_regex = build_thinking_regex(&think_tags);
 
-        let context = Ollama::new(url, port);
+        let client = OllamaClient::new(base_url.as_str());
+        let context = Ollama::from_url(base_url.clone());
         let history = Vec::new();
 
         let tool_model = tool_model.to_string();
         let vision_model = vision_model.to_string();
 
-        Self { context, history, tool_model, vision_model, thinking_regex }
+        let seed = generate_seed();
+
+        Self { context, client, history, details: Vec::new(), tool_model, vision_model, thinking_regex, think_tags, num_thread: None, num_gpu: None, temperature: None, top_p: None, top_k: None, title: None, title_model: None, title_max_len: 30, seed, external_tools: Vec::new(), mcp_tools: Vec::new(), auto_compact: false, compact_budget: 8192, context_budget: None, num_ctx: None, renderer: Box::new(TerminalRenderer), system_prompt: None, strip_system_echo: false, echo_prompt: false, precise_calculator: false, enabled_tools: None, http: build_fetch_client(false), fetch_max_bytes: 100_000, fetch_allow_private: false, memory: Arc::new(MemoryStore::new(None)), audit_log: None, tool_timeout: Duration::from_secs(60), max_tool_rounds: 5, tool_policies: HashMap::new(), interactive: true, stream: true, hide_thinking: false, partial_response: String::new(), cancel: CancellationToken::new(), retry_empty: false, compare_models: Vec::new(), turn_tool_calls: Vec::new(), dry_run: false, supports_tools: HashMap::new(), last_stats: None }
+    }
+
+    /// 会話の先頭に付与するシステムプロンプトを設定します。
+    ///
+    /// システムプロンプトは履歴とは別に保持し、送信時に常に最初のメッセージとして付与します。
+    /// そのため`clear_history`や履歴の削除・圧縮を行っても失われません。
+    pub fn set_system_prompt(&mut self, system_prompt: Option<String>) {
+        self.system_prompt = system_prompt.filter(|prompt| !prompt.trim().is_empty());
+    }
+
+    pub fn get_system_prompt(&self) -> Option<&str> {
+        self.system_prompt.as_deref()
+    }
+
+    /// 応答の先頭でシステムプロンプトが繰り返された場合に取り除くかを設定します。
+    pub fn set_strip_system_echo(&mut self, strip_system_echo: bool) {
+        self.strip_system_echo = strip_system_echo;
+    }
+
+    /// 応答をストリーミングで表示するかを設定します。
+    pub fn set_stream(&mut self, stream: bool) {
+        self.stream = stream;
+    }
+
+    /// 応答を生成せず、送信するリクエストをJSONで表示するかを設定します。
+    ///
+    /// 履歴の削除やシステムプロンプトの付与を行った後の、実際に送信される内容を確認できます。
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// thinkingモデルの思考過程を表示しないかを設定します。
+    pub fn set_hide_thinking(&mut self, hide_thinking: bool) {
+        self.hide_thinking = hide_thinking;
+    }
+
+    /// 思考過程の開始と終了を示すタグを設定します。
+    pub fn set_think_tags(&mut self, tags: ThinkTags) {
+        self.thinking_regex = build_thinking_regex(&tags);
+        self.think_tags = tags;
+    }
+
+    /// ツール呼び出しを記録する監査ログを設定します。
+    pub fn set_audit_log(&mut self, audit_log: Option<AuditLog>) {
+        self.audit_log = audit_log;
+    }
+
+    /// 計算ツールで整数・有理数の式を任意精度で計算するかを設定します。
+    ///
+    /// 誤差のない結果が得られる一方、桁数の大きな計算では時間がかかります。
+    pub fn set_precise_calculator(&mut self, precise_calculator: bool) {
+        self.precise_calculator = precise_calculator;
+    }
+
+    /// 登録する組み込みツールを、名前の一覧で制限します。空の一覧を指定すると組み込みツールを使用しません。
+    ///
+    /// 外部ツールとMCPツールは制限しません。組み込みツールの名前は`BUILTIN_TOOL_NAMES`を参照してください。
+    pub fn set_enabled_tools(&mut self, enabled_tools: Vec<String>) {
+        self.enabled_tools = Some(enabled_tools);
+    }
+
+    /// `fetch_url`ツールが返す本文の最大バイト数と、localhostやプライベートアドレスへの接続を許可するかを設定します。
+    pub fn set_fetch_options(&mut self, max_bytes: usize, allow_private: bool) {
+        self.fetch_max_bytes = max_bytes;
+        self.fetch_allow_private = allow_private;
+        self.http = build_fetch_client(allow_private);
+    }
+
+    /// 応答の前にユーザーの入力を出力先へ渡すかを設定します。
+    pub fn set_echo_prompt(&mut self, echo_prompt: bool) {
+        self.echo_prompt = echo_prompt;
+    }
+
+    /// 生成結果の出力先を設定します。
+    pub fn set_renderer(&mut self, renderer: Box<dyn OutputRenderer>) {
+        self.renderer = renderer;
+    }
+
+    /// 履歴がコンテキストの予算（推定トークン数）を超えた場合に、古い会話を要約して圧縮するかを設定します。
+    pub fn set_auto_compact(&mut self, auto_compact: bool, compact_budget: usize) {
+        self.auto_compact = auto_compact;
+        self.compact_budget = compact_budget;
+    }
+
+    /// 送信する会話の上限（推定トークン数）を設定します。
+    ///
+    /// 超える場合は、システムメッセージと今回の入力を残して古いメッセージから削除します。
+    pub fn set_context_budget(&mut self, tokens: usize) {
+        self.context_budget = Some(tokens);
+    }
+
+    /// 履歴の推定トークン数を返します。
+    pub fn estimate_tokens(&self) -> usize {
+        self.history.iter().map(|message| estimate_tokens(&message.content)).sum()
+    }
+
+    /// モデルの読み込みを待つ最大時間を設定します。
+    pub fn set_model_load_timeout(&mut self, timeout: Duration) {
+        self.client.set_model_load_timeout(timeout);
+    }
+
+    /// `remember`と`recall`ツールの記憶を保存するファイルを設定します。Noneの場合は起動している間のみ記憶します。
+    pub fn set_memory_file(&mut self, path: Option<std::path::PathBuf>) {
+        self.memory = Arc::new(MemoryStore::new(path));
+    }
+
+    /// 記憶を保存するファイルを返します。
+    pub fn get_memory_file(&self) -> Option<&Path> {
+        self.memory.path()
+    }
+
+    /// 本文が空の応答（思考過程のみの応答など）を、シードを変えて1回だけ再生成するかを設定します。
+    pub fn set_retry_empty(&mut self, retry_empty: bool) {
+        self.retry_empty = retry_empty;
+    }
+
+    /// 応答を同時に生成させて比較するモデルを設定します。空の場合は比較せず、tool_modelで応答します。
+    pub fn set_compare_models(&mut self, models: Vec<String>) {
+        self.compare_models = models;
+    }
+
+    pub fn get_compare_models(&self) -> &[String] {
+        &self.compare_models
+    }
+
+    /// モデルが呼び出したツールの実行を待つ最大時間を設定します。超えた場合はその旨をツールの結果としてモデルに返します。
+    pub fn set_tool_timeout(&mut self, timeout: Duration) {
+        self.tool_timeout = timeout;
+    }
+
+    /// 1回の応答でツールを呼び出せる回数を設定します。超えた場合は、ツールを使わずに回答するようモデルに伝えます。
+    pub fn set_max_tool_rounds(&mut self, max_tool_rounds: usize) {
+        self.max_tool_rounds = max_tool_rounds;
+    }
+
+    /// ツールごとの、呼び出された時の扱い（確認せずに実行、確認してから実行、実行しない）を設定します。
+    /// ここで指定したツールは、MCPや外部ツールの設定ファイルの指定より優先します。
+    pub fn set_tool_policies(&mut self, policies: HashMap<String, ToolPolicy>) {
+        self.tool_policies = policies;
+    }
+
+    /// 生成を中断するためのトークンを設定します。
+    ///
+    /// 取り消すと、応答の受信やツールの実行を止め、それまでに受け取った応答を履歴に残してターンを終えます。
+    /// 取り消したトークンは取り消されたままのため、ターンごとに新しいトークンを設定してください。
+    pub fn set_cancellation_token(&mut self, cancel: CancellationToken) {
+        self.cancel = cancel;
+    }
+
+    /// ツールの実行をユーザーに確認できるかを設定します。確認できない場合、`confirm`のツールは実行しません。
+    pub fn set_interactive(&mut self, interactive: bool) {
+        self.interactive = interactive;
+    }
+
+    /// サーバーにあるモデルの一覧を取得します。
+    pub async fn list_models(&self) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+        self.client.list_models().await
+    }
+
+    /// モデルをサーバーにダウンロードします。進捗は`on_progress`へ渡します。
+    pub async fn pull_model(&self, model: &str, on_progress: &mut dyn FnMut(&PullProgress)) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.client.pull_model(model, on_progress).await
+    }
+
+    /// モデルをサーバーのメモリに読み込みます。最初の応答で読み込みを待たずに済みます。
+    pub async fn load_model(&self, model: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.client.load_model(model).await
+    }
+
+    /// モデルが対応するコンテキストの長さを取得します。取得できない場合はNoneを返します。
+    pub async fn context_length(&self, model: &str) -> Result<Option<u64>, Box<dyn std::error::Error + Send + Sync>> {
+        self.client.context_length(model).await
+    }
+
+    /// 以降の応答の生成に使用するモデルを変更します。履歴はそのまま引き継ぎます。
+    pub fn set_tool_model(&mut self, model: String) {
+        self.tool_model = model;
+    }
+
+    pub fn get_tool_model(&self) -> &str {
+        &self.tool_model
+    }
+
+    pub fn get_vision_model(&self) -> &str {
+        &self.vision_model
+    }
+
+    /// 接続先のAPIの形式（OllamaまたはOpenAI互換）を設定します。
+    pub fn set_api_flavor(&mut self, flavor: ApiFlavor) {
+        self.client.set_api_flavor(flavor);
+    }
+
+    /// Ollamaへのリクエストのタイムアウトを設定します。
+    pub fn set_timeouts(&mut self, connect_timeout: Duration, timeout: Duration, stream_timeout: Duration) {
+        self.client.set_timeouts(connect_timeout, timeout, stream_timeout);
+    }
+
+    /// Ollamaへの全てのリクエストに付与するHTTPヘッダー（認証など）を設定します。
+    pub fn set_headers(&mut self, headers: reqwest::header::HeaderMap) {
+        // モデル情報の取得とコード補完で使う`Ollama`にも同じヘッダーを付与する
+        let url = self.context.url().clone();
+        let port = url.port_or_known_default().unwrap_or(80);
+        let http = reqwest::Client::builder().default_headers(headers.clone()).build().unwrap_or_default();
+        self.context = Ollama::new_with_client(url, port, http);
+        self.client.set_headers(headers);
+    }
+
+    /// Ollamaへのリクエストが一時的に失敗した場合に再試行する回数を設定します。
+    pub fn set_max_retries(&mut self, max_retries: u32) {
+        self.client.set_retry(max_retries, Duration::from_millis(500));
+    }
+
+    /// 応答後にモデルをメモリに保持する時間を設定します。未設定の場合はOllamaの既定値（5分）で解放されます。
+    pub fn set_keep_alive(&mut self, keep_alive: Option<KeepAlive>) {
+        self.client.set_keep_alive(keep_alive);
+    }
+
+    /// ストリーミング中にツール呼び出しを受け取った時点で、残りの応答を待たずにツールを実行するかを設定します。
+    pub fn set_stop_on_tool_call(&mut self, stop_on_tool_call: bool) {
+        self.client.set_stop_on_tool_call(stop_on_tool_call);
+    }
+
+    /// 外部プログラムで実装されたツールを設定します。
+    pub fn set_external_tools(&mut self, external_tools: Vec<ExternalTool>) {
+        self.external_tools = external_tools;
+    }
+
+    /// 接続中のMCPサーバーが提供するツールを設定します。
+    pub fn set_mcp_tools(&mut self, mcp_tools: Vec<McpTool>) {
+        self.mcp_tools = mcp_tools;
+    }
+
+    /// 生成に使用するシードを設定します。未設定の場合はセッションごとにランダムなシードを使用します。
+    pub fn set_seed(&mut self, seed: i32) {
+        self.seed = seed;
+    }
+
+    pub fn get_seed(&self) -> i32 {
+        self.seed
+    }
+
+    /// パフォーマンス関連のオプションを設定します。Ollama側で無視される場合があります。
+    pub fn set_performance_options(&mut self, num_thread: Option<u32>, num_gpu: Option<u32>) {
+        self.num_thread = num_thread;
+        self.num_gpu = num_gpu;
+    }
+
+    /// コンテキストウィンドウのトークン数を設定します。未設定の場合はOllamaの既定値を使用します。
+    ///
+    /// 既定値は小さい場合があり、超えた分の履歴はサーバー側で切り捨てられます。
+    pub fn set_num_ctx(&mut self, num_ctx: Option<u64>) {
+        self.num_ctx = num_ctx;
+    }
+
+    pub fn get_num_ctx(&self) -> Option<u64> {
+        self.num_ctx
+    }
+
+    /// サンプリングのオプションを設定します。未設定の項目はモデルの既定値を使用します。
+    pub fn set_sampling_options(&mut self, temperature: Option<f32>, top_p: Option<f32>, top_k: Option<u32>) {
+        self.temperature = temperature;
+        self.top_p = top_p;
+        self.top_k = top_k;
+    }
+
+    fn model_options(&self) -> ModelOptions {
+        let mut options = ModelOptions::default().seed(self.seed);
+        if let Some(temperature) = self.temperature {
+            options = options.temperature(temperature);
+        }
+        if let Some(top_p) = self.top_p {
+            options = options.top_p(top_p);
+        }
+        if let Some(top_k) = self.top_k {
+            options = options.top_k(top_k);
+        }
+        if let Some(num_thread) = self.num_thread {
+            options = options.num_thread(num_thread);
+        }
+        if let Some(num_gpu) = self.num_gpu {
+            options = options.num_gpu(num_gpu);
+        }
+        if let Some(num_ctx) = self.num_ctx {
+            options = options.num_ctx(num_ctx);
+        }
+        options
     }
 
+    #[allow(dead_code)]
     pub fn add_message(&mut self, message: ChatMessage) {
         self.history.push(message);
+        self.details.push(MessageDetails::now(None));
     }
 
     pub fn get_history(&self) -> &Vec<ChatMessage> {
         &self.history
     }
 
+    /// 会話履歴をJSONファイルに保存します。
+    pub fn save_history(&self, path: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let json_data = serde_json::to_string_pretty(&self.history)?;
+        std::fs::write(path, json_data)?;
+        Ok(())
+    }
+
+    /// 会話履歴を共有しやすいMarkdownの文書に変換します。
+    ///
+    /// 生成したタイトルがあれば文書の見出しにします。思考過程は`<details>`に折りたたみ、
+    /// ツールの呼び出しは引数をJSONのコードブロックで示します。
+    pub fn export_markdown(&self) -> String {
+        let mut output = format!("# {}\n\n", self.title.as_deref().unwrap_or("Conversation"));
+        for (index, message) in self.history.iter().enumerate() {
+            let role = match message.role {
+                MessageRole::User => "User",
+                MessageRole::Assistant => "Assistant",
+                MessageRole::Tool => "Tool",
+                MessageRole::System => "System",
+            };
+            output.push_str(&format!("## {}\n\n", role));
+
+            let details = self.details.get(index).cloned().unwrap_or_default();
+            if let Some(thinking) = details.thinking.as_deref() {
+                output.push_str(&format!("<details>\n<summary>Thinking</summary>\n\n{}\n\n</details>\n\n", thinking.trim()));
+            }
+            if !message.content.trim().is_empty() {
+                if message.role == MessageRole::Tool {
+                    output.push_str(&fenced_block("", message.content.trim_end()));
+                } else {
+                    output.push_str(&format!("{}\n\n", message.content.trim()));
+                }
+            }
+            if let Some(images) = message.images.as_ref().filter(|images| !images.is_empty()) {
+                output.push_str(&format!("*({} image(s) attached)*\n\n", images.len()));
+            }
+            for call in &message.tool_calls {
+                let arguments = serde_json::to_string_pretty(&call.function.arguments).unwrap_or_default();
+                output.push_str(&format!("**Tool call:** `{}`\n\n", call.function.name));
+                output.push_str(&fenced_block("json", &arguments));
+            }
+            for call in &details.tool_calls {
+                let arguments = serde_json::to_string_pretty(&call.arguments).unwrap_or_default();
+                output.push_str(&format!("**Tool call:** `{}`\n\n", call.name));
+                output.push_str(&fenced_block("json", &arguments));
+                if let Some(result) = call.result.as_deref() {
+                    output.push_str(&fenced_block("", result.trim_end()));
+                }
+            }
+        }
+        format!("{}\n", output.trim_end())
+    }
+
+    /// 会話履歴を、思考過程や呼び出したツールを含めた記録に変換します。
+    pub fn transcript(&self) -> Transcript {
+        let messages = self.history.iter().enumerate().map(|(index, message)| {
+            let details = self.details.get(index).cloned().unwrap_or_default();
+            let mut tool_calls: Vec<TranscriptToolCall> = message.tool_calls.iter()
+                .map(|call| TranscriptToolCall { name: call.function.name.clone(), arguments: call.function.arguments.clone(), result: None })
+                .collect();
+            tool_calls.extend(details.tool_calls);
+            TranscriptMessage {
+                role: message.role.clone(),
+                content: message.content.clone(),
+                thinking: details.thinking,
+                timestamp: details.timestamp,
+                tool_calls,
+            }
+        }).collect();
+        Transcript { exported_at: Local::now().to_rfc3339(), model: self.tool_model.clone(), title: self.title.clone(), messages }
+    }
+
+    /// 記録から会話履歴とタイトルを復元します。
+    pub fn restore_transcript(&mut self, transcript: Transcript) {
+        self.history.clear();
+        self.details.clear();
+        for message in transcript.messages {
+            self.history.push(ChatMessage::new(message.role, message.content));
+            self.details.push(MessageDetails { thinking: message.thinking, timestamp: message.timestamp, tool_calls: message.tool_calls });
+        }
+        self.title = transcript.title;
+    }
+
+    /// JSONファイルから会話履歴を読み込みます。ファイルが存在しないか空の場合は新しい会話として扱います。
+    pub fn load_history(&mut self, path: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if !path.exists() {
+            return Ok(());
+        }
+        let json_data = std::fs::read_to_string(path)?;
+        if json_data.trim().is_empty() {
+            return Ok(());
+        }
+        self.history = serde_json::from_str(&json_data)?;
+        self.details = vec![MessageDetails::default(); self.history.len()];
+        Ok(())
+    }
+
+    /// 保存したセッションの会話履歴とタイトルに置き換えます。
+    pub fn restore_session(&mut self, history: Vec<ChatMessage>, title: Option<String>) {
+        self.details = vec![MessageDetails::default(); history.len()];
+        self.history = history;
+        self.title = title;
+    }
+
     pub fn clear_history(&mut self) {
         self.history.clear();
+        self.details.clear();
+        self.title = None;
     }
 
-    pub async fn generate_response(&mut self, prompt: &str) {
-        let mut coordinator = Coordinator::new(self.context.clone(), self.tool_model.to_string(), self.history.clone())
-            .add_tool(get_datetime_now)
-            .add_tool(calculator);
+    /// 最後のアシスタントの応答の思考過程を取得します。思考過程がない場合はNoneを返します。
+    pub fn get_last_thinking(&self) -> Option<&str> {
+        let index = self.history.iter().rposition(|message| message.role == MessageRole::Assistant)?;
+        self.details.get(index)?.thinking.as_deref()
+    }
+
+    /// 最後のアシスタントの応答を取得します。
+    pub fn get_last_response(&self) -> Option<&str> {
+        self.history.iter().rev()
+            .find(|message| message.role == MessageRole::Assistant)
+            .map(|message| message.content.as_str())
+    }
+
+    /// パターンに一致する履歴のメッセージを、履歴内の位置とともに返します。
+    pub fn search_history(&self, pattern: &Regex) -> Vec<(usize, &ChatMessage)> {
+        self.history.iter()
+            .enumerate()
+            .filter(|(_, message)| pattern.is_match(&message.content))
+            .collect()
+    }
+
+    /// テキストからフェンス付きコードブロックを抽出します。
+    ///
+    /// 閉じられていないフェンスはテキストの末尾までをコードブロックとして扱います。
+    pub fn extract_code_blocks(text: &str) -> Vec<CodeBlock> {
+        let mut blocks = Vec::new();
+        // (フェンスのバッククォート数, 言語, 内容)
+        let mut current: Option<(usize, String, Vec<&str>)> = None;
+
+        for line in text.lines() {
+            let trimmed = line.trim_start();
+            let fence_len = trimmed.chars().take_while(|c| *c == '`').count();
+
+            match current.as_mut() {
+                None => {
+                    if fence_len >= 3 {
+                        let lang = trimmed[fence_len..].split_whitespace().next().unwrap_or_default().to_string();
+                        current = Some((fence_len, lang, Vec::new()));
+                    }
+                }
+                Some((open_len, _, lines)) => {
+                    // 開始フェンス以上の長さのバッククォートのみの行で閉じる
+                    if fence_len >= *open_len && trimmed.trim_end().len() == fence_len {
+                        let (_, lang, lines) = current.take().unwrap();
+                        blocks.push(CodeBlock { lang, content: lines.join("\n") });
+                    } else {
+                        lines.push(line);
+                    }
+                }
+            }
+        }
 
+        if let Some((_, lang, lines)) = current {
+            blocks.push(CodeBlock { lang, content: lines.join("\n") });
+        }
+        blocks
+    }
+
+    /// タイトルの生成に使用するモデルを設定します。
+    ///
+    /// タイトルの生成は文章のみのタスクのため、未設定の場合はvision_modelではなくtool_modelを使用します。
+    pub fn set_title_model(&mut self, title_model: Option<String>) {
+        self.title_model = title_model;
+    }
+
+    /// タイトルの生成に使用するモデルを返します。
+    pub fn get_title_model(&self) -> &str {
+        self.title_model.as_deref().unwrap_or(&self.tool_model)
+    }
+
+    /// 生成するタイトルの最大文字数を設定します。超えた部分は切り捨てます。
+    pub fn set_title_max_len(&mut self, title_max_len: usize) {
+        self.title_max_len = title_max_len;
+    }
+
+    /// 最後の応答の生成にかかった時間とトークン数を返します。
+    pub fn get_last_stats(&self) -> Option<&ResponseStats> {
+        self.last_stats.as_ref()
+    }
+
+    /// 最後に生成したタイトルを取得します。
+    pub fn get_title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    pub async fn generate_response(&mut self, prompt: &str) {
+        self.begin_turn(prompt);
         let message = ChatMessage::user(prompt.to_string());
-        let res = coordinator.chat(vec![message.clone()]).await;
-        if res.is_err() {
-            println!("Error: {}", res.unwrap_err());
+        if !self.compare_models.is_empty() {
+            self.compare_responses(message).await;
+            return;
+        }
+
+        // 会話の要約ツールには今回の発言を含めた履歴を共有する
+        let mut current_history = self.history.clone();
+        current_history.push(message.clone());
+        let registry = self.tool_registry(current_history);
+
+        self.respond(message, registry).await;
+    }
+
+    /// 応答の生成に使用するツールの定義と提供元を、モデルへ渡す順に返します。
+    pub fn tool_definitions(&self) -> Vec<(ToolDefinition, String)> {
+        self.tool_registry(self.history.clone()).definitions_with_source()
+    }
+
+    /// 組み込みツール、外部ツール、MCPツールの順に登録したツールの一覧を作成する
+    fn tool_registry(&self, history: Vec<ChatMessage>) -> ToolRegistry {
+        let enabled = |name: &str| self.enabled_tools.as_ref().is_none_or(|tools| tools.iter().any(|tool| tool == name));
+
+        let mut registry = ToolRegistry::new()
+            .audit_log(self.audit_log.clone())
+            .policies(self.tool_policies.clone())
+            .interactive(self.interactive)
+            .timeout(self.tool_timeout);
+        if enabled("get_datetime_now") {
+            registry = registry.add(BuiltinTool(get_datetime_now));
+        }
+        if enabled("calculator") {
+            registry = if self.precise_calculator {
+                registry.add(BuiltinTool(PreciseCalculator))
+            } else {
+                registry.add(BuiltinTool(Calculator))
+            };
+        }
+        if enabled("get_conversation_summary") {
+            registry = registry.add(BuiltinTool(ConversationSummary { history: Arc::new(history) }));
+        }
+        if enabled("fetch_url") {
+            registry = registry.add(BuiltinTool(FetchUrl {
+                http: self.http.clone(),
+                max_bytes: self.fetch_max_bytes,
+                allow_private: self.fetch_allow_private,
+            }));
+        }
+        if enabled("remember") {
+            registry = registry.add(BuiltinTool(Remember { memory: self.memory.clone() }));
+        }
+        if enabled("recall") {
+            registry = registry.add(BuiltinTool(Recall { memory: self.memory.clone() }));
+        }
+        for external_tool in &self.external_tools {
+            registry = registry.add(external_tool.clone());
+        }
+        for mcp_tool in &self.mcp_tools {
+            registry = registry.add(mcp_tool.clone());
+        }
+        registry
+    }
+
+    /// 画像を添付して、vision_modelに応答を生成させます。
+    pub async fn generate_vision_response(&mut self, image_path: &Path, prompt: &str) {
+        let image = match load_image(image_path) {
+            Ok(image) => image,
+            Err(e) => {
+                self.renderer.on_error(&e);
+                return;
+            }
+        };
+
+        self.begin_turn(prompt);
+        let message = ChatMessage::user(prompt.to_string()).with_images(vec![image]);
+        self.respond(message, ToolRegistry::new()).await;
+    }
+
+    /// 入力を出力先へ渡し、履歴を予算に収めます。
+    fn begin_turn(&mut self, prompt: &str) {
+        if self.echo_prompt {
+            self.renderer.on_user_prompt(prompt);
+        }
+
+        if let Some(context_budget) = self.context_budget {
+            let reserved = estimate_tokens(prompt) + self.system_prompt.as_deref().map(estimate_tokens).unwrap_or(0);
+            let dropped = self.truncate_history(context_budget.saturating_sub(reserved));
+            if dropped > 0 {
+                let notice = format!("Warning: dropped {} old messages to fit the context budget (~{} tokens).", dropped, context_budget);
+                self.renderer.on_notice(&notice);
+            }
+        }
+    }
+
+    /// 応答を生成して表示し、入力と応答を履歴に追加します。
+    async fn respond(&mut self, message: ChatMessage, mut registry: ToolRegistry) {
+        let mut messages = Vec::new();
+        if let Some(system_prompt) = self.system_prompt.as_ref() {
+            messages.push(ChatMessage::system(system_prompt.clone()));
+        }
+        messages.extend(self.history.iter().cloned());
+        messages.push(message.clone());
+        // システムプロンプトの繰り返しを取り除く場合は、応答全体が揃ってから表示する
+        let stream = self.stream && !self.strip_system_echo;
+        if self.dry_run {
+            self.print_request(&registry, &messages, stream);
             return;
         }
-        let res = res.unwrap();
+        let mut options = self.model_options();
+        let mut retried = false;
+        let res = loop {
+            let mut res = match self.chat_with_tools(&mut registry, messages.clone(), &options, stream).await {
+                Ok(res) => res,
+                Err(e) if e.is::<Cancelled>() => {
+                    self.keep_partial_response(message);
+                    return;
+                }
+                Err(e) => {
+                    self.renderer.on_error(&e.to_string());
+                    return;
+                }
+            };
+            if self.strip_system_echo
+                && let Some(system_prompt) = self.system_prompt.as_ref()
+                && let Some(stripped) = strip_echo(&res.message.content, system_prompt, &self.think_tags.close) {
+                res.message.content = stripped;
+            }
+
+            // 思考過程のみの応答などで本文が空の場合は、シードを変えて1回だけ再生成する。
+            // 変えたシードはこの再生成のみに使い、以降の応答には設定したシードを使う
+            if self.retry_empty && !retried && self.is_empty_response(&res.message.content) {
+                self.renderer.on_notice("Warning: the model returned an empty response, retrying once...");
+                options = self.model_options().seed(self.seed.wrapping_add(1));
+                retried = true;
+                continue;
+            }
+            break res;
+        };
 
         let text = res.message.content.clone();
-        println!("{}", text);
+        if !stream {
+            let mut renderer = ThinkingSplitter::new(self.renderer.as_mut(), self.hide_thinking, self.think_tags.clone());
+            renderer.on_content_chunk(&text);
+            renderer.flush();
+            self.pace_output().await;
+        }
+        self.renderer.on_done();
 
+        // 画像は容量が大きいため、履歴には入力の文章のみを残す
+        let mut message = message;
+        message.images = None;
+        // 本文が空の応答は、以降の応答の妨げになるため履歴に残さない。
+        // 発言と応答の組を保つよう、発言も履歴に残さない
+        if self.is_empty_response(&text) {
+            self.renderer.on_notice("Warning: the model returned an empty response; the prompt and the response were not added to the history.");
+            self.turn_tool_calls.clear();
+            return;
+        }
         self.history.push(message);
         self.history.push(res.message);
+        self.details.push(MessageDetails::now(None));
+        let mut details = MessageDetails::now(self.get_thinking(&text, false).filter(|thinking| !thinking.is_empty()));
+        details.tool_calls = std::mem::take(&mut self.turn_tool_calls);
+        self.details.push(details);
 
         // thinkingモデルの場合は、会話履歴からthinkingタグを削除することでコンテキスト長を節約する
         let thinking_result = self.get_thinking(&text, true);
-        if let Some(thinking) = thinking_result {
-            if let Some(res) = self.history.last_mut() {
-                res.content = thinking.clone();
+        if let Some(thinking) = thinking_result
+            && let Some(res) = self.history.last_mut() {
+            res.content = thinking.clone();
+        }
+
+        if self.auto_compact && self.estimate_tokens() > self.compact_budget {
+            const KEEP_RECENT_MESSAGES: usize = 4;
+
+            let before = self.estimate_tokens();
+            match self.summarize_and_compress(KEEP_RECENT_MESSAGES).await {
+                Ok(0) => {}
+                Ok(count) => {
+                    let notice = format!("Warning: compacted {} old messages into a summary (~{} -> ~{} tokens).", count, before, self.estimate_tokens());
+                    self.renderer.on_notice(&notice);
+                }
+                Err(e) => self.renderer.on_error(&format!("failed to compact history: {}", e)),
             }
         }
     }
 
-    pub async fn generate_title(&mut self) -> String {
-        let prompt = "長文は禁止されています。また、余計な文章も禁止されています。会話内容からユーザー目線でのタイトルを日本語で生成してください。";
-        let message = ChatMessage::user(prompt.to_string());
-        let res = self.context.send_chat_messages_with_history(
-            &mut self.history.clone(),
-            ChatMessageRequest::new(
-                self.vision_model.clone(),
-                vec![message.clone()],
-            ),
-        ).await.unwrap();
-
-        // thinkingモデルの場合は、会話履歴からthinkingタグを削除することでコンテキスト長を節約する
-        let thinking_result = self.get_thinking(&res.message.content, false);
-        if let Some(thinking) = thinking_result {
-            return thinking;
+    /// 出力先が表示を遅らせている出力を、間隔を空けて表示し終えるまで待つ
+    async fn pace_output(&mut self) {
+        while let Some(delay) = self.renderer.next_delay() {
+            tokio::time::sleep(delay).await;
         }
-        return res.message.content;
     }
 
-    fn get_thinking(&self, text: &str, is_result: bool) -> Option<String> {
-        if let Some(captures) = self.thinking_regex.captures(text) {
-            if is_result {
-                if let Some(matched) = captures.get(0) {
-                    return Some(text.replace(matched.as_str(), "").trim().to_string());
-                }
+    /// 思考過程を取り除いた本文が空か
+    fn is_empty_response(&self, content: &str) -> bool {
+        self.get_thinking(content, true).unwrap_or_default().trim().is_empty()
+    }
+
+    /// 比較するモデルそれぞれに同じ発言への応答を同時に生成させ、モデル名を付けて順に表示する
+    ///
+    /// 応答が混ざらないよう、全てのモデルの応答を受け取ってからまとめて表示する。ツールは使用しない。
+    /// 履歴には、応答を生成できた最初のモデルの応答のみを残す。
+    async fn compare_responses(&mut self, message: ChatMessage) {
+        let mut messages = Vec::new();
+        if let Some(system_prompt) = self.system_prompt.as_ref() {
+            messages.push(ChatMessage::system(system_prompt.clone()));
+        }
+        messages.extend(self.history.iter().cloned());
+        messages.push(message.clone());
+        if self.dry_run {
+            self.print_request(&ToolRegistry::new(), &messages, false);
+            return;
+        }
+
+        let options = self.model_options();
+        let requests = self.compare_models.iter().map(|model| {
+            let (client, messages, options) = (&self.client, &messages, &options);
+            async move {
+                // 読み込み待ちなどの通知は、他のモデルの表示と混ざらないよう表示しない
+                let mut renderer = StringRenderer::default();
+                client.chat_with_tools(messages, model, &[], options, &mut renderer).await
             }
-            else {
-                if let Some(matched) = captures.get(1) {
-                    return Some(matched.as_str().to_string());
+        });
+        let results = futures_util::future::join_all(requests);
+        // 中断した場合は、どのモデルの応答も表示せず履歴にも残さない
+        let Ok(results) = cancellable(&self.cancel.clone(), async { Ok(results.await) }).await else {
+            return;
+        };
+
+        let mut recorded: Option<(String, ChatMessageResponse)> = None;
+        for (model, result) in self.compare_models.clone().into_iter().zip(results) {
+            self.renderer.on_notice(&format!("=== {} ===", model));
+            match result {
+                Ok(res) => {
+                    let mut renderer = ThinkingSplitter::new(self.renderer.as_mut(), self.hide_thinking, self.think_tags.clone());
+                    renderer.on_content_chunk(&res.message.content);
+                    renderer.flush();
+                    self.pace_output().await;
+                    if recorded.is_none() && !self.is_empty_response(&res.message.content) {
+                        recorded = Some((model, res));
+                    }
                 }
+                Err(e) => self.renderer.on_error(&e.to_string()),
             }
+            self.renderer.on_done();
         }
-        if is_result {
-            return Some(text.to_string());
+
+        let Some((model, res)) = recorded else {
+            return;
+        };
+        let text = res.message.content.clone();
+        let mut response = res.message;
+        response.content = self.get_thinking(&text, true).unwrap_or_default();
+        self.history.push(message);
+        self.history.push(response);
+        self.details.push(MessageDetails::now(None));
+        self.details.push(MessageDetails::now(self.get_thinking(&text, false).filter(|thinking| !thinking.is_empty())));
+        self.renderer.on_notice(&format!("Note: kept the response from {} in the history.", model));
+    }
+
+    /// 最後のユーザーの発言とそれ以降の応答を履歴から取り除き、その発言を返します。
+    ///
+    /// 再生成で同じ応答にならないよう、シードを1つ進めます。ユーザーの発言がない場合はNoneを返します。
+    pub fn pop_last_turn(&mut self) -> Option<String> {
+        let index = self.history.iter().rposition(|message| message.role == MessageRole::User)?;
+        let prompt = self.history[index].content.clone();
+        self.history.truncate(index);
+        self.details.truncate(index);
+        self.seed = self.seed.wrapping_add(1);
+        Some(prompt)
+    }
+
+    /// 生成を中断した場合に、ユーザーの入力とそれまでに受け取った応答を履歴に追加します。
+    ///
+    /// 発言と応答の組を保つよう、応答を受け取る前に中断した場合は入力も履歴に残しません。
+    fn keep_partial_response(&mut self, mut message: ChatMessage) {
+        let partial_response = std::mem::take(&mut self.partial_response);
+        let turn_tool_calls = std::mem::take(&mut self.turn_tool_calls);
+        let thinking = self.get_thinking(&partial_response, false).filter(|thinking| !thinking.is_empty());
+        let partial_response = self.get_thinking(&partial_response, true).unwrap_or_default();
+        if partial_response.trim().is_empty() {
+            return;
         }
-        else {
-            return None;
+        message.images = None;
+        self.history.push(message);
+        self.details.push(MessageDetails::now(None));
+        self.history.push(ChatMessage::assistant(partial_response));
+        let mut details = MessageDetails::now(thinking);
+        details.tool_calls = turn_tool_calls;
+        self.details.push(details);
+    }
+
+    /// 履歴が`budget`（推定トークン数）に収まるまで、システムメッセージ以外の古いメッセージを削除します。
+    ///
+    /// 会話が応答から始まらないよう、ユーザーの発言の前までまとめて削除します。削除したメッセージ数を返します。
+    fn truncate_history(&mut self, budget: usize) -> usize {
+        let mut dropped = 0;
+        while self.estimate_tokens() > budget {
+            let Some(index) = self.history.iter().position(|message| message.role != MessageRole::System) else {
+                break;
+            };
+            self.history.remove(index);
+            self.details.remove(index);
+            dropped += 1;
+            while let Some(index) = self.history.iter().position(|message| message.role != MessageRole::System)
+                && self.history[index].role != MessageRole::User {
+                self.history.remove(index);
+                self.details.remove(index);
+                dropped += 1;
+            }
         }
+        dropped
     }
-}
 
+    /// 直近の`keep_recent`件を残し、それより古い履歴を要約した1件のメッセージに置き換えます。
+    ///
+    /// 置き換えたメッセージ数を返します。
+    pub async fn summarize_and_compress(&mut self, keep_recent: usize) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        if self.history.len() <= keep_recent {
+            return Ok(0);
+        }
+        let split_at = self.history.len() - keep_recent;
+        self.summarize_before(split_at).await?;
+        Ok(split_at)
+    }
 
-/// 現在の時刻を取得します。
-#[ollama_rs::function]
-async fn get_datetime_now() -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    /// 直近の`keep_turns`回のやり取り（ユーザーの発言とそれに続く応答）を残し、それより古い履歴を要約に置き換えます。
+    ///
+    /// 要約に置き換えたメッセージ数と、削減できた推定トークン数を返します。要約する履歴がない場合はNoneを返します。
+    pub async fn compress_history(&mut self, keep_turns: usize) -> Result<Option<(usize, usize)>, Box<dyn std::error::Error + Send + Sync>> {
+        let split_at = if keep_turns == 0 {
+            self.history.len()
+        } else {
+            let mut user_indices = self.history.iter().enumerate()
+                .filter(|(_, message)| message.role == MessageRole::User)
+                .map(|(index, _)| index);
+            user_indices.nth_back(keep_turns - 1).unwrap_or(0)
+        };
+        if split_at == 0 {
+            return Ok(None);
+        }
+
+        let before = self.estimate_tokens();
+        self.summarize_before(split_at).await?;
+        Ok(Some((split_at, before.saturating_sub(self.estimate_tokens()))))
+    }
+
+    /// 履歴の`split_at`より前を、モデルに生成させた要約の1件のシステムメッセージに置き換える
+    async fn summarize_before(&mut self, split_at: usize) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let prompt = "これまでの会話を、後の会話で必要になる事実や決定事項を漏らさずに日本語で簡潔に要約してください。要約以外の文章は禁止されています。";
+        let mut messages = self.history[..split_at].to_vec();
+        messages.push(ChatMessage::user(prompt.to_string()));
+        let options = self.model_options();
+        let res = self.client.chat_with_tools(&messages, &self.tool_model, &[], &options, self.renderer.as_mut()).await?;
+
+        let summary = self.get_thinking(&res.message.content, true).unwrap_or(res.message.content);
+        let summary = ChatMessage::system(format!("これまでの会話の要約:\n{}", summary));
+        self.history.splice(..split_at, [summary]);
+        self.details.splice(..split_at, [MessageDetails::now(None)]);
+        Ok(())
+    }
+
+    /// 最初に送信するリクエストを、サーバーへ送信せずにJSONで表示します。
+    fn print_request(&mut self, registry: &ToolRegistry, messages: &[ChatMessage], stream: bool) {
+        let tools: Vec<Value> = registry.definitions().iter().map(|tool| tool.to_json()).collect();
+        let has_images = messages.last().is_some_and(|message| message.images.is_some());
+        let (model, tools) = select_model(&self.tool_model, &self.vision_model, &tools, has_images);
+        let request = self.client.request_body(messages, model, tools, &self.model_options(), stream);
+        match serde_json::to_string_pretty(&request) {
+            Ok(json_data) => println!("{}", json_data),
+            Err(e) => self.renderer.on_error(&e.to_string()),
+        }
+    }
+
+    /// ツール呼び出しがなくなるまでモデルとのやり取りを繰り返し、最終的な応答を返します。
+    ///
+    /// ツールを呼び出した後のリクエストには、`messages`（システムプロンプト、履歴、ユーザーのメッセージ）に続けて
+    /// ツール呼び出しを含むアシスタントのメッセージとツールの結果を、この順に追加して送信します。
+    /// 戻り値の本文には、ツール呼び出しの前に受け取って表示した本文も含みます。
+    async fn chat_with_tools(&mut self, registry: &mut ToolRegistry, mut messages: Vec<ChatMessage>, options: &ModelOptions, stream: bool) -> Result<ChatMessageResponse, Box<dyn std::error::Error + Send + Sync>> {
+        let tools: Vec<Value> = registry.definitions().iter().map(|tool| tool.to_json()).collect();
+        let mut has_images = messages.last().is_some_and(|message| message.images.is_some());
+        let mut stats = ResponseStats::default();
+        let mut has_stats = false;
+        let mut content_before_tools = String::new();
+        let mut tool_rounds = 0;
+        // 呼び出し回数の上限に達した後は、ツールを渡さずに生成する
+        let mut tools_exhausted = false;
+        self.last_stats = None;
+        self.turn_tool_calls.clear();
+        // 中断した場合にツール呼び出しの前に表示した本文も残せるよう、ターンの間は全ての回の本文を記録する
+        self.partial_response.clear();
+        let cancel = self.cancel.clone();
+
+        loop {
+            let (model, mut tools) = select_model(&self.tool_model, &self.vision_model, &tools, has_images);
+            if tools_exhausted || self.supports_tools.get(model) == Some(&false) {
+                tools = &[];
+            }
+            if !self.partial_response.is_empty() && !self.partial_response.ends_with("\n\n") {
+                self.partial_response.push_str("\n\n");
+            }
+            let res = if stream {
+                let mut visible = HoldLeadingWhitespace { inner: self.renderer.as_mut(), pending: String::new(), started: false };
+                let mut splitter = ThinkingSplitter::new(&mut visible, self.hide_thinking, self.think_tags.clone());
+                let mut renderer = RecordingRenderer { inner: &mut splitter, buffer: &mut self.partial_response };
+                let res = self.client.chat_stream_with_tools(&messages, model, tools, options, &mut renderer, &cancel).await;
+                splitter.flush();
+                res
+            } else {
+                cancellable(&cancel, self.client.chat_with_tools(&messages, model, tools, options, self.renderer.as_mut())).await
+            };
+            let mut res = match res {
+                // ツールに対応していないモデルでは、以降このセッションではツールを渡さずに生成する
+                Err(e) if !tools.is_empty() && is_tools_unsupported(e.as_ref()) => {
+                    self.supports_tools.insert(model.to_string(), false);
+                    self.renderer.on_notice(&format!("Warning: model \"{}\" does not support tools, retrying without tools.", model));
+                    continue;
+                }
+                Err(e) => return Err(e),
+                Ok(res) => {
+                    if !tools.is_empty() {
+                        self.supports_tools.insert(model.to_string(), true);
+                    }
+                    res
+                }
+            };
+            has_stats |= res.final_data.is_some();
+            stats.add(&res);
+            // 出力先が閉じられた場合は、ツールを呼び出さずにここで終える
+            // ツールを渡していないのに呼び出そうとした場合も、これ以上は実行しない
+            if crate::render::output_closed() || tools_exhausted {
+                res.message.tool_calls.clear();
+            }
+
+            if res.message.tool_calls.is_empty() {
+                self.last_stats = has_stats.then_some(stats);
+                // ツール呼び出しの前に表示した本文も、最終的な応答とあわせて履歴に残す
+                if !content_before_tools.is_empty() {
+                    content_before_tools.push_str(&res.message.content);
+                    res.message.content = content_before_tools;
+                }
+                return Ok(res);
+            }
+
+            let content = self.get_thinking(&res.message.content, true).unwrap_or_default();
+            if !content.trim().is_empty() {
+                content_before_tools.push_str(content.trim());
+                content_before_tools.push_str("\n\n");
+            }
+            messages.push(res.message.clone());
+            if tool_rounds >= self.max_tool_rounds {
+                log::warn!("reached the tool call limit ({} rounds)", self.max_tool_rounds);
+                self.renderer.on_notice(&format!("Warning: reached the limit of {} tool call rounds, asking the model to answer without tools.", self.max_tool_rounds));
+                // 全ての呼び出しに結果を返さないと、モデルが呼び出しの応答を待ったままになる
+                for _ in &res.message.tool_calls {
+                    messages.push(ChatMessage::tool(format!("Error: ツールの呼び出し回数の上限（{}回）に達したため、このターンではこれ以上ツールを使用できません。これまでの結果をもとに回答してください。", self.max_tool_rounds)));
+                }
+                tools_exhausted = true;
+                continue;
+            }
+            tool_rounds += 1;
+            for call in res.message.tool_calls {
+                self.renderer.on_tool_call(&call.function.name, &call.function.arguments);
+                // ユーザーの確認を待つ時間をタイムアウトに含めないよう、実行の可否は先に判断する
+                let permitted = cancellable(&cancel, async { Ok(registry.permit(&call.function.name, &call.function.arguments).await) }).await?;
+                let result = if let Err(denied) = permitted {
+                    ToolOutput::from(denied)
+                } else {
+                    // 実行を待つ間、出力先に経過を通知する
+                    let call_future = registry.call(&call.function.name, call.function.arguments.clone());
+                    tokio::pin!(call_future);
+                    let start = tokio::time::Instant::now();
+                    let mut progress = tokio::time::interval_at(start + TOOL_PROGRESS_INTERVAL, TOOL_PROGRESS_INTERVAL);
+                    let result = loop {
+                        tokio::select! {
+                            result = &mut call_future => break result,
+                            _ = cancel.cancelled() => return Err(Cancelled.into()),
+                            _ = progress.tick() => self.renderer.on_tool_progress(&call.function.name, start.elapsed()),
+                        }
+                    };
+                    // ツールのエラーやタイムアウトはモデルに返して対処させる
+                    result.unwrap_or_else(|e| ToolOutput::from(format!("Error: {}", e)))
+                };
+                self.renderer.on_tool_result(&call.function.name, &result.text);
+                self.turn_tool_calls.push(TranscriptToolCall {
+                    name: call.function.name.clone(),
+                    arguments: call.function.arguments,
+                    result: Some(result.text.clone()),
+                });
+
+                let mut message = ChatMessage::tool(result.text);
+                if !result.images.is_empty() {
+                    has_images = true;
+                    message = message.with_images(result.images);
+                }
+                messages.push(message);
+            }
+        }
+    }
+
+    /// prefixとsuffixの間を補完（fill-in-the-middle）し、結果をストリーミングで表示します。
+    pub async fn generate_fim(&mut self, model: &str, prefix: &str, suffix: &str) {
+        // FIMに対応したモデルはテンプレート内でSuffixを参照している
+        match self.context.show_model_info(model.to_string()).await {
+            Ok(info) if info.template.contains(".Suffix") => {}
+            Ok(_) => {
+                self.renderer.on_error(&format!("このモデルはFIMに対応していません: {}", model));
+                return;
+            }
+            Err(e) => {
+                self.renderer.on_error(&e.to_string());
+                return;
+            }
+        }
+
+        let mut request = GenerationRequest::new(model.to_string(), prefix)
+            .suffix(suffix)
+            .options(self.model_options());
+        if let Some(keep_alive) = self.client.get_keep_alive() {
+            request = request.keep_alive(keep_alive.clone());
+        }
+        let mut stream = match self.context.generate_stream(request).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                self.renderer.on_error(&e.to_string());
+                return;
+            }
+        };
+
+        while let Some(chunk) = stream.next().await {
+            match chunk {
+                Ok(responses) => {
+                    for response in responses {
+                        self.renderer.on_content_chunk(&response.response);
+                    }
+                    if crate::render::output_closed() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    self.renderer.on_error(&e.to_string());
+                    return;
+                }
+            }
+        }
+        self.renderer.on_done();
+    }
+
+    /// 会話のタイトルを生成します。使用するモデルは`get_title_model`で確認できます。
+    pub async fn generate_title(&mut self) -> String {
+        let prompt = "長文は禁止されています。また、余計な文章も禁止されています。会話内容からユーザー目線でのタイトルを日本語で生成してください。";
+        let mut messages = self.history.clone();
+        messages.push(ChatMessage::user(prompt.to_string()));
+        let model = self.get_title_model().to_string();
+        let options = self.model_options();
+        let content = match self.client.chat_with_tools(&messages, &model, &[], &options, self.renderer.as_mut()).await {
+            Ok(res) => res.message.content,
+            Err(e) => {
+                self.renderer.on_error(&e.to_string());
+                String::new()
+            }
+        };
+
+        // thinkingモデルの場合は、thinkingタグを削除してから整形する
+        let content = self.get_thinking(&content, true).unwrap_or(content);
+        let title = clean_title(&content, self.title_max_len)
+            .unwrap_or_else(|| format!("会話 {}", Local::now().format("%Y-%m-%d %H:%M")));
+        self.title = Some(title.clone());
+        title
+    }
+
+    fn get_thinking(&self, text: &str, is_result: bool) -> Option<String> {
+        if let Some(captures) = self.thinking_regex.captures(text) {
+            if is_result {
+                if let Some(matched) = captures.get(0) {
+                    return Some(text.replace(matched.as_str(), "").trim().to_string());
+                }
+            }
+            else if let Some(matched) = captures.get(1) {
+                return Some(matched.as_str().to_string());
+            }
+        }
+        if is_result {
+            Some(text.to_string())
+        }
+        else {
+            None
+        }
+    }
+}
+
+
+/// 画像ファイルを読み込み、base64でエンコードします。
+fn load_image(image_path: &Path) -> Result<Image, String> {
+    const SUPPORTED_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "webp"];
+
+    let extension = image_path.extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase())
+        .unwrap_or_default();
+    if !SUPPORTED_EXTENSIONS.contains(&extension.as_str()) {
+        return Err(format!("対応していない画像の形式です: {}（対応形式: {}）", image_path.display(), SUPPORTED_EXTENSIONS.join(", ")));
+    }
+
+    let data = std::fs::read(image_path)
+        .map_err(|e| format!("画像を読み込めません: {} {}", image_path.display(), e))?;
+    Ok(Image::from_base64(BASE64_STANDARD.encode(data)))
+}
+
+
+/// 受け取った応答本文を記録しながら出力先へ渡す
+struct RecordingRenderer<'a> {
+    inner: &'a mut dyn OutputRenderer,
+    buffer: &'a mut String,
+}
+
+impl OutputRenderer for RecordingRenderer<'_> {
+    fn inner(&mut self) -> Option<&mut dyn OutputRenderer> {
+        Some(self.inner)
+    }
+
+    fn on_content_chunk(&mut self, chunk: &str) {
+        self.buffer.push_str(chunk);
+        self.inner.on_content_chunk(chunk);
+    }
+}
+
+
+/// 本文の先頭の空白を、空白以外の文字が届くまで出力先へ渡さずに保持する
+///
+/// 空白のみの応答（`--retry-empty`で再生成する応答など）を表示しないため。
+struct HoldLeadingWhitespace<'a> {
+    inner: &'a mut dyn OutputRenderer,
+    pending: String,
+    started: bool,
+}
+
+impl OutputRenderer for HoldLeadingWhitespace<'_> {
+    fn inner(&mut self) -> Option<&mut dyn OutputRenderer> {
+        Some(self.inner)
+    }
+
+    fn on_content_chunk(&mut self, chunk: &str) {
+        if self.started {
+            self.inner.on_content_chunk(chunk);
+            return;
+        }
+        self.pending.push_str(chunk);
+        if !self.pending.trim().is_empty() {
+            self.started = true;
+            self.inner.on_content_chunk(&std::mem::take(&mut self.pending));
+        }
+    }
+}
+
+
+/// 応答の先頭がシステムプロンプトの繰り返しであれば、それを取り除いた応答を返す
+///
+/// 空白の違いは無視して比較する。thinkingタグ（`think_close`で閉じる）より後に繰り返された場合も対象とする。
+fn strip_echo(text: &str, system_prompt: &str, think_close: &str) -> Option<String> {
+    let prompt: Vec<&str> = system_prompt.split_whitespace().collect();
+    if prompt.is_empty() {
+        return None;
+    }
+
+    // thinkingタグは残したまま、その後の本文の先頭を調べる
+    let (head, body) = match text.find(think_close) {
+        Some(pos) => text.split_at(pos + think_close.len()),
+        None => ("", text),
+    };
+
+    let mut rest = body.trim_start();
+    for word in prompt {
+        rest = rest.strip_prefix(word)?.trim_start();
+    }
+    if head.is_empty() {
+        Some(rest.to_string())
+    } else {
+        Some(format!("{}\n{}", head, rest))
+    }
+}
+
+
+/// 内容を囲むコードブロックを作成する。内容に含まれるバッククォートの連続より長いフェンスを使う
+fn fenced_block(lang: &str, content: &str) -> String {
+    let longest = content.split(|c| c != '`').map(|run| run.len()).max().unwrap_or(0);
+    let fence = "`".repeat(longest.max(2) + 1);
+    format!("{}{}\n{}\n{}\n\n", fence, lang, content, fence)
+}
+
+
+/// 英数字は約4文字、それ以外（日本語など）は約1文字を1トークンとして推定する
+fn estimate_tokens(text: &str) -> usize {
+    let ascii = text.chars().filter(|c| c.is_ascii()).count();
+    let others = text.chars().count() - ascii;
+    ascii.div_ceil(4) + others
+}
+
+
+/// 思考過程を取り出す正規表現を作成する。タグは正規表現として解釈されないようエスケープする
+fn build_thinking_regex(tags: &ThinkTags) -> Regex {
+    let pattern = format!(r"(?s){}\s*(.*?)\s*(?:{}|\z)", regex::escape(&tags.open), regex::escape(&tags.close));
+    Regex::new(&pattern).expect("エスケープしたタグから正規表現を作成できませんでした")
+}
+
+
+/// 使用するモデルと、モデルへ渡すツールを選ぶ
+///
+/// 入力やツールの結果に画像が含まれる場合は、画像を扱えるvision_modelに生成させる。
+/// vision_modelはツールに対応していない場合があるため、ツールは渡さない。
+fn select_model<'a>(tool_model: &'a str, vision_model: &'a str, tools: &'a [Value], has_images: bool) -> (&'a str, &'a [Value]) {
+    if has_images {
+        (vision_model, &[])
+    } else {
+        (tool_model, tools)
+    }
+}
+
+
+/// モデルが生成したタイトルから前後の引用符や改行を取り除き、空白をまとめてmax_len文字以内にする
+///
+/// 整形後に空になった場合はNoneを返す。
+fn clean_title(text: &str, max_len: usize) -> Option<String> {
+    const QUOTES: &[char] = &['"', '\'', '`', '「', '」', '『', '』', '“', '”', '*', '#'];
+
+    let title = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let title = title.trim_matches(|c: char| QUOTES.contains(&c) || c.is_whitespace());
+
+    // 文字数で切り詰める。絵文字の異体字セレクタや結合文字の途中では切らず、その文字ごと取り除く
+    let mut end = title.char_indices().nth(max_len).map(|(i, _)| i).unwrap_or(title.len());
+    while end < title.len() && end > 0 {
+        let next = title[end..].chars().next().unwrap_or_default();
+        let prev = title[..end].chars().next_back().unwrap_or_default();
+        if !is_grapheme_extend(next) && prev != '\u{200D}' {
+            break;
+        }
+        end -= prev.len_utf8();
+    }
+    let title = title[..end].trim_end();
+    (!title.is_empty()).then(|| title.to_string())
+}
+
+
+/// 直前の文字と組み合わせて1文字として表示される文字か
+///
+/// 結合文字、ゼロ幅接合子、異体字セレクタ、肌の色の修飾子、濁点・半濁点などを対象とする。
+fn is_grapheme_extend(c: char) -> bool {
+    matches!(c as u32,
+        0x0300..=0x036F | 0x200D | 0x20D0..=0x20FF | 0x3099..=0x309A | 0xFE00..=0xFE0F
+        | 0x1F3FB..=0x1F3FF | 0xE0020..=0xE007F | 0xE0100..=0xE01EF)
+}
+
+
+/// 出力を再現できるように、クライアント側でシードを生成する
+fn generate_seed() -> i32 {
+    let nanos = Local::now().timestamp_nanos_opt().unwrap_or_default();
+    let mixed = (nanos as u64) ^ ((std::process::id() as u64) << 32);
+    (mixed % i32::MAX as u64) as i32
+}
+
+
+/// 現在の時刻を取得します。
+#[ollama_rs::function]
+async fn get_datetime_now() -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
     let now = Local::now();
     let result: String = format!("現在時刻: {}", now);
     Ok(result)
 }
 
 
-/// 計算時の使用が義務付けられています。与えられた計算式を計算します。
-/// 
-/// * formula: 計算式、例: "1+sum(2,3)*abs(4-5)/6^2"
-#[ollama_rs::function]
-async fn calculator(formula: String) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+const CALCULATOR_DESCRIPTION: &str = "計算時の使用が義務付けられています。与えられた計算式を計算します。定数pi、e、tauと、varsで指定した変数を使用できます。";
+
+
+/// 計算式をf64で計算する`calculator`
+pub struct Calculator;
+
+impl Tool for Calculator {
+    type Params = CalculatorParams;
+
+    fn name() -> &'static str {
+        "calculator"
+    }
+
+    fn description() -> &'static str {
+        CALCULATOR_DESCRIPTION
+    }
+
+    async fn call(&mut self, parameters: Self::Params) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        validate_formula(&parameters.formula)?;
+        evaluate_f64(&parameters.formula, &parameters.vars.unwrap_or_default())
+    }
+}
+
+
+/// 極端に長い式や深く入れ子になった式は、計算に時間がかかるため受け付けない
+fn validate_formula(formula: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    const MAX_FORMULA_CHARS: usize = 1000;
+    const MAX_NESTING_DEPTH: usize = 32;
+
+    let length = formula.chars().count();
+    if length > MAX_FORMULA_CHARS {
+        return Err(format!("計算式が長すぎます（{}文字）。{}文字以内にしてください。", length, MAX_FORMULA_CHARS).into());
+    }
+
+    let mut depth: usize = 0;
+    for c in formula.chars() {
+        match c {
+            '(' | '[' => {
+                depth += 1;
+                if depth > MAX_NESTING_DEPTH {
+                    return Err(format!("計算式の括弧の入れ子が深すぎます。{}段以内にしてください。", MAX_NESTING_DEPTH).into());
+                }
+            }
+            ')' | ']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+
+fn evaluate_f64(formula: &str, vars: &HashMap<String, f64>) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
     let parser = fasteval::Parser::new();
     let mut slab = fasteval::Slab::new();
-    let val = parser.parse(&formula, &mut slab.ps);
+    let val = parser.parse(formula, &mut slab.ps);
     if let Err(e) = val {
         return Err(Box::new(e));
     }
 
+    // 未定義の名前を全て報告できるよう、評価は最後まで続ける
+    let mut undefined: Vec<String> = Vec::new();
+    let mut namespace = |name: &str, _args: Vec<f64>| -> Option<f64> {
+        if let Some(value) = vars.get(name) {
+            return Some(*value);
+        }
+        match name {
+            "pi" => Some(std::f64::consts::PI),
+            "e" => Some(std::f64::consts::E),
+            "tau" => Some(std::f64::consts::TAU),
+            _ => {
+                if !undefined.iter().any(|undefined_name| undefined_name == name) {
+                    undefined.push(name.to_string());
+                }
+                Some(f64::NAN)
+            }
+        }
+    };
     let val = val.unwrap()
         .from(&slab.ps)
-        .eval(&slab, &mut fasteval::EmptyNamespace);
+        .eval(&slab, &mut namespace);
 
+    if !undefined.is_empty() {
+        return Err(format!("未定義の変数があります: {}。varsで値を指定してください。", undefined.join(", ")).into());
+    }
     if let Err(e) = val {
         return Err(Box::new(e));
     }
-    Ok(val.unwrap().to_string())
-}
\ No newline at end of file
+
+    // ゼロ除算などで無限大や非数になった場合は、結果として返さずにエラーにする
+    let val = val.unwrap();
+    if val.is_nan() {
+        return Err("計算結果が数値になりません（NaN）。0/0や負の数の平方根などが含まれていないか確認してください。".into());
+    }
+    if val.is_infinite() {
+        return Err("計算結果が無限大になりました。ゼロ除算やオーバーフローが含まれていないか確認してください。".into());
+    }
+    Ok(val.to_string())
+}
+
+
+/// 計算ツールの引数
+#[derive(Deserialize, JsonSchema)]
+pub struct CalculatorParams {
+    /// 計算式、例: "1+sum(2,3)*abs(4-5)/6^2"、"2*pi*r"
+    #[serde(deserialize_with = "deserialize_formula")]
+    formula: String,
+    /// 省略可。変数名と値の対応、例: {"r": 1.5}
+    vars: Option<HashMap<String, f64>>,
+}
+
+
+/// モデルが渡しがちな形の計算式を、計算できる文字列に整える
+///
+/// 数値などの文字列以外の値は文字列に変換し、前後の空白、囲みのバッククォート、先頭や末尾の`=`を取り除く。
+/// 例: `42` → "42"、"= 2+2" → "2+2"、"`1+1`" → "1+1"
+fn deserialize_formula<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let formula = match Value::deserialize(deserializer)? {
+        Value::String(formula) => formula,
+        value @ (Value::Number(_) | Value::Bool(_)) => value.to_string(),
+        value => return Err(serde::de::Error::custom(format!("計算式は文字列で指定してください: {}", value))),
+    };
+    // "= `2+2`"のように組み合わさっている場合もあるため、変わらなくなるまで取り除く
+    let mut formula = formula.as_str();
+    loop {
+        let trimmed = formula.trim().trim_matches('`').trim_matches('=');
+        if trimmed == formula {
+            return Ok(formula.to_string());
+        }
+        formula = trimmed;
+    }
+}
+
+
+/// 整数・有理数の式を誤差なく計算し、それ以外はf64で計算する`calculator`
+pub struct PreciseCalculator;
+
+impl Tool for PreciseCalculator {
+    type Params = CalculatorParams;
+
+    fn name() -> &'static str {
+        "calculator"
+    }
+
+    fn description() -> &'static str {
+        CALCULATOR_DESCRIPTION
+    }
+
+    async fn call(&mut self, parameters: Self::Params) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        validate_formula(&parameters.formula)?;
+        match crate::calc::evaluate_exact(&parameters.formula) {
+            Some(result) => result.map_err(|e| e.into()),
+            None => evaluate_f64(&parameters.formula, &parameters.vars.unwrap_or_default()),
+        }
+    }
+}
+
+
+/// 会話の要約ツールの引数（引数なし）
+#[derive(Deserialize, JsonSchema)]
+pub struct ConversationSummaryParams {}
+
+
+/// 現在の会話履歴を参照する組み込みツール
+pub struct ConversationSummary {
+    history: Arc<Vec<ChatMessage>>,
+}
+
+impl Tool for ConversationSummary {
+    type Params = ConversationSummaryParams;
+
+    fn name() -> &'static str {
+        "get_conversation_summary"
+    }
+
+    fn description() -> &'static str {
+        "これまでの会話について質問された場合に使用します。現在のターン数とユーザーが話した話題の一覧を取得します。"
+    }
+
+    async fn call(&mut self, _parameters: Self::Params) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        const TOPIC_MAX_CHARS: usize = 40;
+
+        let topics: Vec<String> = self.history.iter()
+            .filter(|message| message.role == MessageRole::User)
+            .map(|message| {
+                let line = message.content.lines().next().unwrap_or_default().trim();
+                if line.chars().count() > TOPIC_MAX_CHARS {
+                    format!("{}...", line.chars().take(TOPIC_MAX_CHARS).collect::<String>())
+                } else {
+                    line.to_string()
+                }
+            })
+            .collect();
+
+        let mut result = format!("ターン数: {}\n話題:", topics.len());
+        for (index, topic) in topics.iter().enumerate() {
+            result.push_str(&format!("\n{}. {}", index + 1, topic));
+        }
+        Ok(result)
+    }
+}
+
+
+/// 記憶ツールの引数
+#[derive(Deserialize, JsonSchema)]
+pub struct RememberParams {
+    /// 後で思い出すためのキー、例: "user_name"
+    key: String,
+    /// 記憶する内容
+    value: String,
+}
+
+
+/// 会話の履歴とは別に、キーと値を記憶する組み込みツール
+pub struct Remember {
+    memory: Arc<MemoryStore>,
+}
+
+impl Tool for Remember {
+    type Params = RememberParams;
+
+    fn name() -> &'static str {
+        "remember"
+    }
+
+    fn description() -> &'static str {
+        "ユーザーの名前や好みなど、会話が長くなっても覚えておくべき事柄をキーと値で記憶します。同じキーの値は上書きされます。"
+    }
+
+    async fn call(&mut self, parameters: Self::Params) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        self.memory.remember(parameters.key.trim(), &parameters.value)?;
+        Ok(format!("記憶しました: {}", parameters.key.trim()))
+    }
+}
+
+
+/// 記憶の呼び出しツールの引数
+#[derive(Deserialize, JsonSchema)]
+pub struct RecallParams {
+    /// `remember`で記憶した際のキー
+    key: String,
+}
+
+
+/// `remember`で記憶した値を返す組み込みツール
+pub struct Recall {
+    memory: Arc<MemoryStore>,
+}
+
+impl Tool for Recall {
+    type Params = RecallParams;
+
+    fn name() -> &'static str {
+        "recall"
+    }
+
+    fn description() -> &'static str {
+        "rememberで記憶した値をキーで取得します。キーが見つからない場合は、記憶しているキーの一覧を返します。"
+    }
+
+    async fn call(&mut self, parameters: Self::Params) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let key = parameters.key.trim();
+        if let Some(value) = self.memory.recall(key)? {
+            return Ok(value);
+        }
+        let keys = self.memory.keys()?;
+        if keys.is_empty() {
+            Ok(format!("キーが見つかりません: {}（記憶している項目はありません）", key))
+        } else {
+            Ok(format!("キーが見つかりません: {}\n記憶しているキー: {}", key, keys.join(", ")))
+        }
+    }
+}
+
+
+/// `fetch_url`ツールで使うHTTPクライアントを作成する
+///
+/// リダイレクトは接続先を確認しながら処理するため、自動では追わない。
+/// プライベートアドレスへの接続を許可しない場合は、接続するアドレスを名前解決の時点で確認し、
+/// 確認した後に名前解決の結果が変わる場合（DNS rebinding）やプロキシを経由する場合にも接続できないようにする。
+fn build_fetch_client(allow_private: bool) -> reqwest::Client {
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    let builder = reqwest::Client::builder()
+        .timeout(TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none());
+    let builder = if allow_private {
+        builder
+    } else {
+        builder.no_proxy().dns_resolver(Arc::new(PublicAddressResolver))
+    };
+    builder.build().unwrap_or_default()
+}
+
+
+/// localhostやプライベートアドレスに解決される名前を拒否する名前解決
+struct PublicAddressResolver;
+
+impl reqwest::dns::Resolve for PublicAddressResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addresses: Vec<std::net::SocketAddr> = tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            if addresses.iter().any(|address| is_private_address(&address.ip())) {
+                return Err(format!("localhostやプライベートアドレスには接続できません: {}", host).into());
+            }
+            Ok(Box::new(addresses.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+
+/// URL取得ツールの引数
+#[derive(Deserialize, JsonSchema)]
+pub struct FetchUrlParams {
+    /// 取得するページのURL（httpまたはhttps）、例: "https://example.com/"
+    url: String,
+}
+
+
+/// URLを取得して本文のテキストを返す組み込みツール
+///
+/// 内部のサービスへのアクセスに使われないよう、既定ではlocalhostやプライベートアドレスへの接続を拒否します。
+/// リダイレクト先も同様に確認します。
+pub struct FetchUrl {
+    http: reqwest::Client,
+    /// 返す本文の最大バイト数。超えた部分は切り捨てる
+    max_bytes: usize,
+    allow_private: bool,
+}
+
+impl Tool for FetchUrl {
+    type Params = FetchUrlParams;
+
+    fn name() -> &'static str {
+        "fetch_url"
+    }
+
+    fn description() -> &'static str {
+        "指定したURL（httpまたはhttps）のページを取得し、本文のテキストを返します。本文が上限のサイズを超える場合は、超えた部分が切り捨てられます。"
+    }
+
+    async fn call(&mut self, parameters: Self::Params) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        const MAX_REDIRECTS: usize = 5;
+
+        let mut url = reqwest::Url::parse(parameters.url.trim())?;
+        let mut redirects = 0;
+        let mut res = loop {
+            self.check_url(&url).await?;
+            let res = self.http.get(url.clone()).send().await?;
+            if !res.status().is_redirection() {
+                break res;
+            }
+            redirects += 1;
+            let location = res.headers().get(reqwest::header::LOCATION).and_then(|location| location.to_str().ok());
+            let Some(location) = location.filter(|_| redirects <= MAX_REDIRECTS) else {
+                return Err(format!("リダイレクトを処理できません: {}", url).into());
+            };
+            url = url.join(location)?;
+        };
+        if !res.status().is_success() {
+            return Err(format!("URLを取得できません: {} {}", url, res.status()).into());
+        }
+
+        let mut body = Vec::new();
+        let mut truncated = false;
+        while let Some(chunk) = res.chunk().await? {
+            body.extend_from_slice(&chunk);
+            if body.len() > self.max_bytes {
+                body.truncate(self.max_bytes);
+                truncated = true;
+                break;
+            }
+        }
+        let mut text = String::from_utf8_lossy(&body).into_owned();
+        if truncated {
+            // バイト数で切り詰めた末尾の不完全な文字を取り除く
+            text = text.trim_end_matches(char::REPLACEMENT_CHARACTER).to_string();
+            text.push_str(&format!("\n\n（本文が{}バイトを超えたため、以降を省略しました）", self.max_bytes));
+        }
+        Ok(text)
+    }
+}
+
+impl FetchUrl {
+    /// http(s)以外のURLと、許可していない場合のlocalhostやプライベートアドレスへのURLを拒否する
+    ///
+    /// ここでの確認は分かりやすいエラーを返すためのもので、接続するアドレスは`PublicAddressResolver`でも確認する。
+    async fn check_url(&self, url: &reqwest::Url) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(format!("http(s)以外のURLは取得できません: {}", url).into());
+        }
+        if self.allow_private {
+            return Ok(());
+        }
+
+        let host = url.host_str().ok_or_else(|| format!("URLにホスト名がありません: {}", url))?;
+        let port = url.port_or_known_default().unwrap_or(80);
+        let addresses = tokio::net::lookup_host((host.trim_matches(['[', ']']), port)).await?;
+        for address in addresses {
+            if is_private_address(&address.ip()) {
+                return Err(format!("localhostやプライベートアドレスのURLは取得できません: {}", url).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+
+fn is_private_address(ip: &std::net::IpAddr) -> bool {
+    match ip {
+        std::net::IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+            // キャリアグレードNAT（100.64.0.0/10）
+            || ip.is_broadcast() || (ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64),
+        std::net::IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_private_address(&std::net::IpAddr::V4(ip)),
+            // ユニークローカル（fc00::/7）とリンクローカル（fe80::/10）
+            None => ip.is_loopback() || ip.is_unspecified() || (ip.segments()[0] & 0xfe00) == 0xfc00 || (ip.segments()[0] & 0xffc0) == 0xfe80,
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc, sync::Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// `/api/chat`へのリクエストを記録し、用意した応答を順に返すOllamaの代わりのサーバーを起動する
+    ///
+    /// 用意した応答を返し終えた後は、最後の応答を繰り返す。
+    /// 応答が配列の場合は、要素を1行ずつストリーミングで返した後、接続を閉じずに待ち続ける。
+    async fn fake_ollama(responses: Vec<Value>) -> (reqwest::Url, Arc<Mutex<Vec<Value>>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = reqwest::Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buffer = Vec::new();
+                let mut chunk = [0; 4096];
+                let body_start = loop {
+                    let n = stream.read(&mut chunk).await.unwrap();
+                    buffer.extend_from_slice(&chunk[..n]);
+                    if let Some(pos) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+                        break pos + 4;
+                    }
+                };
+                let headers = String::from_utf8_lossy(&buffer[..body_start]).to_lowercase();
+                let length: usize = headers.lines()
+                    .find_map(|line| line.strip_prefix("content-length:"))
+                    .and_then(|length| length.trim().parse().ok())
+                    .unwrap_or(0);
+                while buffer.len() < body_start + length {
+                    let n = stream.read(&mut chunk).await.unwrap();
+                    buffer.extend_from_slice(&chunk[..n]);
+                }
+                let request: Value = serde_json::from_slice(&buffer[body_start..]).unwrap();
+                let response = {
+                    let mut requests = recorded.lock().unwrap();
+                    requests.push(request);
+                    responses[(requests.len() - 1).min(responses.len() - 1)].clone()
+                };
+                if let Value::Array(lines) = response {
+                    stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-type: application/x-ndjson\r\nconnection: close\r\n\r\n").await.unwrap();
+                    for line in lines {
+                        stream.write_all(format!("{}\n", line).as_bytes()).await.unwrap();
+                    }
+                    tokio::spawn(async move {
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                        drop(stream);
+                    });
+                    continue;
+                }
+                let body = response.to_string();
+                let response = format!("HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}", body.len(), body);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    /// ストリーミングしない応答
+    fn reply(content: &str, tool_calls: Value) -> Value {
+        serde_json::json!({
+            "model": "m",
+            "created_at": "",
+            "message": { "role": "assistant", "content": content, "tool_calls": tool_calls },
+            "done": true,
+        })
+    }
+
+    /// テストから出力を確認できるよう、`StringRenderer`を共有して出力先とする
+    struct SharedRenderer(Rc<RefCell<StringRenderer>>);
+
+    impl OutputRenderer for SharedRenderer {
+        fn on_content_chunk(&mut self, chunk: &str) {
+            self.0.borrow_mut().on_content_chunk(chunk);
+        }
+
+        fn on_thinking_chunk(&mut self, chunk: &str) {
+            self.0.borrow_mut().on_thinking_chunk(chunk);
+        }
+
+        fn on_tool_call(&mut self, name: &str, arguments: &Value) {
+            self.0.borrow_mut().on_tool_call(name, arguments);
+        }
+
+        fn on_tool_result(&mut self, name: &str, result: &str) {
+            self.0.borrow_mut().on_tool_result(name, result);
+        }
+
+        fn on_notice(&mut self, message: &str) {
+            self.0.borrow_mut().on_notice(message);
+        }
+
+        fn on_error(&mut self, error: &str) {
+            self.0.borrow_mut().on_error(error);
+        }
+
+        fn on_done(&mut self) {}
+    }
+
+    /// 用意した応答を返すサーバーに接続し、ストリーミングせずに応答させる
+    async fn test_chat(responses: Vec<Value>) -> (Chat, Arc<Mutex<Vec<Value>>>, Rc<RefCell<StringRenderer>>) {
+        let (url, requests) = fake_ollama(responses).await;
+        let mut chat = Chat::new(&url, "m", "m");
+        let output = Rc::new(RefCell::new(StringRenderer::default()));
+        chat.set_renderer(Box::new(SharedRenderer(output.clone())));
+        chat.set_stream(false);
+        chat.set_enabled_tools(vec!["calculator".to_string()]);
+        (chat, requests, output)
+    }
+
+    fn roles(request: &Value) -> Vec<&str> {
+        request["messages"].as_array().unwrap().iter().map(|message| message["role"].as_str().unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn tool_follow_up_keeps_conversation_order() {
+        let (mut chat, requests, output) = test_chat(vec![
+            reply("こんにちは", serde_json::json!([])),
+            reply("", serde_json::json!([{ "function": { "name": "calculator", "arguments": { "formula": "1+1" } } }])),
+            reply("答えは2です", serde_json::json!([])),
+        ]).await;
+        chat.set_system_prompt(Some("あなたはアシスタントです".to_string()));
+        chat.generate_response("こんにちは").await;
+        chat.generate_response("1+1は？").await;
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        // ツールの結果を返すリクエストには、それまでの会話、ツール呼び出し、結果がこの順に含まれる
+        let follow_up = &requests[2];
+        assert_eq!(roles(follow_up), ["system", "user", "assistant", "user", "assistant", "tool"]);
+        assert_eq!(follow_up["messages"][0]["content"], "あなたはアシスタントです");
+        assert_eq!(follow_up["messages"][1]["content"], "こんにちは");
+        assert_eq!(follow_up["messages"][3]["content"], "1+1は？");
+        assert_eq!(follow_up["messages"][4]["tool_calls"][0]["function"]["name"], "calculator");
+        assert_eq!(follow_up["messages"][5]["content"], "2");
+
+        let output = output.borrow();
+        assert_eq!(output.tool_calls, [("calculator".to_string(), serde_json::json!({ "formula": "1+1" }))]);
+        assert_eq!(output.content, "こんにちは答えは2です");
+        assert!(output.errors.is_empty(), "{:?}", output.errors);
+    }
+
+    #[tokio::test]
+    async fn thinking_only_response_is_not_added_to_history() {
+        let (mut chat, requests, output) = test_chat(vec![reply("<think>考え中</think>\n", serde_json::json!([]))]).await;
+        assert!(chat.is_empty_response("<think>考え中</think>\n"));
+        assert!(!chat.is_empty_response("<think>考え中</think>答え"));
+
+        chat.generate_response("こんにちは").await;
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        // 発言と応答の組を保つよう、発言も履歴に残さない
+        assert!(chat.get_history().is_empty());
+        assert!(output.borrow().notices.iter().any(|notice| notice.contains("not added to the history")));
+    }
+
+    #[tokio::test]
+    async fn empty_retry_keeps_history_in_pairs() {
+        let (mut chat, requests, output) = test_chat(vec![
+            reply("答え", serde_json::json!([])),
+            reply("<think>考え中</think>", serde_json::json!([])),
+            reply(" \n", serde_json::json!([])),
+            reply("次の答え", serde_json::json!([])),
+        ]).await;
+        chat.set_retry_empty(true);
+        chat.generate_response("最初").await;
+        // 再生成しても空の場合は、発言ごと履歴に残さない
+        chat.generate_response("空になる発言").await;
+        chat.generate_response("次").await;
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(roles(&requests[3]), ["user", "assistant", "user"]);
+        assert_eq!(requests[3]["messages"][2]["content"], "次");
+        let history: Vec<&str> = chat.get_history().iter().map(|message| message.content.as_str()).collect();
+        assert_eq!(history, ["最初", "答え", "次", "次の答え"]);
+        assert_eq!(output.borrow().notices.iter().filter(|notice| notice.contains("not added to the history")).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn empty_response_is_retried_once_with_another_seed() {
+        let (mut chat, requests, output) = test_chat(vec![
+            reply("<think>考え中</think>", serde_json::json!([])),
+            reply("答え", serde_json::json!([])),
+        ]).await;
+        chat.set_retry_empty(true);
+        chat.set_seed(42);
+        chat.generate_response("こんにちは").await;
+        chat.generate_response("もう一度").await;
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0]["options"]["seed"], 42);
+        assert_eq!(requests[1]["options"]["seed"], 43);
+        // 変えたシードは再生成のみに使う
+        assert_eq!(requests[2]["options"]["seed"], 42);
+        assert_eq!(chat.get_seed(), 42);
+
+        assert_eq!(roles(&requests[2]), ["user", "assistant", "user"]);
+        assert_eq!(chat.get_history()[1].content, "答え");
+        // 空の応答は表示しない
+        assert_eq!(output.borrow().content, "答え答え");
+        assert_eq!(output.borrow().thinking, "");
+    }
+
+    #[tokio::test]
+    async fn cancel_keeps_partial_streamed_response() {
+        let chunk = serde_json::json!({ "model": "m", "created_at": "", "message": { "role": "assistant", "content": "途中まで" }, "done": false });
+        let (mut chat, _, output) = test_chat(vec![serde_json::json!([chunk])]).await;
+        chat.set_stream(true);
+        let cancel = CancellationToken::new();
+        chat.set_cancellation_token(cancel.clone());
+        // 応答の一部が届いた後に中断する
+        let cancel_after_output = async {
+            while output.borrow().content.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            cancel.cancel();
+        };
+        let generation = async { tokio::join!(chat.generate_response("長い話"), cancel_after_output) };
+        tokio::time::timeout(Duration::from_secs(5), generation).await.unwrap();
+
+        let history: Vec<&str> = chat.get_history().iter().map(|message| message.content.as_str()).collect();
+        assert_eq!(history, ["長い話", "途中まで"]);
+        assert!(output.borrow().errors.is_empty(), "{:?}", output.borrow().errors);
+    }
+
+    #[tokio::test]
+    async fn tool_rounds_are_capped() {
+        let call = reply("", serde_json::json!([{ "function": { "name": "calculator", "arguments": { "formula": "1+1" } } }]));
+        let (mut chat, requests, output) = test_chat(vec![call.clone(), call.clone(), call, reply("答えは2です", serde_json::json!([]))]).await;
+        chat.set_max_tool_rounds(2);
+        chat.generate_response("1+1は？").await;
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 4);
+        let has_tools = |request: &Value| request["tools"].as_array().is_some_and(|tools| !tools.is_empty());
+        assert!(requests[..3].iter().all(has_tools));
+        // 上限に達した後の呼び出しは実行せず、ツールを渡さずに回答させる
+        assert!(!has_tools(&requests[3]));
+        assert_eq!(roles(&requests[3]), ["user", "assistant", "tool", "assistant", "tool", "assistant", "tool"]);
+        assert!(requests[3]["messages"][6]["content"].as_str().unwrap().contains("上限（2回）"));
+
+        let output = output.borrow();
+        assert_eq!(output.tool_calls.len(), 2);
+        assert!(output.notices.iter().any(|notice| notice.contains("limit of 2 tool call rounds")));
+        assert_eq!(output.content, "答えは2です");
+    }
+
+    #[tokio::test]
+    async fn fetch_client_rejects_names_resolving_to_private_addresses() {
+        // URLの確認を通らずに接続しても、名前解決の時点で拒否される
+        let http = build_fetch_client(false);
+        let error = http.get("http://localhost:9/").send().await.unwrap_err();
+        let mut source: Option<&dyn std::error::Error> = Some(&error);
+        let mut rejected = false;
+        while let Some(e) = source {
+            rejected |= e.to_string().contains("プライベートアドレス");
+            source = e.source();
+        }
+        assert!(rejected, "{:?}", error);
+    }
+
+    #[tokio::test]
+    async fn calculator_accepts_malformed_formulas() {
+        let formula = |arguments: Value| serde_json::from_value::<CalculatorParams>(arguments).unwrap().formula;
+        assert_eq!(formula(serde_json::json!({"formula": 42})), "42");
+        assert_eq!(formula(serde_json::json!({"formula": 1.5})), "1.5");
+        assert_eq!(formula(serde_json::json!({"formula": "  2+2\n"})), "2+2");
+        assert_eq!(formula(serde_json::json!({"formula": "`1+1`"})), "1+1");
+        assert_eq!(formula(serde_json::json!({"formula": "= 2+2"})), "2+2");
+        assert_eq!(formula(serde_json::json!({"formula": " ` 3*3 = ` "})), "3*3");
+        assert!(serde_json::from_value::<CalculatorParams>(serde_json::json!({"formula": ["1+1"]})).is_err());
+
+        let params = serde_json::from_value(serde_json::json!({"formula": "= `2+2`"})).unwrap();
+        assert_eq!(Calculator.call(params).await.unwrap(), "4");
+    }
+
+    #[test]
+    fn clean_title_truncates_japanese_and_emoji() {
+        assert_eq!(clean_title("「東京の天気について」", 5).as_deref(), Some("東京の天気"));
+        assert_eq!(clean_title("  \"Hello   world\"\n", 30).as_deref(), Some("Hello world"));
+        // 異体字セレクタの前では切らず、絵文字ごと取り除く
+        assert_eq!(clean_title("今日は晴れ☀\u{FE0F}です", 6).as_deref(), Some("今日は晴れ"));
+        // ゼロ幅接合子でつながった絵文字は途中で切らない
+        assert_eq!(clean_title("家族👨\u{200D}👩\u{200D}👧の話", 4).as_deref(), Some("家族"));
+        assert_eq!(clean_title("家族👨\u{200D}👩\u{200D}👧の話", 7).as_deref(), Some("家族👨\u{200D}👩\u{200D}👧"));
+        // 分解された濁点の前では切らない
+        assert_eq!(clean_title("ありか\u{3099}とう", 3).as_deref(), Some("あり"));
+        assert_eq!(clean_title("「」", 30), None);
+    }
+}
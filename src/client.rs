@@ -0,0 +1,934 @@
+//! OllamaのHTTP APIクライアント
+//!
+//! `/api/chat`へツール定義付きのリクエストを送信します。
+//! 応答はストリーミング（`chat_stream_with_tools`）と一括（`chat_with_tools`）のどちらでも受け取れます。
+//! `ApiFlavor::OpenAI`を指定した場合は、OpenAI互換の`/v1/chat/completions`へ送信し、SSEのストリーミングを受け取ります。
+//! 接続エラーと5xxの応答は、`max_retries`回まで指数バックオフで再試行します。4xxの応答は再試行しません。
+//! タイムアウトした場合は、サーバーが応答しない状態が続く可能性が高いため再試行しません。
+//! ストリーミングで応答の一部を表示した後に失敗した場合も、表示が2つの応答の混ざったものにならないよう再試行しません。
+//! ストリーミング中に`CancellationToken`が取り消された場合は、接続を閉じて`Cancelled`を返します。
+
+use std::{collections::{BTreeMap, VecDeque}, future::Future, time::{Duration, Instant}};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION};
+use ollama_rs::{generation::{chat::{ChatMessage, ChatMessageResponse, MessageRole}, parameters::{KeepAlive, TimeUnit}}, models::ModelOptions};
+use serde_json::{json, Value};
+use tokio_util::sync::CancellationToken;
+use crate::render::OutputRenderer;
+
+
+type ClientResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+
+/// 接続先のAPIの形式
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ApiFlavor {
+    /// Ollamaの`/api/chat`（NDJSONでストリーミング）
+    #[default]
+    Ollama,
+    /// OpenAI互換の`/v1/chat/completions`（SSEでストリーミング）
+    #[value(name = "openai")]
+    OpenAI,
+}
+
+
+pub struct OllamaClient {
+    http: reqwest::Client,
+    base_url: String,
+    flavor: ApiFlavor,
+    model_load_timeout: Duration,
+    max_retries: u32,
+    base_delay: Duration,
+    connect_timeout: Duration,
+    timeout: Duration,
+    /// ストリーミングの応答全体を受け取るまでのタイムアウト
+    stream_timeout: Duration,
+    /// ストリーミング中にツール呼び出しを受け取った時点で、残りの応答を受け取らずに打ち切る
+    stop_on_tool_call: bool,
+    /// 応答後にモデルをメモリに保持する時間。Noneの場合はサーバーの既定値（5分）
+    keep_alive: Option<KeepAlive>,
+    /// 全てのリクエストに付与するHTTPヘッダー（認証など）
+    headers: HeaderMap,
+}
+
+
+/// モデルのダウンロードの進捗
+pub struct PullProgress<'a> {
+    /// 状態の説明（例: "pulling manifest"、"downloading ..."、"success"）
+    pub status: &'a str,
+    /// ダウンロード済みのバイト数
+    pub completed: u64,
+    /// ダウンロードするバイト数。ダウンロード中以外はNone
+    pub total: Option<u64>,
+}
+
+
+/// 成功以外のHTTPステータスの応答
+#[derive(Debug)]
+struct StatusError {
+    status: reqwest::StatusCode,
+    body: String,
+}
+
+impl std::fmt::Display for StatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // 本文がJSONのエラーであれば、メッセージのみを表示する
+        let message = serde_json::from_str::<Value>(&self.body).ok()
+            .and_then(|value| error_message(&value))
+            .unwrap_or_else(|| self.body.clone());
+        write!(f, "{}", with_hint(Some(self.status), &message))
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+
+/// 生成の中断
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "生成を中断しました")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+
+/// `cancel`が取り消された時点で`future`を破棄し、`Cancelled`を返します。
+pub async fn cancellable<T>(cancel: &CancellationToken, future: impl Future<Output = ClientResult<T>>) -> ClientResult<T> {
+    tokio::select! {
+        biased;
+        _ = cancel.cancelled() => Err(Cancelled.into()),
+        result = future => result,
+    }
+}
+
+
+/// リクエストのタイムアウト
+#[derive(Debug)]
+struct TimeoutError {
+    timeout: Duration,
+}
+
+impl std::fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "request timed out after {} seconds", self.timeout.as_secs())
+    }
+}
+
+impl std::error::Error for TimeoutError {}
+
+
+impl OllamaClient {
+    pub fn new(base_url: &str) -> Self {
+        const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+        const TIMEOUT: Duration = Duration::from_secs(120);
+        const STREAM_TIMEOUT: Duration = Duration::from_secs(600);
+
+        OllamaClient {
+            http: build_http_client(CONNECT_TIMEOUT, TIMEOUT, &HeaderMap::new()),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            flavor: ApiFlavor::Ollama,
+            model_load_timeout: Duration::from_secs(300),
+            max_retries: 3,
+            base_delay: Duration::from_millis(500),
+            connect_timeout: CONNECT_TIMEOUT,
+            timeout: TIMEOUT,
+            stream_timeout: STREAM_TIMEOUT,
+            stop_on_tool_call: false,
+            keep_alive: None,
+            headers: HeaderMap::new(),
+        }
+    }
+
+    /// 接続先のAPIの形式を設定します。
+    pub fn set_api_flavor(&mut self, flavor: ApiFlavor) {
+        self.flavor = flavor;
+    }
+
+    /// 接続、リクエスト全体、ストリーミングの応答全体のタイムアウトを設定します。
+    ///
+    /// 生成には数分かかる場合があるため、ストリーミングには長めのタイムアウトを指定してください。
+    pub fn set_timeouts(&mut self, connect_timeout: Duration, timeout: Duration, stream_timeout: Duration) {
+        self.http = build_http_client(connect_timeout, timeout, &self.headers);
+        self.connect_timeout = connect_timeout;
+        self.timeout = timeout;
+        self.stream_timeout = stream_timeout;
+    }
+
+    /// 全てのリクエストに付与するHTTPヘッダー（認証など）を設定します。
+    pub fn set_headers(&mut self, headers: HeaderMap) {
+        self.http = build_http_client(self.connect_timeout, self.timeout, &headers);
+        self.headers = headers;
+    }
+
+    /// 一時的なエラーで再試行する回数と、最初の再試行までの待ち時間を設定します。
+    ///
+    /// 待ち時間は再試行のたびに2倍になります。
+    pub fn set_retry(&mut self, max_retries: u32, base_delay: Duration) {
+        self.max_retries = max_retries;
+        self.base_delay = base_delay;
+    }
+
+    /// ストリーミング中に完全なツール呼び出しを受け取った時点で、残りの応答を待たずに打ち切るかを設定します。
+    ///
+    /// 打ち切った後の生成が無駄になりませんが、本文とツール呼び出しを交互に返すサーバーでは、
+    /// 後に続く本文や複数のツール呼び出しのうち2つ目以降を受け取れません。
+    pub fn set_stop_on_tool_call(&mut self, stop_on_tool_call: bool) {
+        self.stop_on_tool_call = stop_on_tool_call;
+    }
+
+    /// 応答後にモデルをメモリに保持する時間を設定します（Ollamaのみ）。
+    pub fn set_keep_alive(&mut self, keep_alive: Option<KeepAlive>) {
+        self.keep_alive = keep_alive;
+    }
+
+    pub fn get_keep_alive(&self) -> Option<&KeepAlive> {
+        self.keep_alive.as_ref()
+    }
+
+    /// モデルの読み込み中に再試行を続ける最大時間を設定します。
+    pub fn set_model_load_timeout(&mut self, timeout: Duration) {
+        self.model_load_timeout = timeout;
+    }
+
+    /// サーバーにあるモデルの名前を取得します（Ollamaは`GET /api/tags`、OpenAI互換は`GET /v1/models`）。
+    pub async fn list_models(&self) -> ClientResult<Vec<String>> {
+        let (path, list_key, name_key) = match self.flavor {
+            ApiFlavor::Ollama => ("/api/tags", "models", "name"),
+            ApiFlavor::OpenAI => ("/v1/models", "data", "id"),
+        };
+
+        let url = format!("{}{}", self.base_url, path);
+        let res = self.http.get(&url).send().await.map_err(|e| timeout_error(e, self.timeout))?;
+        let status = res.status();
+        if !status.is_success() {
+            return Err(Box::new(StatusError { status, body: res.text().await? }));
+        }
+        let value: Value = res.json().await.map_err(|e| timeout_error(e, self.timeout))?;
+        let models = value[list_key].as_array().ok_or("モデルの一覧を取得できません")?;
+        Ok(models.iter().filter_map(|model| model[name_key].as_str()).map(|name| name.to_string()).collect())
+    }
+
+    /// モデルが対応するコンテキストの長さを`POST /api/show`で取得します。
+    ///
+    /// OpenAI互換APIには取得する方法がないため、常にNoneを返します。
+    pub async fn context_length(&self, model: &str) -> ClientResult<Option<u64>> {
+        if self.flavor == ApiFlavor::OpenAI {
+            return Ok(None);
+        }
+
+        let url = format!("{}/api/show", self.base_url);
+        let res = self.http.post(&url).json(&json!({ "model": model })).send().await.map_err(|e| timeout_error(e, self.timeout))?;
+        let status = res.status();
+        if !status.is_success() {
+            return Err(Box::new(StatusError { status, body: res.text().await? }));
+        }
+        let value: Value = res.json().await.map_err(|e| timeout_error(e, self.timeout))?;
+        // キーはモデルのアーキテクチャごとに異なる（例: qwen3.context_length）
+        let context_length = value["model_info"].as_object().and_then(|info| {
+            info.iter()
+                .find(|(key, _)| key.ends_with(".context_length"))
+                .and_then(|(_, value)| value.as_u64())
+        });
+        Ok(context_length)
+    }
+
+    /// モデルを`POST /api/pull`でダウンロードします（Ollamaのみ）。
+    ///
+    /// 進捗の状態を受け取るたびに`on_progress`を呼び出します。
+    pub async fn pull_model(&self, name: &str, on_progress: &mut dyn FnMut(&PullProgress)) -> ClientResult<()> {
+        // 大きなモデルのダウンロードには時間がかかるため、通常のタイムアウトは適用しない
+        const PULL_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+        if self.flavor != ApiFlavor::Ollama {
+            return Err("モデルのダウンロードはOllamaのみ対応しています".into());
+        }
+
+        let url = format!("{}/api/pull", self.base_url);
+        let mut res = self.http.post(&url).json(&json!({ "model": name, "stream": true })).timeout(PULL_TIMEOUT).send().await
+            .map_err(|e| timeout_error(e, PULL_TIMEOUT))?;
+        let status = res.status();
+        if !status.is_success() {
+            return Err(Box::new(StatusError { status, body: res.text().await? }));
+        }
+
+        let mut buffer = Vec::new();
+        while let Some(bytes) = res.chunk().await.map_err(|e| timeout_error(e, PULL_TIMEOUT))? {
+            buffer.extend_from_slice(&bytes);
+            while let Some(pos) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=pos).collect();
+                let line = String::from_utf8_lossy(&line);
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let value = parse_json(line.trim())?;
+                if let Some(error) = error_message(&value) {
+                    return Err(error.into());
+                }
+                on_progress(&PullProgress {
+                    status: value["status"].as_str().unwrap_or_default(),
+                    completed: value["completed"].as_u64().unwrap_or_default(),
+                    total: value["total"].as_u64(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// モデルを`POST /api/generate`でメモリに読み込みます（Ollamaのみ）。
+    ///
+    /// プロンプトを指定せずに生成を要求すると、Ollamaはモデルを読み込んだ時点で応答します。
+    pub async fn load_model(&self, model: &str) -> ClientResult<()> {
+        if self.flavor != ApiFlavor::Ollama {
+            return Err("モデルの事前読み込みはOllamaのみ対応しています".into());
+        }
+
+        let url = format!("{}/api/generate", self.base_url);
+        let mut request = json!({ "model": model });
+        if let Some(keep_alive) = self.keep_alive.as_ref() {
+            request["keep_alive"] = json!(keep_alive);
+        }
+        // 大きなモデルは読み込みに時間がかかるため、読み込みを待つ時間までは待つ
+        let timeout = self.timeout.max(self.model_load_timeout);
+        let res = self.http.post(&url).json(&request).timeout(timeout).send().await
+            .map_err(|e| timeout_error(e, timeout))?;
+        let status = res.status();
+        if !status.is_success() {
+            return Err(Box::new(StatusError { status, body: res.text().await? }));
+        }
+        Ok(())
+    }
+
+    /// 送信するリクエストの本文を、APIの形式に合わせて作成します。
+    pub fn request_body(&self, messages: &[ChatMessage], model: &str, tools: &[Value], options: &ModelOptions, stream: bool) -> Value {
+        match self.flavor {
+            ApiFlavor::Ollama => chat_request(messages, model, tools, options, self.keep_alive.as_ref(), stream),
+            ApiFlavor::OpenAI => openai_request(messages, model, tools, options, stream),
+        }
+    }
+
+    /// ストリーミングせずに応答を一括で受け取ります。
+    pub async fn chat_with_tools(&self, messages: &[ChatMessage], model: &str, tools: &[Value], options: &ModelOptions, renderer: &mut dyn OutputRenderer) -> ClientResult<ChatMessageResponse> {
+        let request = self.request_body(messages, model, tools, options, false);
+        let mut attempt = 0;
+        loop {
+            let result: ClientResult<ChatMessageResponse> = async {
+                let res = self.post_chat(&request, self.timeout, renderer).await?;
+                let body = res.text().await.map_err(|e| timeout_error(e, self.timeout))?;
+                log::debug!("response: {}", body);
+                let value = parse_json(&body)?;
+                match self.flavor {
+                    ApiFlavor::Ollama => Ok(serde_json::from_value(with_final_data_defaults(value))?),
+                    ApiFlavor::OpenAI => openai_response(&value),
+                }
+            }.await;
+            match result {
+                Err(e) if attempt < self.max_retries && is_retryable(e.as_ref()) => {
+                    self.wait_for_retry(attempt, e.as_ref(), renderer).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// 応答をストリーミングで受け取り、届いた順にrendererへ渡します。
+    ///
+    /// 戻り値の`message`には、全てのチャンクの内容とツール呼び出しをまとめたものが入ります。
+    /// 再試行した応答は表示済みの内容と一致するとは限らないため、応答の一部を表示した後に失敗した場合は再試行しません。
+    /// `cancel`が取り消された場合は、受信を止めて`Cancelled`を返します。それまでの内容はrendererへ渡し済みです。
+    pub async fn chat_stream_with_tools(&self, messages: &[ChatMessage], model: &str, tools: &[Value], options: &ModelOptions, renderer: &mut dyn OutputRenderer, cancel: &CancellationToken) -> ClientResult<ChatMessageResponse> {
+        let request = self.request_body(messages, model, tools, options, true);
+        let mut printed = 0;
+        let mut attempt = 0;
+        loop {
+            match self.stream_chat(&request, renderer, &mut printed, cancel).await {
+                Err(e) if printed == 0 && attempt < self.max_retries && is_retryable(e.as_ref()) => {
+                    cancellable(cancel, async {
+                        self.wait_for_retry(attempt, e.as_ref(), renderer).await;
+                        Ok(())
+                    }).await?;
+                    attempt += 1;
+                }
+                Err(e) if printed > 0 && is_retryable(e.as_ref()) => {
+                    log::warn!("not retrying because part of the response was already shown: {}", e);
+                    return Err(e);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// 1回分のストリーミングを受け取ります。`printed`はこれまでに表示した内容のバイト数です。
+    async fn stream_chat(&self, request: &Value, renderer: &mut dyn OutputRenderer, printed: &mut usize, cancel: &CancellationToken) -> ClientResult<ChatMessageResponse> {
+        let mut res = cancellable(cancel, self.post_chat(request, self.stream_timeout, renderer)).await?;
+
+        let mut buffer = Vec::new();
+        let mut result: Option<ChatMessageResponse> = None;
+        let mut tool_calls = ToolCallDeltas::new();
+        // 中断した場合は応答を破棄して接続を閉じ、サーバー側の生成も止める
+        'stream: while let Some(bytes) = cancellable(cancel, async { res.chunk().await.map_err(|e| timeout_error(e, self.stream_timeout)) }).await? {
+            buffer.extend_from_slice(&bytes);
+            while let Some(pos) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=pos).collect();
+                self.merge_line(&mut result, &mut tool_calls, &line, renderer, printed)?;
+                if self.stop_on_tool_call && has_complete_tool_call(&result, &tool_calls) {
+                    // 応答を破棄して接続を閉じると、サーバー側の生成も止まる
+                    log::info!("stopped streaming at a tool call");
+                    buffer.clear();
+                    break 'stream;
+                }
+            }
+            if crate::render::output_closed() {
+                log::info!("stopped streaming because the output was closed");
+                buffer.clear();
+                break;
+            }
+        }
+        if !buffer.is_empty() {
+            self.merge_line(&mut result, &mut tool_calls, &buffer, renderer, printed)?;
+        }
+
+        let mut result = result.ok_or("応答が空です")?;
+        for (index, (name, arguments)) in tool_calls {
+            if name.is_empty() {
+                log::warn!("ignored a streamed tool call without a name (index {})", index);
+                continue;
+            }
+            // 途中で打ち切った場合は、引数が揃っていないツール呼び出しを除く
+            if self.stop_on_tool_call && serde_json::from_str::<Value>(&arguments).is_err() {
+                continue;
+            }
+            result.message.tool_calls.push(serde_json::from_value(tool_call(&name, &arguments))?);
+        }
+        Ok(result)
+    }
+
+    fn merge_line(&self, result: &mut Option<ChatMessageResponse>, tool_calls: &mut ToolCallDeltas, line: &[u8], renderer: &mut dyn OutputRenderer, printed: &mut usize) -> ClientResult<()> {
+        log::debug!("stream: {}", String::from_utf8_lossy(line).trim_end());
+        match self.flavor {
+            ApiFlavor::Ollama => merge_chunk(result, line, renderer, printed),
+            ApiFlavor::OpenAI => merge_sse_line(result, tool_calls, line, renderer, printed),
+        }
+    }
+
+    async fn wait_for_retry(&self, attempt: u32, error: &(dyn std::error::Error + 'static), renderer: &mut dyn OutputRenderer) {
+        let delay = self.base_delay.saturating_mul(2u32.saturating_pow(attempt));
+        log::warn!("request failed: {} (retry {}/{})", error, attempt + 1, self.max_retries);
+        renderer.on_notice(&format!("request failed ({}), retrying in {:.1}s ({}/{})...", error, delay.as_secs_f64(), attempt + 1, self.max_retries));
+        tokio::time::sleep(delay).await;
+    }
+
+    /// リクエストを送信します。モデルの読み込み中の場合は読み込みが終わるまで再試行します。
+    async fn post_chat(&self, request: &Value, timeout: Duration, renderer: &mut dyn OutputRenderer) -> ClientResult<reqwest::Response> {
+        const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+        let path = match self.flavor {
+            ApiFlavor::Ollama => "/api/chat",
+            ApiFlavor::OpenAI => "/v1/chat/completions",
+        };
+        let url = format!("{}{}", self.base_url, path);
+        log::debug!("POST {} {}", url, request);
+        let start = Instant::now();
+        let mut notified = false;
+        loop {
+            let res = self.http.post(&url).json(request).timeout(timeout).send().await
+                .map_err(|e| timeout_error(e, timeout))?;
+            if res.status().is_success() {
+                return Ok(res);
+            }
+
+            let status = res.status();
+            let body = res.text().await?;
+            log::warn!("POST {} returned {}: {}", url, status, body);
+            if !is_model_loading(status, &body) || start.elapsed() + RETRY_INTERVAL > self.model_load_timeout {
+                return Err(Box::new(StatusError { status, body }));
+            }
+
+            if !notified {
+                renderer.on_notice("model loading, please wait...");
+                notified = true;
+            }
+            tokio::time::sleep(RETRY_INTERVAL).await;
+        }
+    }
+}
+
+
+fn build_http_client(connect_timeout: Duration, timeout: Duration, headers: &HeaderMap) -> reqwest::Client {
+    reqwest::Client::builder()
+        .default_headers(headers.clone())
+        .connect_timeout(connect_timeout)
+        .timeout(timeout)
+        .build()
+        .expect("Unable to build HTTP client")
+}
+
+
+/// タイムアウトの場合は、待った時間がわかるエラーに置き換える
+fn timeout_error(error: reqwest::Error, timeout: Duration) -> Box<dyn std::error::Error + Send + Sync> {
+    if error.is_timeout() {
+        Box::new(TimeoutError { timeout })
+    } else {
+        Box::new(error)
+    }
+}
+
+
+fn chat_request(messages: &[ChatMessage], model: &str, tools: &[Value], options: &ModelOptions, keep_alive: Option<&KeepAlive>, stream: bool) -> Value {
+    let mut request = json!({
+        "model": model,
+        "messages": messages,
+        "options": options,
+        "stream": stream,
+    });
+    if let Some(keep_alive) = keep_alive {
+        request["keep_alive"] = json!(keep_alive);
+    }
+    // ツールに対応していないモデルもあるため、ツールがない場合は送信しない
+    if !tools.is_empty() {
+        request["tools"] = json!(tools);
+    }
+    request
+}
+
+
+/// `--keep-alive`の値を読み込む
+///
+/// `-1`は読み込んだまま保持し、`0`は応答後すぐに解放します。
+/// それ以外は秒数、または`30s`、`5m`、`2h`のように単位を付けた時間で指定します。
+pub fn parse_keep_alive(value: &str) -> Result<KeepAlive, String> {
+    let value = value.trim();
+    match value {
+        "-1" => return Ok(KeepAlive::Indefinitely),
+        "0" => return Ok(KeepAlive::UnloadOnCompletion),
+        _ => {}
+    }
+
+    let (number, unit) = match value.char_indices().last() {
+        Some((index, 's')) => (&value[..index], TimeUnit::Seconds),
+        Some((index, 'm')) => (&value[..index], TimeUnit::Minutes),
+        Some((index, 'h')) => (&value[..index], TimeUnit::Hours),
+        _ => (value, TimeUnit::Seconds),
+    };
+    match number.parse::<u64>() {
+        Ok(0) => Ok(KeepAlive::UnloadOnCompletion),
+        Ok(time) => Ok(KeepAlive::Until { time, unit }),
+        Err(_) => Err(format!("invalid duration \"{}\" (use -1, 0, or a number with s/m/h, e.g. 5m)", value)),
+    }
+}
+
+
+/// `--header`の値（`name=value`）を読み込む
+///
+/// 認証に関するヘッダーの値は、ログなどに表示されないよう機密として扱います。
+pub fn parse_header(value: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let Some((name, header_value)) = value.split_once('=') else {
+        return Err(format!("invalid header \"{}\" (use name=value)", value));
+    };
+    let name = HeaderName::from_bytes(name.trim().as_bytes()).map_err(|e| format!("invalid header name \"{}\": {}", name, e))?;
+    let mut header_value = HeaderValue::from_str(header_value.trim()).map_err(|e| format!("invalid value for header \"{}\": {}", name, e))?;
+    if name == AUTHORIZATION || name == PROXY_AUTHORIZATION || name == COOKIE {
+        header_value.set_sensitive(true);
+    }
+    Ok((name, header_value))
+}
+
+
+/// `--base-url`の値を読み込む
+///
+/// スキームがhttpまたはhttpsで、ホスト名を含むURLのみを受け付けます。
+/// リバースプロキシのパス（例: `https://example.com/ollama`）を含めることができ、APIのパスはその後に続けます。
+pub fn parse_base_url(value: &str) -> Result<reqwest::Url, String> {
+    let mut url = reqwest::Url::parse(value.trim()).map_err(|e| format!("invalid URL \"{}\": {}", value, e))?;
+    if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+        return Err(format!("invalid URL \"{}\" (use http:// or https:// with a host name)", value));
+    }
+    if url.query().is_some() || url.fragment().is_some() {
+        return Err(format!("invalid URL \"{}\" (query and fragment are not allowed)", value));
+    }
+    // パスの末尾に`/`がないと、APIのパスを続けた時に最後の部分が置き換えられてしまう
+    if !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+    Ok(url)
+}
+
+
+/// OpenAI互換APIのリクエストの本文を作成する
+fn openai_request(messages: &[ChatMessage], model: &str, tools: &[Value], options: &ModelOptions, stream: bool) -> Value {
+    let mut request = json!({
+        "model": model,
+        "messages": openai_messages(messages),
+        "stream": stream,
+    });
+    // Ollama固有のオプション（スレッド数、top_k、num_ctxなど）は送信せず、OpenAIのAPIにある項目のみを渡す
+    if let Ok(options) = serde_json::to_value(options) {
+        for key in ["seed", "temperature", "top_p"] {
+            if let Some(value) = options.get(key) {
+                request[key] = value.clone();
+            }
+        }
+    }
+    if !tools.is_empty() {
+        request["tools"] = json!(tools);
+    }
+    request
+}
+
+
+/// base64でエンコードされた画像の先頭のバイト列から、画像の形式を判定する。判定できない場合はPNGとして扱う
+fn image_mime_type(base64: &str) -> &'static str {
+    use base64::{prelude::BASE64_STANDARD, Engine};
+
+    // 16文字（12バイト）あればWebPの識別子まで判定できる
+    let head = BASE64_STANDARD.decode(base64.get(..16).unwrap_or(base64)).unwrap_or_default();
+    if head.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        "image/jpeg"
+    } else if head.starts_with(b"GIF8") {
+        "image/gif"
+    } else if head.starts_with(b"RIFF") && head.get(8..12) == Some(b"WEBP") {
+        "image/webp"
+    } else {
+        "image/png"
+    }
+}
+
+
+/// メッセージをOpenAI互換APIの形式に変換する
+///
+/// ツール呼び出しにはIDが必要なため連番で割り当て、続くツールの結果に呼び出しの順で対応付ける。
+fn openai_messages(messages: &[ChatMessage]) -> Vec<Value> {
+    let mut call_ids = VecDeque::new();
+    let mut next_id = 0;
+    messages.iter().map(|message| {
+        let role = match message.role {
+            MessageRole::User => "user",
+            MessageRole::Assistant => "assistant",
+            MessageRole::System => "system",
+            MessageRole::Tool => "tool",
+        };
+        let mut value = json!({ "role": role, "content": message.content });
+
+        if let Some(images) = message.images.as_ref().filter(|images| !images.is_empty()) {
+            let mut parts = vec![json!({ "type": "text", "text": message.content })];
+            parts.extend(images.iter().map(|image| json!({
+                "type": "image_url",
+                "image_url": { "url": format!("data:{};base64,{}", image_mime_type(image.to_base64()), image.to_base64()) },
+            })));
+            value["content"] = json!(parts);
+        }
+
+        if !message.tool_calls.is_empty() {
+            let tool_calls: Vec<Value> = message.tool_calls.iter().map(|call| {
+                let id = format!("call_{}", next_id);
+                next_id += 1;
+                call_ids.push_back(id.clone());
+                json!({
+                    "id": id,
+                    "type": "function",
+                    "function": { "name": call.function.name, "arguments": call.function.arguments.to_string() },
+                })
+            }).collect();
+            value["tool_calls"] = json!(tool_calls);
+        }
+        if message.role == MessageRole::Tool {
+            value["tool_call_id"] = json!(call_ids.pop_front().unwrap_or_default());
+        }
+        value
+    }).collect()
+}
+
+
+/// OpenAI互換APIの一括の応答を変換する
+fn openai_response(value: &Value) -> ClientResult<ChatMessageResponse> {
+    if let Some(error) = error_message(value) {
+        return Err(with_hint(None, &error).into());
+    }
+    let message = &value["choices"][0]["message"];
+    let tool_calls = message["tool_calls"].as_array().into_iter().flatten()
+        .map(|call| tool_call(call["function"]["name"].as_str().unwrap_or_default(), call["function"]["arguments"].as_str().unwrap_or_default()))
+        .collect();
+    assistant_response(&value["model"], message["content"].as_str().unwrap_or_default(), tool_calls, true)
+}
+
+
+/// 応答をJSONとして解析する。解析できない場合は、原因を調べられるよう受け取った内容をログに残す
+fn parse_json(text: &str) -> ClientResult<Value> {
+    serde_json::from_str(text).map_err(|e| {
+        log::warn!("failed to parse response: {} ({})", e, text);
+        e.into()
+    })
+}
+
+
+/// 応答のエラーメッセージ。OpenAI互換APIでは`error`がオブジェクトの場合がある
+fn error_message(value: &Value) -> Option<String> {
+    value["error"].as_str()
+        .or_else(|| value["error"]["message"].as_str())
+        .map(|error| error.to_string())
+}
+
+
+/// サーバーのエラーメッセージに、よくある原因への対処方法を付け加える
+fn with_hint(status: Option<reqwest::StatusCode>, message: &str) -> String {
+    let lower = message.to_lowercase();
+    let hint = if matches!(status, Some(reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN)) {
+        "check --auth-token and --header"
+    } else if lower.contains("model") && lower.contains("not found") {
+        "check the model name with `models`, or start with --pull to download it"
+    } else if ["out of memory", "requires more system memory", "insufficient memory", "cudamalloc failed"].iter().any(|pattern| lower.contains(pattern)) {
+        "the model does not fit in memory; try a smaller model, a smaller --num-ctx or a lower --num-gpu"
+    } else if ["invalid option", "invalid value", "cannot unmarshal"].iter().any(|pattern| lower.contains(pattern)) {
+        "check the model options (--temperature, --top-p, --top-k, --num-ctx, --num-gpu, --num-thread)"
+    } else {
+        return message.to_string();
+    };
+    format!("{} (hint: {})", message, hint)
+}
+
+
+fn assistant_response(model: &Value, content: &str, tool_calls: Vec<Value>, done: bool) -> ClientResult<ChatMessageResponse> {
+    Ok(serde_json::from_value(json!({
+        "model": model.as_str().unwrap_or_default(),
+        "created_at": "",
+        "message": { "role": "assistant", "content": content, "tool_calls": tool_calls },
+        "done": done,
+    }))?)
+}
+
+
+/// OpenAI互換APIのツール呼び出し（引数はJSON文字列）を、Ollamaの形式に変換する
+fn tool_call(name: &str, arguments: &str) -> Value {
+    let arguments = serde_json::from_str(arguments).unwrap_or_else(|_| json!({}));
+    json!({ "function": { "name": name, "arguments": arguments } })
+}
+
+
+/// ストリーミングの1行を、これまでに受け取った応答へ追加する
+fn merge_chunk(result: &mut Option<ChatMessageResponse>, line: &[u8], renderer: &mut dyn OutputRenderer, printed: &mut usize) -> ClientResult<()> {
+    let line = String::from_utf8_lossy(line);
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(());
+    }
+
+    let value = parse_json(line)?;
+    if let Some(error) = error_message(&value) {
+        return Err(with_hint(None, &error).into());
+    }
+    merge_response(result, serde_json::from_value(with_final_data_defaults(value))?, renderer, printed);
+    Ok(())
+}
+
+
+/// 完了した応答の統計情報のうち、欠けている項目を0で補う
+///
+/// プロンプトがキャッシュされた場合や古いサーバーでは一部の項目が省略され、統計情報全体が読み込まれなくなるため。
+fn with_final_data_defaults(mut value: Value) -> Value {
+    const FIELDS: [&str; 5] = ["total_duration", "prompt_eval_count", "prompt_eval_duration", "eval_count", "eval_duration"];
+
+    if value["done"].as_bool() == Some(true)
+        && let Some(object) = value.as_object_mut() {
+        for field in FIELDS {
+            object.entry(field).or_insert(json!(0));
+        }
+    }
+    value
+}
+
+
+/// ストリーミングで分割して届いたツール呼び出しの、`index`ごとの名前と引数
+///
+/// サーバーが返す`index`は連続しているとは限らないため、キーとして保持する。
+type ToolCallDeltas = BTreeMap<u64, (String, String)>;
+
+
+/// OpenAI互換APIのSSEの1行を、これまでに受け取った応答へ追加する
+///
+/// ツール呼び出しは名前と引数が分割されて届くため、`index`ごとに`tool_calls`に集めてから最後にまとめて追加する。
+fn merge_sse_line(result: &mut Option<ChatMessageResponse>, tool_calls: &mut ToolCallDeltas, line: &[u8], renderer: &mut dyn OutputRenderer, printed: &mut usize) -> ClientResult<()> {
+    let line = String::from_utf8_lossy(line);
+    // `event:`やコメント、イベントの区切りの空行は使用しない
+    let Some(data) = line.trim().strip_prefix("data:") else {
+        return Ok(());
+    };
+    let data = data.trim();
+    if data == "[DONE]" {
+        return Ok(());
+    }
+
+    let value = parse_json(data)?;
+    if let Some(error) = error_message(&value) {
+        return Err(with_hint(None, &error).into());
+    }
+    let choice = &value["choices"][0];
+    for call in choice["delta"]["tool_calls"].as_array().into_iter().flatten() {
+        let last = tool_calls.last_key_value().map(|(index, _)| *index);
+        let index = match (call["index"].as_u64(), last) {
+            (Some(index), _) => index,
+            // `index`を返さないサーバーでは、`id`のある断片を新しいツール呼び出しの始まりとみなす
+            (None, Some(last)) if !call["id"].is_string() => last,
+            (None, last) => last.map_or(0, |last| last + 1),
+        };
+        let (name, arguments) = tool_calls.entry(index).or_default();
+        if let Some(delta) = call["function"]["name"].as_str() {
+            name.push_str(delta);
+        }
+        if let Some(delta) = call["function"]["arguments"].as_str() {
+            arguments.push_str(delta);
+        }
+    }
+
+    let content = choice["delta"]["content"].as_str().unwrap_or_default();
+    let chunk = assistant_response(&value["model"], content, Vec::new(), !choice["finish_reason"].is_null())?;
+    merge_response(result, chunk, renderer, printed);
+    Ok(())
+}
+
+
+/// ツール呼び出しを1つ以上、引数まで全て受け取ったかを返す
+///
+/// Ollamaは1つのチャンクで完全なツール呼び出しを返す。OpenAI互換APIは引数を分割して返すため、JSONとして読める場合に揃ったとみなす。
+fn has_complete_tool_call(result: &Option<ChatMessageResponse>, tool_calls: &ToolCallDeltas) -> bool {
+    result.as_ref().is_some_and(|result| !result.message.tool_calls.is_empty())
+        || tool_calls.values().any(|(name, arguments)| !name.is_empty() && serde_json::from_str::<Value>(arguments).is_ok_and(|arguments| arguments.is_object()))
+}
+
+
+/// 受け取ったチャンクを応答へ追加し、まだ表示していない部分を表示する
+///
+/// `printed`は表示済みの内容のバイト数で、それより後の部分のみを表示する。
+fn merge_response(result: &mut Option<ChatMessageResponse>, chunk: ChatMessageResponse, renderer: &mut dyn OutputRenderer, printed: &mut usize) {
+    match result {
+        Some(result) => {
+            result.message.content.push_str(&chunk.message.content);
+            result.message.tool_calls.extend(chunk.message.tool_calls);
+            result.done = chunk.done;
+            if chunk.final_data.is_some() {
+                result.final_data = chunk.final_data;
+            }
+        }
+        None => *result = Some(chunk),
+    }
+
+    if let Some(result) = result.as_ref() {
+        let content = &result.message.content;
+        if content.len() > *printed && content.is_char_boundary(*printed) {
+            renderer.on_content_chunk(&content[*printed..]);
+            *printed = content.len();
+        }
+    }
+}
+
+
+/// 接続エラーとサーバー側のエラー（5xx）は一時的なものとして再試行する
+fn is_retryable(error: &(dyn std::error::Error + 'static)) -> bool {
+    if let Some(error) = error.downcast_ref::<StatusError>() {
+        return error.status.is_server_error();
+    }
+    if let Some(error) = error.downcast_ref::<reqwest::Error>() {
+        return error.is_connect() || error.is_request() || error.is_body();
+    }
+    false
+}
+
+
+/// モデルがツールに対応していないため、リクエストが拒否されたかを判定します。
+///
+/// Ollamaは`does not support tools`、OpenAI互換のサーバーの多くは`... not supported`という本文で400を返す。
+pub fn is_tools_unsupported(error: &(dyn std::error::Error + 'static)) -> bool {
+    let Some(error) = error.downcast_ref::<StatusError>() else {
+        return false;
+    };
+    let body = error.body.to_lowercase();
+    error.status == reqwest::StatusCode::BAD_REQUEST
+        && (body.contains("does not support tools") || (body.contains("tool") && body.contains("not supported")))
+}
+
+
+/// 503の場合でも、本文が読み込み中を示していなければ通常のエラーとして扱う
+fn is_model_loading(status: reqwest::StatusCode, body: &str) -> bool {
+    let body = body.to_lowercase();
+    status == reqwest::StatusCode::SERVICE_UNAVAILABLE && (body.contains("loading") || body.contains("busy"))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::StringRenderer;
+    use base64::{prelude::BASE64_STANDARD, Engine};
+
+    #[test]
+    fn image_mime_type_from_magic_bytes() {
+        let encode = |bytes: &[u8]| BASE64_STANDARD.encode(bytes);
+        assert_eq!(image_mime_type(&encode(&[0xFF, 0xD8, 0xFF, 0xE0, 0, 0x10, b'J', b'F', b'I', b'F', 0, 1])), "image/jpeg");
+        assert_eq!(image_mime_type(&encode(b"GIF89a\x01\x00\x01\x00\x00\x00")), "image/gif");
+        assert_eq!(image_mime_type(&encode(b"RIFF\x24\x00\x00\x00WEBPVP8 ")), "image/webp");
+        assert_eq!(image_mime_type(&encode(b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0d")), "image/png");
+    }
+
+    /// SSEの行を順に追加し、集めたツール呼び出しを返す
+    fn merge_sse_lines(lines: &[Value]) -> ToolCallDeltas {
+        let (mut result, mut tool_calls, mut printed) = (None, ToolCallDeltas::new(), 0);
+        let mut renderer = StringRenderer::default();
+        for line in lines {
+            let line = format!("data: {}", line);
+            merge_sse_line(&mut result, &mut tool_calls, line.as_bytes(), &mut renderer, &mut printed).unwrap();
+        }
+        tool_calls
+    }
+
+    fn tool_call_delta(index: Option<u64>, id: Option<&str>, name: Option<&str>, arguments: &str) -> Value {
+        let mut call = json!({ "function": { "arguments": arguments } });
+        if let Some(index) = index {
+            call["index"] = json!(index);
+        }
+        if let Some(id) = id {
+            call["id"] = json!(id);
+        }
+        if let Some(name) = name {
+            call["function"]["name"] = json!(name);
+        }
+        json!({ "model": "m", "choices": [{ "delta": { "tool_calls": [call] }, "finish_reason": null }] })
+    }
+
+    #[test]
+    fn sse_tool_call_arguments_split_across_chunks() {
+        let tool_calls = merge_sse_lines(&[
+            tool_call_delta(Some(0), Some("call_1"), Some("calculator"), "{\"for"),
+            tool_call_delta(Some(0), None, None, "mula\": \"1"),
+            tool_call_delta(Some(0), None, None, "+1\"}"),
+        ]);
+        assert_eq!(tool_calls.into_values().collect::<Vec<_>>(), vec![("calculator".to_string(), "{\"formula\": \"1+1\"}".to_string())]);
+    }
+
+    #[test]
+    fn sse_tool_calls_with_sparse_index() {
+        // 飛ばされた`index`を空のツール呼び出しで埋めず、大きな`index`でも確保しない
+        let tool_calls = merge_sse_lines(&[
+            tool_call_delta(Some(0), Some("call_1"), Some("calculator"), "{\"formula\": \"1\"}"),
+            tool_call_delta(Some(2), Some("call_2"), Some("get_datetime_now"), "{}"),
+            tool_call_delta(Some(4_000_000_000), Some("call_3"), Some("calculator"), "{\"formula\": \"2\"}"),
+        ]);
+        assert_eq!(tool_calls.keys().copied().collect::<Vec<_>>(), [0, 2, 4_000_000_000]);
+        assert!(tool_calls.values().all(|(name, _)| !name.is_empty()));
+    }
+
+    #[test]
+    fn sse_tool_calls_without_index_are_split_by_id() {
+        let tool_calls = merge_sse_lines(&[
+            tool_call_delta(None, Some("call_1"), Some("calculator"), "{\"formula\":"),
+            tool_call_delta(None, None, None, " \"2*3\"}"),
+            tool_call_delta(None, Some("call_2"), Some("get_datetime_now"), "{"),
+            tool_call_delta(None, None, None, "}"),
+        ]);
+        assert_eq!(tool_calls.into_values().collect::<Vec<_>>(), vec![
+            ("calculator".to_string(), "{\"formula\": \"2*3\"}".to_string()),
+            ("get_datetime_now".to_string(), "{}".to_string()),
+        ]);
+    }
+}
@@ -0,0 +1,55 @@
+//! 端末への出力の色付け
+//!
+//! ユーザー、アシスタント、ツールの表示をそれぞれ別の色で区別します。
+//! 環境変数`NO_COLOR`が設定されている場合や`--no-color`を指定した場合は、色を付けずにそのまま返します。
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+
+/// 色付けを有効にするかを設定します。`NO_COLOR`が空でない値で設定されている場合は常に無効です。
+pub fn init(no_color: bool) {
+    let env_no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    ENABLED.store(!no_color && !env_no_color, Ordering::Relaxed);
+}
+
+/// 色付けが有効かを返します。
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// ユーザーの入力を示すラベル（緑）
+pub fn user(text: &str) -> String {
+    paint("32", text)
+}
+
+/// アシスタントの応答を示すラベル（シアン）
+pub fn assistant(text: &str) -> String {
+    paint("36", text)
+}
+
+/// ツール呼び出しを示すラベル（黄）
+pub fn tool(text: &str) -> String {
+    paint("33", text)
+}
+
+/// 検索で一致した箇所（太字の赤）
+pub fn highlight(text: &str) -> String {
+    paint("1;31", text)
+}
+
+/// thinkingモデルの思考過程（灰）
+pub fn thinking(text: &str) -> String {
+    paint("90", text)
+}
+
+
+fn paint(code: &str, text: &str) -> String {
+    if enabled() {
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    } else {
+        text.to_string()
+    }
+}
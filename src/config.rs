@@ -0,0 +1,114 @@
+//! 設定ファイル（TOML）
+//!
+//! 接続先、モデル、タイムアウト、使用する組み込みツール、システムプロンプトを設定ファイルで指定できます。
+//! 設定は「既定値 < 設定ファイル < 環境変数 < コマンドライン引数」の順に優先されます。
+//! 設定ファイルは`--config`（環境変数`BRAIN_CONFIG`）で指定し、未指定時は
+//! `$XDG_CONFIG_HOME/brain/brain.toml`（未設定時は`~/.config/brain/brain.toml`）があれば読み込みます。
+//!
+//! ```toml
+//! host = "192.168.1.10"
+//! port = 11434
+//! tool_model = "qwen3:30b-a3b"
+//! timeout_secs = 300
+//! tools = ["calculator", "fetch_url"]
+//! system_prompt = "日本語で簡潔に答えてください。"
+//! ```
+
+use std::path::{Path, PathBuf};
+use clap::{parser::ValueSource, ArgMatches};
+use serde::Deserialize;
+use crate::Args;
+
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub tool_model: Option<String>,
+    pub vision_model: Option<String>,
+    pub title_model: Option<String>,
+    pub code_model: Option<String>,
+    pub timeout_secs: Option<u64>,
+    pub connect_timeout_secs: Option<u64>,
+    pub stream_timeout_secs: Option<u64>,
+    pub model_load_timeout: Option<u64>,
+    pub tool_timeout: Option<u64>,
+    pub max_tool_rounds: Option<usize>,
+    pub mcp_timeout_secs: Option<u64>,
+    pub tools: Option<Vec<String>>,
+    pub no_builtin_tools: Option<bool>,
+    pub system_prompt: Option<String>,
+    pub system_file: Option<String>,
+    pub persona: Option<String>,
+}
+
+
+/// 環境変数とコマンドライン引数のどちらでも指定されていない項目に、設定ファイルの値を設定する
+macro_rules! merge {
+    ($config:ident, $args:ident, $matches:ident; $($field:ident),* ; $($option:ident),*) => {
+        $(if let Some(value) = $config.$field && is_default($matches, stringify!($field)) {
+            $args.$field = value;
+        })*
+        $(if let Some(value) = $config.$option && is_default($matches, stringify!($option)) {
+            $args.$option = Some(value);
+        })*
+    };
+}
+
+
+impl Config {
+    /// 設定ファイルを読み込みます。
+    ///
+    /// パスを指定しない場合は既定の場所から読み込み、ファイルがなければNoneを返します。
+    /// 指定したファイルがない場合はエラーを返します。
+    pub fn load(path: Option<&str>) -> Result<Option<(PathBuf, Config)>, String> {
+        let path = match path {
+            Some(path) => PathBuf::from(path),
+            None => match Self::default_path().filter(|path| path.exists()) {
+                Some(path) => path,
+                None => return Ok(None),
+            },
+        };
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| format!("設定ファイルを読み込めませんでした: {}: {}", path.display(), e))?;
+        let config = toml::from_str(&text)
+            .map_err(|e| format!("設定ファイルの形式が正しくありません: {}: {}", path.display(), e))?;
+        Ok(Some((path, config)))
+    }
+
+    /// 既定の設定ファイルの場所を返します。ホームディレクトリが分からない場合はNoneを返します。
+    pub fn default_path() -> Option<PathBuf> {
+        let config_home = match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+            Some(dir) => PathBuf::from(dir),
+            None => Path::new(&std::env::var_os("HOME")?).join(".config"),
+        };
+        Some(config_home.join("brain").join("brain.toml"))
+    }
+
+    /// 環境変数とコマンドライン引数で指定されていない項目を、設定ファイルの値で上書きします。
+    pub fn apply(self, args: &mut Args, matches: &ArgMatches) {
+        let config = self;
+        merge!(config, args, matches;
+            host, port, tool_model, vision_model, timeout_secs, connect_timeout_secs, stream_timeout_secs,
+            model_load_timeout, tool_timeout, max_tool_rounds, mcp_timeout_secs;
+            title_model, code_model, system_prompt, system_file, persona);
+
+        // `--tools`と`--no-builtin-tools`は同時に指定できないため、片方が指定されている場合はもう片方を設定しない
+        let tools_unset = is_default(matches, "tools") && is_default(matches, "no_builtin_tools");
+        if tools_unset {
+            if let Some(tools) = config.tools {
+                args.tools = Some(tools);
+            }
+            if let Some(no_builtin_tools) = config.no_builtin_tools {
+                args.no_builtin_tools = no_builtin_tools;
+            }
+        }
+    }
+}
+
+
+/// 環境変数とコマンドライン引数のどちらでも指定されていないか
+fn is_default(matches: &ArgMatches, id: &str) -> bool {
+    matches.value_source(id).is_none_or(|source| source == ValueSource::DefaultValue)
+}
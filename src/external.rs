@@ -0,0 +1,216 @@
+//! 外部プログラムをツールとして呼び出す
+//!
+//! ツールの対応表（既定では`tools.json`）にツール名と実行するコマンドを記述します。
+//!
+//! ```json
+//! {
+//!     "get_weather": {
+//!         "command": "./weather.sh",
+//!         "args": ["--json"],
+//!         "description": "指定した都市の天気を取得します。",
+//!         "parameters": {
+//!             "type": "object",
+//!             "properties": { "city": { "type": "string", "description": "都市名" } },
+//!             "required": ["city"]
+//!         },
+//!         "timeout": 30
+//!     }
+//! }
+//! ```
+//!
+//! モデルがツールを呼び出すと、ユーザーの承認後にコマンドを実行します。
+//! `"policy": "auto"`を指定すると確認せずに実行し、`"deny"`を指定すると実行しません。
+//! * 標準入力: ツールの引数をJSONオブジェクトとして1行で渡し、標準入力を閉じます。
+//! * 標準出力: 出力された内容をそのままツールの結果としてモデルへ返します。
+//!   MCPの`CallToolResult`形式のJSON（`{"content":[{"type":"image","data":"<base64>","mimeType":"image/png"}]}`など）
+//!   を出力した場合は、テキストと画像に分けてモデルへ返します。
+//! * 終了コード: 0以外の場合は標準エラー出力の内容をエラーとして返します。
+//! * タイムアウト: `timeout`秒（既定30秒）を超えた場合はプロセスを終了し、エラーを返します。
+
+use std::{process::Stdio, time::Duration};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::{io::AsyncWriteExt, process::Command};
+use crate::mcp::SettingEntries;
+use crate::tools::{ToolDefinition, ToolFuture, ToolHandler, ToolOutput, ToolPolicy};
+use crate::verbosity::status;
+
+
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+
+#[derive(Debug, Clone)]
+pub struct ExternalTool {
+    name: String,
+    description: String,
+    parameters: Value,
+    command: String,
+    args: Vec<String>,
+    timeout: Duration,
+    policy: ToolPolicy,
+}
+
+
+impl ToolHandler for ExternalTool {
+    fn definition(&self) -> ToolDefinition {
+        ToolDefinition {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            parameters: self.parameters.clone(),
+        }
+    }
+
+    fn source(&self) -> String {
+        format!("external:{}", self.command)
+    }
+
+    fn policy(&self) -> ToolPolicy {
+        self.policy
+    }
+
+    fn call(&mut self, arguments: Value) -> ToolFuture<'_> {
+        Box::pin(async move {
+            let input = serde_json::to_string(&arguments)?;
+
+            let mut child = Command::new(&self.command)
+                .args(&self.args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()?;
+
+            // 標準入力を読まないツールでも止まらないよう、書き込みと終了の待機を同時に行い、両方をタイムアウトの対象にする
+            let stdin = child.stdin.take();
+            let write = async move {
+                let Some(mut stdin) = stdin else {
+                    return Ok(());
+                };
+                stdin.write_all(input.as_bytes()).await?;
+                stdin.write_all(b"\n").await
+            };
+            let (written, output) = match tokio::time::timeout(self.timeout, async { tokio::join!(write, child.wait_with_output()) }).await {
+                Ok(result) => result,
+                Err(_) => return Err(format!("外部ツールがタイムアウトしました: {}", self.name).into()),
+            };
+            // 入力を読まずに終了したツールの出力は、そのまま使う
+            match written {
+                Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => return Err(e.into()),
+                _ => {}
+            }
+            let output = output?;
+
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                return Err(format!("外部ツールが失敗しました: {} ({}) {}", self.name, output.status, stderr.trim()).into());
+            }
+            let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            if let Ok(result) = serde_json::from_str::<rmcp::model::CallToolResult>(&stdout) {
+                return Ok(crate::mcp::tool_output_from_content(&result.content));
+            }
+            Ok(ToolOutput::from(stdout))
+        })
+    }
+}
+
+
+/// 外部ツールの設定ファイルを読み込みます。ファイルがない場合は空の一覧を返します。
+pub fn load_external_tools(file_path: &str) -> Result<Vec<ExternalTool>, String> {
+    if !std::path::Path::new(file_path).exists() {
+        return Ok(Vec::new());
+    }
+
+    let json_data = std::fs::read_to_string(file_path)
+        .map_err(|e| format!("外部ツールの設定ファイルを読み込めません: {} {}", file_path, e))?;
+    let entries: SettingEntries = serde_json::from_str(&json_data)
+        .map_err(|e| format!("外部ツールの設定ファイルの形式が正しくありません: {} {}", file_path, e))?;
+
+    // モデルへ渡すツールの順序が毎回同じになるよう、ファイルに記述された順に登録する。
+    // 同じ名前のツールは、最初の定義の位置で後の定義に上書きする
+    let mut definitions: Vec<(String, Value)> = Vec::new();
+    for (name, value) in entries.0 {
+        match definitions.iter_mut().find(|(defined, _)| *defined == name) {
+            Some(definition) => definition.1 = value,
+            None => definitions.push((name, value)),
+        }
+    }
+
+    let mut tools = Vec::new();
+    for (name, value) in definitions {
+        let Some(command) = value["command"].as_str() else {
+            status!("外部ツールのコマンドが指定されていません: {}", name);
+            continue;
+        };
+        let args = value["args"].as_array().map(|arr| {
+            arr.iter()
+                .filter_map(|v| v.as_str())
+                .map(|s| s.to_string())
+                .collect()
+        }).unwrap_or_default();
+        let parameters = match value.get("parameters") {
+            Some(parameters) => parameters.clone(),
+            None => serde_json::json!({ "type": "object", "properties": {} }),
+        };
+        let timeout = value["timeout"].as_u64().unwrap_or(DEFAULT_TIMEOUT_SECS);
+        // 外部ツールは任意のコマンドを実行するため、指定がない場合は実行前に確認する
+        let policy = match value.get("policy") {
+            Some(policy) => match ToolPolicy::deserialize(policy) {
+                Ok(policy) => policy,
+                Err(_) => {
+                    status!("外部ツールのpolicyが不正なためスキップしました: {} (auto、confirm、denyのいずれかを指定してください)", name);
+                    continue;
+                }
+            },
+            None => ToolPolicy::Confirm,
+        };
+
+        tools.push(ExternalTool {
+            name: name.to_string(),
+            description: value["description"].as_str().unwrap_or_default().to_string(),
+            parameters,
+            command: command.to_string(),
+            args,
+            timeout: Duration::from_secs(timeout),
+            policy,
+        });
+    }
+    Ok(tools)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shell_tool(script: &str, timeout: Duration) -> ExternalTool {
+        ExternalTool {
+            name: "test".to_string(),
+            description: String::new(),
+            parameters: Value::Null,
+            command: "sh".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            timeout,
+            policy: ToolPolicy::Auto,
+        }
+    }
+
+    /// パイプの容量を超える大きさの引数
+    fn large_arguments() -> Value {
+        serde_json::json!({ "text": "a".repeat(1 << 20) })
+    }
+
+    #[tokio::test]
+    async fn tool_that_does_not_read_stdin_keeps_its_output() {
+        let mut tool = shell_tool("echo ok", Duration::from_secs(10));
+        let output = tool.call(large_arguments()).await.unwrap();
+        assert_eq!(output.text, "ok");
+    }
+
+    #[tokio::test]
+    async fn blocked_stdin_write_times_out() {
+        let mut tool = shell_tool("sleep 5", Duration::from_millis(200));
+        let start = std::time::Instant::now();
+        assert!(tool.call(large_arguments()).await.is_err());
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+}
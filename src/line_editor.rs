@@ -0,0 +1,80 @@
+//! REPLの入力の1行読み込み
+//!
+//! 端末から入力する場合は`rustyline`で行を編集でき、上下キーで過去の入力を呼び出せます。
+//! 入力の履歴は`~/.brain_history`に保存し、次回の起動時に読み込みます。
+//! `--no-readline`を指定した場合や標準入力が端末でない場合は、標準入力から1行ずつ読み込みます。
+
+use std::{io::IsTerminal, path::PathBuf};
+use rustyline::{error::ReadlineError, DefaultEditor};
+
+
+pub enum LineEditor {
+    Readline {
+        editor: Box<DefaultEditor>,
+        /// 履歴を保存するファイル。ホームディレクトリが分からない場合はNone
+        history_path: Option<PathBuf>,
+    },
+    Stdin,
+}
+
+
+impl LineEditor {
+    /// 行の編集を使用しない場合や使用できない場合は、標準入力から読み込みます。
+    pub fn new(readline: bool) -> Self {
+        if !readline || !std::io::stdin().is_terminal() {
+            return LineEditor::Stdin;
+        }
+        let mut editor = match DefaultEditor::new() {
+            Ok(editor) => editor,
+            Err(e) => {
+                log::warn!("failed to initialize line editor: {}", e);
+                return LineEditor::Stdin;
+            }
+        };
+        let history_path = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".brain_history"));
+        if let Some(path) = history_path.as_ref().filter(|path| path.exists())
+            && let Err(e) = editor.load_history(path) {
+            log::warn!("failed to load input history {}: {}", path.display(), e);
+        }
+        LineEditor::Readline { editor: Box::new(editor), history_path }
+    }
+
+    /// 1行読み込みます。入力の終わり（EOF）に達した場合はNoneを返します。
+    ///
+    /// 行の編集中にCtrl-Cを押した場合は、履歴を保存して終了します。
+    pub fn read_line(&mut self) -> std::io::Result<Option<String>> {
+        match self {
+            LineEditor::Readline { editor, .. } => match editor.readline("") {
+                Ok(line) => {
+                    if !line.trim().is_empty() && line != "." {
+                        editor.add_history_entry(line.as_str()).ok();
+                    }
+                    Ok(Some(line))
+                }
+                Err(ReadlineError::Eof) => Ok(None),
+                Err(ReadlineError::Interrupted) => {
+                    self.save_history();
+                    println!();
+                    std::process::exit(130);
+                }
+                Err(ReadlineError::Io(e)) => Err(e),
+                Err(e) => Err(std::io::Error::other(e)),
+            },
+            LineEditor::Stdin => {
+                let mut line = String::new();
+                if std::io::stdin().read_line(&mut line)? == 0 {
+                    return Ok(None);
+                }
+                Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+            }
+        }
+    }
+
+    /// 入力の履歴をファイルに保存します。
+    pub fn save_history(&mut self) {
+        if let LineEditor::Readline { editor, history_path: Some(path) } = self
+            && let Err(e) = editor.save_history(path) {
+            log::warn!("failed to save input history {}: {}", path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! 診断ログ
+//!
+//! `log`クレートのマクロで記録したログを、次の形式のJSONで1行ずつ書き出します。
+//! `{"timestamp":"...","level":"WARN","target":"brain::client","message":"..."}`
+//! 応答の表示と混ざらないよう、標準出力には書き出しません。ファイルを指定しない場合は標準エラー出力に書き出します。
+//!
+//! 通常は警告と情報のみを記録し、`--verbose`を指定した場合はリクエストの本文やストリーミングの各行も記録します。
+
+use std::{fs::File, io::Write, sync::Mutex};
+use chrono::Local;
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::json;
+
+
+struct Logger {
+    /// 書き出し先のファイル。Noneの場合は標準エラー出力に書き出す
+    file: Option<Mutex<File>>,
+}
+
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // 依存クレートのログは記録しない
+        metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let entry = json!({
+            "timestamp": Local::now().to_rfc3339(),
+            "level": record.level().as_str(),
+            "target": record.target(),
+            "message": record.args().to_string(),
+        });
+        match self.file.as_ref() {
+            Some(file) => {
+                if let Ok(mut file) = file.lock() {
+                    writeln!(file, "{}", entry).ok();
+                }
+            }
+            None => eprintln!("{}", entry),
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = self.file.as_ref()
+            && let Ok(mut file) = file.lock() {
+            file.flush().ok();
+        }
+    }
+}
+
+
+/// ログの書き出し先と詳細度を設定します。
+///
+/// `log_file`と`verbose`のどちらも指定しない場合はログを記録しません。
+pub fn init(log_file: Option<&str>, verbose: bool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if log_file.is_none() && !verbose {
+        return Ok(());
+    }
+
+    let file = match log_file {
+        Some(path) => Some(Mutex::new(std::fs::OpenOptions::new().create(true).append(true).open(path)?)),
+        None => None,
+    };
+    log::set_boxed_logger(Box::new(Logger { file }))?;
+    log::set_max_level(if verbose { LevelFilter::Debug } else { LevelFilter::Info });
+    Ok(())
+}
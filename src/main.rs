@@ -1,6 +1,50 @@
-use clap::{self, Parser};
+use clap::{self, CommandFactory, FromArgMatches};
+use std::{io::{IsTerminal, Read}, process::ExitCode, sync::{atomic::{AtomicBool, Ordering}, Arc}};
+use ollama_rs::generation::{chat::MessageRole, parameters::KeepAlive};
+mod audit;
+mod calc;
 mod chat;
+mod client;
+mod color;
+mod config;
+mod external;
+mod line_editor;
+mod logger;
+mod markdown;
 mod mcp;
+mod memory;
+mod render;
+mod server;
+mod session;
+mod system_prompt;
+mod tools;
+mod transcript;
+mod verbosity;
+
+use verbosity::status;
+
+/// 生成結果の出力形式
+#[derive(clap::ValueEnum, Clone, Debug)]
+pub enum OutputFormat {
+    /// 端末に人が読める形式で出力する
+    Terminal,
+    /// 1行に1つのJSONイベントとして出力する
+    Json,
+    /// 1回の応答ごとに、思考過程・本文・ツール呼び出しをまとめた1行のJSONとして出力する
+    JsonTurn,
+    /// 出力しない
+    None,
+}
+
+/// 端末に出力する応答本文の表示形式
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RenderMode {
+    /// 受け取った本文をそのまま表示する
+    #[default]
+    Plain,
+    /// 見出し、表、コードブロックなどを整形して表示する
+    Markdown,
+}
 
 #[derive(clap::Parser, Debug)]
 #[clap(about = "Brain", version = "1.0")]
@@ -11,48 +55,1293 @@ pub struct Args {
     #[clap(short, long, default_value = "11434", env = "BRAIN_LLM_PORT")]
     pub port: u16,
 
+    /// OllamaのURL（例: https://example.com/ollama）。指定した場合はhostとportより優先します
+    #[clap(long, value_parser = client::parse_base_url, env = "BRAIN_BASE_URL")]
+    pub base_url: Option<reqwest::Url>,
+
+    /// Ollamaへのリクエストに付与するBearerトークン（Authorizationヘッダー）
+    #[clap(long, env = "BRAIN_AUTH_TOKEN", hide_env_values = true)]
+    pub auth_token: Option<String>,
+
+    /// Ollamaへのリクエストに付与するHTTPヘッダー（name=value）。複数回指定できます
+    #[clap(long = "header", value_name = "NAME=VALUE", value_parser = client::parse_header)]
+    pub headers: Vec<(reqwest::header::HeaderName, reqwest::header::HeaderValue)>,
+
+    /// 接続先のAPIの形式（openaiはOpenAI互換の/v1/chat/completions。/fimはOllamaのみ対応）
+    #[clap(long, value_enum, default_value = "ollama", env = "BRAIN_API_FLAVOR")]
+    pub api_flavor: client::ApiFlavor,
+
     #[clap(short, long, default_value = "qwen3:30b-a3b", env = "BRAIN_LLM_TOOL_MODEL")]
     pub tool_model: String,
     
     #[clap(short, long, default_value = "gemma3:27b-it-qat", env = "BRAIN_LLM_VISION_MODEL")]
     pub vision_model: String,
+
+    /// 同じ発言への応答を指定したモデル（カンマ区切り）に同時に生成させ、並べて表示する。履歴には最初のモデルの応答を残します（ツールは使用しません）
+    #[clap(long, value_delimiter = ',', env = "BRAIN_COMPARE")]
+    pub compare: Option<Vec<String>>,
+
+    /// `title`でタイトルの生成に使用するモデル（未指定時はtool_model）
+    #[clap(long, env = "BRAIN_LLM_TITLE_MODEL")]
+    pub title_model: Option<String>,
+
+    /// 起動時に会話履歴を読み込み、終了時に保存するファイル
+    #[clap(long, env = "BRAIN_SESSION_FILE")]
+    pub session_file: Option<String>,
+
+    /// `/session`で名前を付けて保存するセッションのディレクトリ（未指定時は`~/.local/share/brain/sessions`）
+    #[clap(long, env = "BRAIN_SESSION_DIR")]
+    pub session_dir: Option<String>,
+
+    /// `remember`ツールで記憶した内容を保存するファイル（未指定時は--session-fileの拡張子を`.memory.json`にしたファイル。どちらもない場合は保存しない）
+    #[clap(long, env = "BRAIN_MEMORY_FILE")]
+    pub memory_file: Option<String>,
+
+    /// MCPサーバーへ同時に接続する最大数
+    #[clap(long, default_value = "8", env = "BRAIN_MAX_MCP_CONCURRENCY")]
+    pub max_mcp_concurrency: usize,
+
+    /// MCPサーバーごとの、接続してツール一覧を取得するまでのタイムアウト（秒）
+    #[clap(long, default_value = "10", env = "BRAIN_MCP_TIMEOUT")]
+    pub mcp_timeout_secs: u64,
+
+    /// MCPサーバーのツール一覧をキャッシュするファイル（未指定時はキャッシュしない）
+    #[clap(long, env = "BRAIN_TOOLS_CACHE")]
+    pub tools_cache: Option<String>,
+
+    /// 推論に使用するCPUスレッド数（Ollama側で無視される場合があります）
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..), env = "BRAIN_LLM_NUM_THREAD")]
+    pub num_thread: Option<u32>,
+
+    /// GPUにオフロードするレイヤー数（Ollama側で無視される場合があります）
+    #[clap(long, env = "BRAIN_LLM_NUM_GPU")]
+    pub num_gpu: Option<u32>,
+
+    /// コンテキストウィンドウのトークン数（未指定時はOllamaの既定値。OpenAI互換APIでは送信しません）
+    #[clap(long, value_parser = clap::value_parser!(u64).range(256..=1_048_576), env = "BRAIN_LLM_NUM_CTX")]
+    pub num_ctx: Option<u64>,
+
+    /// この件数を超える履歴を`/clear`で削除する際に確認する
+    #[clap(long, default_value = "10", env = "BRAIN_CLEAR_CONFIRM_THRESHOLD")]
+    pub clear_confirm_threshold: usize,
+
+    /// `title`で生成するタイトルの最大文字数
+    #[clap(long, default_value = "30", value_parser = clap::value_parser!(u64).range(1..), env = "BRAIN_TITLE_MAX_LEN")]
+    pub title_max_len: u64,
+
+    /// 生成に使用するシード（未指定時はセッションごとにランダム）
+    #[clap(long, env = "BRAIN_LLM_SEED")]
+    pub seed: Option<i32>,
+
+    /// 生成のtemperature（未指定時はモデルの既定値）
+    #[clap(long, env = "BRAIN_LLM_TEMPERATURE")]
+    pub temperature: Option<f32>,
+
+    /// 生成のtop_p（未指定時はモデルの既定値）
+    #[clap(long, env = "BRAIN_LLM_TOP_P")]
+    pub top_p: Option<f32>,
+
+    /// 生成のtop_k（未指定時はモデルの既定値。OpenAI互換のAPIでは送信しない）
+    #[clap(long, env = "BRAIN_LLM_TOP_K")]
+    pub top_k: Option<u32>,
+
+    /// FIM（fill-in-the-middle）に使用するコードモデル（未指定時はtool_model）
+    #[clap(long, env = "BRAIN_LLM_CODE_MODEL")]
+    pub code_model: Option<String>,
+
+    /// 起動時にtool_modelとvision_modelがサーバーになければダウンロードする（Ollamaのみ）
+    #[clap(long, env = "BRAIN_PULL")]
+    pub pull: bool,
+
+    /// 起動時にtool_modelをメモリに読み込み、最初の応答までの待ち時間を短くする（Ollamaのみ）
+    #[clap(long, env = "BRAIN_WARMUP")]
+    pub warmup: bool,
+
+    /// `--warmup`でvision_modelも読み込む
+    #[clap(long, requires = "warmup", env = "BRAIN_WARMUP_VISION")]
+    pub warmup_vision: bool,
+
+    /// 応答後にモデルをメモリに保持する時間（例: 30s, 5m, 2h。-1で保持し続け、0で応答後すぐに解放。未指定時はOllamaの既定値の5分）
+    #[clap(long, value_parser = client::parse_keep_alive, allow_hyphen_values = true, env = "BRAIN_KEEP_ALIVE")]
+    pub keep_alive: Option<KeepAlive>,
+
+    /// モデルの読み込みを待つ最大秒数
+    #[clap(long, default_value = "300", env = "BRAIN_MODEL_LOAD_TIMEOUT")]
+    pub model_load_timeout: u64,
+
+    /// モデルが呼び出したツールの実行を待つ最大秒数（超えた場合はタイムアウトしたことをモデルに返します）
+    #[clap(long, default_value = "60", value_parser = clap::value_parser!(u64).range(1..), env = "BRAIN_TOOL_TIMEOUT")]
+    pub tool_timeout: u64,
+
+    /// 1回の応答でツールを呼び出せる回数（超えた場合はツールを使わずに回答させます）
+    #[clap(long, default_value = "5", env = "BRAIN_MAX_TOOL_ROUNDS")]
+    pub max_tool_rounds: usize,
+
+    /// 本文が空の応答（思考過程のみの応答など）を、シードを変えて1回だけ再生成する
+    #[clap(long, env = "BRAIN_RETRY_EMPTY")]
+    pub retry_empty: bool,
+
+    /// Ollamaへの接続エラーや5xxの応答を再試行する回数（指数バックオフ）
+    #[clap(long, default_value = "3", env = "BRAIN_MAX_RETRIES")]
+    pub max_retries: u32,
+
+    /// Ollamaへのリクエストのタイムアウト（秒）
+    #[clap(long, default_value = "120", env = "BRAIN_TIMEOUT_SECS")]
+    pub timeout_secs: u64,
+
+    /// Ollamaへの接続のタイムアウト（秒）
+    #[clap(long, default_value = "10", env = "BRAIN_CONNECT_TIMEOUT_SECS")]
+    pub connect_timeout_secs: u64,
+
+    /// ストリーミングで応答全体を受け取るまでのタイムアウト（秒）
+    #[clap(long, default_value = "600", env = "BRAIN_STREAM_TIMEOUT_SECS")]
+    pub stream_timeout_secs: u64,
+
+    /// 履歴が予算を超えた場合に古い会話を要約して圧縮する
+    #[clap(long)]
+    pub auto_compact: bool,
+
+    /// 履歴の予算（推定トークン数）
+    #[clap(long, default_value = "8192", env = "BRAIN_CONTEXT_BUDGET")]
+    pub context_budget: usize,
+
+    /// 送信する会話の上限（推定トークン数）。超える場合は古いメッセージから削除する
+    #[clap(long, env = "BRAIN_CONTEXT_TOKENS")]
+    pub context_tokens: Option<usize>,
+
+    /// 外部プログラムで実装するツールの対応表
+    #[clap(long, default_value = "tools.json", env = "BRAIN_TOOL_MAPPING")]
+    pub tool_mapping: String,
+
+    /// 会話の先頭に付与するシステムプロンプト（ペルソナ、ファイルの後に追加されます）
+    #[clap(long, visible_alias = "system", env = "BRAIN_SYSTEM_PROMPT")]
+    pub system_prompt: Option<String>,
+
+    /// システムプロンプトを読み込むファイル（ペルソナの後に追加されます）
+    #[clap(long, env = "BRAIN_SYSTEM_FILE")]
+    pub system_file: Option<String>,
+
+    /// 組み込みのペルソナ（assistant, coder, translator）
+    #[clap(long, env = "BRAIN_PERSONA")]
+    pub persona: Option<String>,
+
+    /// 応答の先頭でシステムプロンプトが繰り返された場合に取り除く（ヒューリスティック）
+    #[clap(long)]
+    pub strip_system_echo: bool,
+
+    /// 生成結果の出力形式
+    #[clap(long, value_enum, default_value = "terminal", env = "BRAIN_OUTPUT")]
+    pub output: OutputFormat,
+
+    /// 応答本文の表示形式（terminal出力で--no-streamを指定した場合のみ。ストリーミング時はそのまま表示します）
+    #[clap(long, value_enum, default_value = "plain", env = "BRAIN_RENDER")]
+    pub render: RenderMode,
+
+    /// ツール呼び出しを記録する監査ログ（JSON Lines）
+    #[clap(long, env = "BRAIN_AUDIT_LOG")]
+    pub audit_log: Option<String>,
+
+    /// 監査ログで値を伏せる引数のフィールド名（カンマ区切り）
+    #[clap(long, value_delimiter = ',', env = "BRAIN_AUDIT_REDACT")]
+    pub audit_redact: Vec<String>,
+
+    /// 計算ツールで整数・有理数の式を任意精度で計算する（関数を含む式はf64で計算）
+    #[clap(long)]
+    pub precise_calculator: bool,
+
+    /// 使用する組み込みツール（カンマ区切り。get_datetime_now, calculator, get_conversation_summary, fetch_url, remember, recall）
+    #[clap(long, value_delimiter = ',', conflicts_with = "no_builtin_tools", env = "BRAIN_TOOLS")]
+    pub tools: Option<Vec<String>>,
+
+    /// 組み込みツールを使用しない（外部ツールとMCPツールは使用します）
+    #[clap(long)]
+    pub no_builtin_tools: bool,
+
+    /// ツールが呼び出された時の扱い（name=auto|confirm|deny、カンマ区切り）。MCPと外部ツールの設定ファイルより優先します
+    #[clap(long, value_name = "NAME=POLICY", value_delimiter = ',', value_parser = tools::parse_tool_policy, env = "BRAIN_TOOL_POLICY")]
+    pub tool_policy: Vec<(String, tools::ToolPolicy)>,
+
+    /// `fetch_url`ツールが返すページ本文の最大バイト数（超えた部分は切り捨てます）
+    #[clap(long, default_value = "100000", value_parser = clap::value_parser!(u64).range(1..), env = "BRAIN_FETCH_MAX_BYTES")]
+    pub fetch_max_bytes: u64,
+
+    /// `fetch_url`ツールでlocalhostやプライベートアドレスのURLの取得を許可する
+    #[clap(long, env = "BRAIN_FETCH_ALLOW_PRIVATE")]
+    pub fetch_allow_private: bool,
+
+    /// JSON出力時に、応答の前にユーザーの入力を出力する
+    #[clap(long)]
+    pub echo_prompt: bool,
+
+    /// ストリーミング中にツール呼び出しを受け取ったら、残りの生成を待たずにツールを実行する
+    /// （本文とツール呼び出しを交互に返すサーバーでは、後続の本文や2つ目以降のツール呼び出しが失われます）
+    #[clap(long, env = "BRAIN_STOP_ON_TOOL_CALL")]
+    pub stop_on_tool_call: bool,
+
+    /// 応答をストリーミングせず、生成が終わってから一括で出力する
+    #[clap(long, env = "BRAIN_NO_STREAM")]
+    pub no_stream: bool,
+
+    /// 一括で受け取った応答を、1文字あたり指定したミリ秒（`--typewriter=30`。省略時は15）の間隔で少しずつ表示する
+    /// （`--no-stream`を指定し、terminal出力で標準出力が端末の場合のみ。Markdownで整形する場合は使用しません）
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "15", env = "BRAIN_TYPEWRITER")]
+    pub typewriter: Option<u64>,
+
+    /// 最終的な応答本文のみを出力する（思考過程、ツール呼び出し、通知、REPLのラベル、終了時の履歴を出力しません）
+    #[clap(short, long, env = "BRAIN_QUIET")]
+    pub quiet: bool,
+
+    /// thinkingモデルの思考過程（<think>タグの中身）を表示しない
+    #[clap(long, env = "BRAIN_HIDE_THINKING")]
+    pub hide_thinking: bool,
+
+    /// 思考過程の開始を示すタグ（例: <thinking>、<|thought|>）
+    #[clap(long, default_value = "<think>", value_parser = clap::builder::NonEmptyStringValueParser::new(), allow_hyphen_values = true, env = "BRAIN_THINK_OPEN")]
+    pub think_open: String,
+
+    /// 思考過程の終了を示すタグ
+    #[clap(long, default_value = "</think>", value_parser = clap::builder::NonEmptyStringValueParser::new(), allow_hyphen_values = true, env = "BRAIN_THINK_CLOSE")]
+    pub think_close: String,
+
+    /// 入力の最大バイト数。超えた場合は確認し、非対話の場合は拒否する
+    #[clap(long, default_value = "51200", env = "BRAIN_MAX_INPUT_LENGTH")]
+    pub max_input_length: usize,
+
+    /// 指定したアドレス（例: 127.0.0.1:8765）でWebSocketサーバーとして起動する（実行の確認が必要なツールは実行しません）
+    #[clap(long, env = "BRAIN_SERVE_WS")]
+    pub serve_ws: Option<String>,
+
+    /// 起動時のバナーに表示する挨拶文
+    #[clap(long, env = "BRAIN_GREETING")]
+    pub greeting: Option<String>,
+
+    /// 起動時のバナーを表示しない
+    #[clap(long)]
+    pub no_banner: bool,
+
+    /// 入力を1行ずつ送信する（未指定時は`.`のみの行またはCtrl-Dまでを1つの入力とする）
+    #[clap(long, env = "BRAIN_SINGLE_LINE")]
+    pub single_line: bool,
+
+    /// REPLで行の編集と入力履歴（`~/.brain_history`）を使用しない（機能の限られた端末向け）
+    #[clap(long, env = "BRAIN_NO_READLINE")]
+    pub no_readline: bool,
+
+    /// 設定ファイル（TOML）。未指定時は`~/.config/brain/brain.toml`があれば読み込む（環境変数とコマンドライン引数が優先されます）
+    #[clap(long, env = "BRAIN_CONFIG")]
+    pub config: Option<String>,
+
+    /// 診断ログ（リクエスト、応答の解析エラーなど）を書き出すファイル（未指定時は--verbose指定時のみ標準エラー出力）
+    #[clap(long, env = "BRAIN_LOG_FILE")]
+    pub log_file: Option<String>,
+
+    /// リクエストの本文やストリーミングの各行も診断ログに記録する
+    #[clap(long)]
+    pub verbose: bool,
+
+    /// 応答を生成せず、送信するリクエスト（モデル、メッセージ、ツール）をJSONで表示する
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// 端末への出力に色を付けない（環境変数`NO_COLOR`でも無効にできます）
+    #[clap(long)]
+    pub no_color: bool,
+
+    /// 1回だけ応答して終了する発言（未指定で標準入力がパイプの場合は、標準入力の全体を発言とする）
+    pub prompt: Option<String>,
 }
 
-#[tokio::main]
-async fn main() {
-    let args = Args::parse();
-    let mut chat = chat::Chat::new(&args.host, args.port, &args.tool_model, &args.vision_model);
+/// REPLで使用できるコマンドと説明
+const COMMANDS: &[(&str, &str)] = &[
+    ("/help", "コマンドの一覧を表示します"),
+    ("/save", "会話履歴をファイルに保存します: /save <file>"),
+    ("/load", "会話履歴をファイルから読み込みます: /load <file>"),
+    ("/export", "会話履歴をMarkdownで書き出します: /export <file.md>"),
+    ("/export-json", "思考過程やツールの呼び出しを含めた会話の記録をJSONで書き出します: /export-json <file>"),
+    ("/import-json", "/export-jsonで書き出した記録から会話を復元します: /import-json <file>"),
+    ("/clear", "会話履歴を削除します（-f で確認を省略）"),
+    ("/config", "現在の設定を表示します"),
+    ("/tools", "使用できるツールの一覧を表示します"),
+    ("/call", "モデルを介さずにMCPツールを呼び出します: /call <tool> <json-args>"),
+    ("/stats", "最後の応答のトークン数と生成速度を表示します"),
+    ("/thinking", "最後の応答の思考過程（thinkingモデルのみ）を表示します"),
+    ("/save-code", "最後の応答のn番目のコードブロックをファイルに保存します: /save-code <n> <file>"),
+    ("/search", "履歴からメッセージを検索します: /search <term> または /search -r <pattern>"),
+    ("/clear-tools-cache", "MCPのツール一覧のキャッシュを削除して再取得します"),
+    ("/system", "現在のシステムプロンプトを表示します: /system show"),
+    ("/fim", "ファイルの内容をprefixとsuffixとして間を補完します: /fim <prefix-file> <suffix-file>"),
+    ("/session", "名前を付けて会話を保存・復元します: /session save <name> | load <name> | list"),
+    ("/compress", "古い履歴を要約に置き換えます（直近のやり取りはそのまま残します）: /compress [turns]"),
+    ("/retry", "最後の応答を取り消して、同じ発言で再生成します"),
+    ("/model", "応答に使用するモデルを変更します: /model <name>"),
+    ("/image", "画像を添付して質問します: /image <path> <prompt>"),
+    ("models", "サーバーにあるモデルの一覧を表示します"),
+    ("title", "会話のタイトルを生成します"),
+    ("exit", "終了します"),
+];
 
-    let mcp_setting_path = "mcp.json";
-    let mut mcp = mcp::Mcp::new();
-    mcp.load_setting(mcp_setting_path).await;
-    //mcp.show_tools();
+/// 入力が指定したコマンドであれば、その引数部分を返します。
+fn command_args<'a>(input: &'a str, command: &str) -> Option<&'a str> {
+    let rest = input.strip_prefix(command)?;
+    if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+        Some(rest.trim())
+    } else {
+        None
+    }
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            current[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(current[j] + 1);
+        }
+        prev = current;
+    }
+    prev[b.len()]
+}
 
+/// 未知のスラッシュコマンドに最も近いコマンドを返します。
+fn suggest_command(command: &str) -> Option<&'static str> {
+    const MAX_DISTANCE: usize = 3;
+
+    COMMANDS.iter()
+        .map(|(name, _)| *name)
+        .filter(|name| name.starts_with('/'))
+        .map(|name| (name, levenshtein(command, name)))
+        .filter(|(_, distance)| *distance <= MAX_DISTANCE)
+        .min_by_key(|(_, distance)| *distance)
+        .map(|(name, _)| name)
+}
+
+fn show_banner(args: &Args, chat: &chat::Chat, tool_count: usize) {
+    println!("Brain {}", env!("CARGO_PKG_VERSION"));
+    if let Some(greeting) = args.greeting.as_ref() {
+        println!("{}", greeting);
+    }
+    println!("server: {}", server_url(args).map(|url| url.to_string()).unwrap_or_else(|e| e));
+    println!("model: {} (vision: {})", args.tool_model, args.vision_model);
+    println!("MCP tools: {}", tool_count);
+    println!("seed: {}", chat.get_seed());
+    if !args.single_line {
+        println!("input: end with a line containing only \".\" or Ctrl-D");
+    }
+    show_commands();
+    println!();
+}
+
+fn show_commands() {
+    println!("commands:");
+    for (name, description) in COMMANDS {
+        println!("    {:<12} {}", name, description);
+    }
+}
+
+/// 使用できるツールを、名前、提供元、説明の表で表示します。
+fn show_tools(chat: &chat::Chat) {
+    let tools = chat.tool_definitions();
+    let name_width = tools.iter().map(|(tool, _)| tool.name.chars().count()).chain(["NAME".len()]).max().unwrap_or_default();
+    let source_width = tools.iter().map(|(_, source)| source.chars().count()).chain(["SOURCE".len()]).max().unwrap_or_default();
+
+    println!("{:<name_width$}  {:<source_width$}  DESCRIPTION", "NAME", "SOURCE");
+    for (tool, source) in &tools {
+        // 複数行の説明は1行目のみを表示する
+        let description = tool.description.lines().next().unwrap_or_default().trim();
+        println!("{:<name_width$}  {:<source_width$}  {}", tool.name, source, description);
+    }
+    println!("{} tools", tools.len());
+}
+
+/// 最後の応答のトークン数、生成時間、生成速度を表示します。
+fn show_stats(chat: &chat::Chat) {
+    let Some(stats) = chat.get_last_stats() else {
+        println!("No statistics available for the last response.");
+        return;
+    };
+    println!("tokens: {} (prompt: {})", stats.eval_tokens, stats.prompt_tokens);
+    println!("duration: {:.2}s (total: {:.2}s)", stats.eval_duration.as_secs_f64(), stats.total_duration.as_secs_f64());
+    match stats.tokens_per_second() {
+        Some(speed) => println!("speed: {:.1} tokens/s", speed),
+        None => println!("speed: -"),
+    }
+}
+
+/// ユーザーの入力を読み込みます。入力の終わり（EOF）に達した場合はNoneを返します。
+///
+/// 複数行の入力では、`.`のみの行またはEOF（Ctrl-D）までを1つの入力とします。
+/// ただし1行目がコマンドの場合は、その行のみで入力を終えます。
+fn read_user_input(editor: &mut line_editor::LineEditor, single_line: bool) -> std::io::Result<Option<String>> {
+    let mut lines: Vec<String> = Vec::new();
     loop {
+        let Some(line) = editor.read_line()? else {
+            return Ok((!lines.is_empty()).then(|| lines.join("\n")));
+        };
+
+        let is_command = line.starts_with('/') || COMMANDS.iter().any(|(name, _)| *name == line.trim());
+        if single_line || (lines.is_empty() && is_command) {
+            return Ok(Some(line));
+        }
+        if line == "." {
+            return Ok(Some(lines.join("\n")));
+        }
+        lines.push(line);
+    }
+}
+
+/// 確認を表示し、`y`が入力されたかを返します。
+///
+/// JSONなどの出力と混ざらないよう、確認は標準エラー出力に表示します。
+/// 入力を待つ間も他のタスク（Ctrl-Cの受け付けなど）が動くよう、標準入力は別のスレッドで読み込みます。
+pub async fn confirm(message: &str) -> bool {
+    eprintln!("{} [y/N]", message);
+    let input = tokio::task::spawn_blocking(|| {
         let mut input = String::new();
-        println!("user:");
-        std::io::stdin().read_line(&mut input).unwrap();
-        let input = input.trim();
+        std::io::stdin().read_line(&mut input).map(|_| input)
+    }).await;
+    match input {
+        Ok(Ok(input)) => matches!(input.trim().to_lowercase().as_str(), "y" | "yes"),
+        _ => false,
+    }
+}
 
-        if input == "exit" {
-            break;
+fn show_config(args: &Args, chat: &chat::Chat) {
+    let unset = || "(default)".to_string();
+    println!("config: {}", args.config.as_deref().unwrap_or("(none)"));
+    println!("host: {}", args.host);
+    println!("port: {}", args.port);
+    println!("base_url: {}", args.base_url.as_ref().map(|url| url.to_string()).unwrap_or_else(|| "(none)".to_string()));
+    println!("auth_token: {}", if args.auth_token.is_some() { "(set)" } else { "(none)" });
+    // ヘッダーの値には認証情報が含まれることがあるため、名前のみを表示する
+    let header_names: Vec<&str> = args.headers.iter().map(|(name, _)| name.as_str()).collect();
+    println!("headers: {}", if header_names.is_empty() { "(none)".to_string() } else { header_names.join(", ") });
+    println!("api_flavor: {:?}", args.api_flavor);
+    println!("tool_model: {}", chat.get_tool_model());
+    println!("vision_model: {}", args.vision_model);
+    println!("title_model: {}", chat.get_title_model());
+    println!("compare: {}", if chat.get_compare_models().is_empty() { "(none)".to_string() } else { chat.get_compare_models().join(", ") });
+    println!("session_file: {}", args.session_file.as_deref().unwrap_or("(none)"));
+    println!("session_dir: {}", session_store(args).map(|store| store.dir().display().to_string()).unwrap_or_else(|| "(none)".to_string()));
+    println!("memory_file: {}", chat.get_memory_file().map(|path| path.display().to_string()).unwrap_or_else(|| "(none)".to_string()));
+    println!("max_mcp_concurrency: {}", args.max_mcp_concurrency);
+    println!("mcp_timeout: {}s", args.mcp_timeout_secs);
+    println!("tools_cache: {}", args.tools_cache.as_deref().unwrap_or("(none)"));
+    println!("num_thread: {}", args.num_thread.map(|v| v.to_string()).unwrap_or_else(unset));
+    println!("num_gpu: {}", args.num_gpu.map(|v| v.to_string()).unwrap_or_else(unset));
+    println!("num_ctx: {}", args.num_ctx.map(|v| v.to_string()).unwrap_or_else(unset));
+    println!("clear_confirm_threshold: {}", args.clear_confirm_threshold);
+    println!("title_max_len: {}", args.title_max_len);
+    println!("model_load_timeout: {}s", args.model_load_timeout);
+    println!("tool_timeout: {}s", args.tool_timeout);
+    println!("max_tool_rounds: {}", args.max_tool_rounds);
+    let tool_policy: Vec<String> = args.tool_policy.iter().map(|(name, policy)| format!("{}={}", name, format!("{:?}", policy).to_lowercase())).collect();
+    println!("tool_policy: {}", if tool_policy.is_empty() { "(none)".to_string() } else { tool_policy.join(", ") });
+    println!("retry_empty: {}", args.retry_empty);
+    println!("pull: {}", args.pull);
+    println!("warmup: {}{}", args.warmup, if args.warmup_vision { " (with vision_model)" } else { "" });
+    println!("keep_alive: {}", args.keep_alive.as_ref().map(|v| serde_json::json!(v).to_string()).unwrap_or_else(unset));
+    println!("max_retries: {}", args.max_retries);
+    println!("timeout: {}s (connect: {}s, stream: {}s)", args.timeout_secs, args.connect_timeout_secs, args.stream_timeout_secs);
+    println!("auto_compact: {}", args.auto_compact);
+    println!("context_budget: {}", args.context_budget);
+    println!("context_tokens: {}", args.context_tokens.map(|v| v.to_string()).unwrap_or_else(|| "(none)".to_string()));
+    println!("tool_mapping: {}", args.tool_mapping);
+    println!("output: {:?}", args.output);
+    println!("render: {:?}", args.render);
+    println!("audit_log: {}", args.audit_log.as_deref().unwrap_or("(none)"));
+    println!("audit_redact: {}", args.audit_redact.join(","));
+    println!("precise_calculator: {}", args.precise_calculator);
+    println!("tools: {}", match (&args.tools, args.no_builtin_tools) {
+        (_, true) => "(none)".to_string(),
+        (Some(tools), false) => tools.join(","),
+        (None, false) => "(all)".to_string(),
+    });
+    println!("fetch_max_bytes: {}", args.fetch_max_bytes);
+    println!("fetch_allow_private: {}", args.fetch_allow_private);
+    println!("echo_prompt: {}", args.echo_prompt);
+    println!("no_stream: {}", args.no_stream);
+    println!("typewriter: {}", args.typewriter.map(|delay| format!("{}ms", delay)).unwrap_or_else(|| "(none)".to_string()));
+    println!("stop_on_tool_call: {}", args.stop_on_tool_call);
+    println!("quiet: {}", args.quiet);
+    println!("hide_thinking: {}", args.hide_thinking);
+    println!("think_open: {}", args.think_open);
+    println!("think_close: {}", args.think_close);
+    println!("no_color: {}", args.no_color);
+    println!("single_line: {}", args.single_line);
+    println!("no_readline: {}", args.no_readline);
+    println!("dry_run: {}", args.dry_run);
+    println!("log_file: {}", args.log_file.as_deref().unwrap_or("(none)"));
+    println!("verbose: {}", args.verbose);
+    println!("max_input_length: {}", args.max_input_length);
+    println!("persona: {}", args.persona.as_deref().unwrap_or("(none)"));
+    println!("system_file: {}", args.system_file.as_deref().unwrap_or("(none)"));
+    println!("strip_system_echo: {}", args.strip_system_echo);
+    println!("code_model: {}", args.code_model.as_deref().unwrap_or(&args.tool_model));
+    match args.seed {
+        Some(seed) => println!("seed: {}", seed),
+        None => println!("seed: {} (random)", chat.get_seed()),
+    }
+    println!("temperature: {}", args.temperature.map(|v| v.to_string()).unwrap_or_else(unset));
+    println!("top_p: {}", args.top_p.map(|v| v.to_string()).unwrap_or_else(unset));
+    println!("top_k: {}", args.top_k.map(|v| v.to_string()).unwrap_or_else(unset));
+}
+
+fn save_code(chat: &chat::Chat, args: &str) {
+    let mut parts = args.split_whitespace();
+    let (Some(index), Some(file_path)) = (parts.next(), parts.next()) else {
+        println!("Usage: /save-code <n> <file>");
+        return;
+    };
+    let Ok(index) = index.parse::<usize>() else {
+        println!("Invalid code block number: {}", index);
+        return;
+    };
+
+    let Some(response) = chat.get_last_response() else {
+        println!("No response yet.");
+        return;
+    };
+    let blocks = chat::Chat::extract_code_blocks(response);
+    let Some(block) = index.checked_sub(1).and_then(|i| blocks.get(i)) else {
+        println!("Code block {} not found ({} blocks).", index, blocks.len());
+        return;
+    };
+
+    match std::fs::write(file_path, format!("{}\n", block.content)) {
+        Ok(_) if block.lang.is_empty() => println!("Saved code block {} to {}", index, file_path),
+        Ok(_) => println!("Saved code block {} ({}) to {}", index, block.lang, file_path),
+        Err(e) => println!("Error: {}", e),
+    }
+}
+
+fn search_history(chat: &chat::Chat, rest: &str) {
+    const CONTEXT_CHARS: usize = 30;
+
+    let pattern = match rest.strip_prefix("-r") {
+        Some(pattern) if rest.starts_with("-r ") => regex::Regex::new(pattern.trim()),
+        _ => regex::Regex::new(&format!("(?i){}", regex::escape(rest))),
+    };
+    let pattern = match pattern {
+        Ok(pattern) if !rest.is_empty() => pattern,
+        Ok(_) => {
+            println!("Usage: /search <term> or /search -r <pattern>");
+            return;
         }
-        else if input.is_empty() {
-            chat.clear_history();
-            println!("History cleared.");
+        Err(e) => {
+            println!("Invalid pattern: {}", e);
+            return;
         }
-        else if input == "title" {
-            let title = chat.generate_title().await;
-            println!("title: {}", title);
+    };
+
+    let results = chat.search_history(&pattern);
+    if results.is_empty() {
+        println!("No messages found.");
+        return;
+    }
+
+    for (index, message) in results {
+        // 一致した箇所の前後だけを表示する
+        let content = &message.content;
+        let matched = pattern.find(content).unwrap();
+        let before: String = content[..matched.start()].chars().rev().take(CONTEXT_CHARS).collect::<Vec<_>>().into_iter().rev().collect();
+        let after: String = content[matched.end()..].chars().take(CONTEXT_CHARS).collect();
+        let prefix = if before.len() < matched.start() { "..." } else { "" };
+        let suffix = if after.len() < content.len() - matched.end() { "..." } else { "" };
+        // 色付けが無効な場合（`--no-color`、`NO_COLOR`）はそのまま表示される
+        let snippet = format!("{}{}{}{}{}", prefix, before, color::highlight(matched.as_str()), after, suffix);
+        println!("[{}] {:?}: {}", index, message.role, snippet.replace('\n', " "));
+    }
+}
+
+async fn fill_in_the_middle(chat: &mut chat::Chat, args: &Args, rest: &str) {
+    let mut parts = rest.split_whitespace();
+    let (Some(prefix_path), Some(suffix_path)) = (parts.next(), parts.next()) else {
+        println!("Usage: /fim <prefix-file> <suffix-file>");
+        return;
+    };
+
+    let prefix = match std::fs::read_to_string(prefix_path) {
+        Ok(prefix) => prefix,
+        Err(e) => {
+            println!("Error: {}: {}", prefix_path, e);
+            return;
+        }
+    };
+    let suffix = match std::fs::read_to_string(suffix_path) {
+        Ok(suffix) => suffix,
+        Err(e) => {
+            println!("Error: {}: {}", suffix_path, e);
+            return;
+        }
+    };
+
+    let model = args.code_model.as_deref().unwrap_or(&args.tool_model);
+    chat.generate_fim(model, &prefix, &suffix).await;
+}
+
+/// 入力が長すぎる場合は確認し、送信してよいかを返します。
+async fn check_input_length(input: &str, max_input_length: usize) -> bool {
+    if input.len() <= max_input_length {
+        return true;
+    }
+
+    let message = format!("Input is {} bytes (limit {} bytes).", input.len(), max_input_length);
+    if !std::io::stdin().is_terminal() {
+        println!("{} Rejected.", message);
+        return false;
+    }
+    confirm(&format!("{} Send anyway?", message)).await
+}
+
+/// タグを省略したモデル名は`:latest`として扱われる
+fn model_exists(models: &[String], model: &str) -> bool {
+    models.iter().any(|name| name == model || *name == format!("{}:latest", model))
+}
+
+/// 指定したモデルがサーバーにない場合に警告します。
+async fn check_models(chat: &chat::Chat, pull: bool) {
+    let models = match chat.list_models().await {
+        Ok(models) => models,
+        Err(e) => {
+            status!("Warning: failed to list models: {}", e);
+            return;
+        }
+    };
+    let mut pulled: Vec<&str> = Vec::new();
+    for model in [chat.get_tool_model(), chat.get_vision_model()] {
+        if model_exists(&models, model) || pulled.contains(&model) {
+            continue;
+        }
+        if !pull {
+            status!("Warning: model \"{}\" is not available on the server. Run `models` to list available models.", model);
             continue;
         }
+        status!("Pulling model \"{}\"...", model);
+        match chat.pull_model(model, &mut print_pull_progress).await {
+            Ok(()) => status!("\rPulled model \"{}\".\x1b[K", model),
+            Err(e) => status!("\nError: failed to pull model \"{}\": {}", model, e),
+        }
+        pulled.push(model);
+    }
+
+    // モデルが対応するより大きなコンテキストウィンドウを指定しても、それ以上の履歴は参照されない
+    if let Some(num_ctx) = chat.get_num_ctx()
+        && let Ok(Some(context_length)) = chat.context_length(chat.get_tool_model()).await
+        && num_ctx > context_length {
+        status!("Warning: num_ctx {} exceeds the context length of model \"{}\" ({}).", num_ctx, chat.get_tool_model(), context_length);
+    }
+}
+
+/// モデルをメモリに読み込みます。失敗した場合は警告を表示して続行します。
+async fn warmup_models(chat: &chat::Chat, vision: bool) {
+    use std::io::Write;
+
+    let mut models = vec![chat.get_tool_model()];
+    if vision && chat.get_vision_model() != chat.get_tool_model() {
+        models.push(chat.get_vision_model());
+    }
+    for model in models {
+        if !verbosity::is_quiet() {
+            print!("Loading model \"{}\"...", model);
+            std::io::stdout().flush().ok();
+        }
+        let start = std::time::Instant::now();
+        match chat.load_model(model).await {
+            Ok(()) => status!("\rLoaded model \"{}\" ({:.1}s).\x1b[K", model, start.elapsed().as_secs_f64()),
+            Err(e) => {
+                status!("\nWarning: failed to load model \"{}\": {}", model, e);
+                // サーバーに接続できない場合は、残りのモデルも読み込めないため諦める
+                if e.downcast_ref::<reqwest::Error>().is_some_and(|e| e.is_connect()) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// モデルのダウンロードの進捗を、同じ行を書き換えて表示します。
+fn print_pull_progress(progress: &client::PullProgress) {
+    use std::io::Write;
+
+    const MIB: u64 = 1024 * 1024;
+
+    if verbosity::is_quiet() {
+        return;
+    }
+    match progress.total {
+        Some(total) if total > 0 => {
+            let percent = progress.completed.saturating_mul(100) / total;
+            print!("\r{} {:>3}% ({}/{} MiB)\x1b[K", progress.status, percent, progress.completed / MIB, total / MIB);
+        }
+        _ => print!("\r{}\x1b[K", progress.status),
+    }
+    std::io::stdout().flush().ok();
+}
+
+/// MCPツールを直接呼び出し、結果をそのまま表示します。
+async fn call_mcp_tool(mcp: &mut mcp::Mcp, rest: &str) {
+    let (name, arguments) = match rest.split_once(char::is_whitespace) {
+        Some((name, arguments)) => (name, arguments.trim()),
+        None => (rest, ""),
+    };
+    if name.is_empty() {
+        println!("Usage: /call <tool> <json-args>");
+        return;
+    }
+    let arguments = if arguments.is_empty() {
+        serde_json::json!({})
+    } else {
+        match serde_json::from_str(arguments) {
+            Ok(arguments) => arguments,
+            Err(e) => {
+                println!("Error: invalid JSON arguments: {}", e);
+                return;
+            }
+        }
+    };
+    match mcp.call_tool(name, arguments).await {
+        Ok(output) => {
+            println!("{}", output.text);
+            if !output.images.is_empty() {
+                println!("({} images)", output.images.len());
+            }
+        }
+        Err(e) => println!("Error: {}", e),
+    }
+}
+
+fn session_store(args: &Args) -> Option<session::SessionStore> {
+    match args.session_dir.as_ref() {
+        Some(dir) => Some(session::SessionStore::new(dir)),
+        None => session::SessionStore::default_dir().map(session::SessionStore::new),
+    }
+}
+
+/// 名前付きのセッションを保存、読み込み、一覧表示します。
+async fn manage_session(chat: &mut chat::Chat, args: &Args, rest: &str) {
+    let Some(store) = session_store(args) else {
+        println!("Error: session directory is unknown. Set --session-dir.");
+        return;
+    };
+    let (action, name) = match rest.split_once(char::is_whitespace) {
+        Some((action, name)) => (action, name.trim()),
+        None => (rest, ""),
+    };
+
+    match (action, name) {
+        ("save", name) if !name.is_empty() => {
+            // 一覧で見分けられるよう、タイトルがなければ生成してから保存する
+            if chat.get_title().is_none() && !chat.get_history().is_empty() {
+                chat.generate_title().await;
+            }
+            match store.save(name, chat.get_tool_model(), chat.get_title(), chat.get_history()) {
+                Ok(()) => println!("Saved session {} ({} messages).", name, chat.get_history().len()),
+                Err(e) => println!("Error: failed to save session {}: {}", name, e),
+            }
+        }
+        ("load", name) if !name.is_empty() => match store.load(name) {
+            Ok(session) => {
+                let count = session.history.len();
+                chat.restore_session(session.history, session.title);
+                println!("Loaded session {} ({} messages, model: {}).", name, count, session.model);
+            }
+            Err(e) => println!("Error: failed to load session {}: {}", name, e),
+        },
+        ("list", "") => match store.list() {
+            Ok(sessions) if sessions.is_empty() => println!("No saved sessions in {}.", store.dir().display()),
+            Ok(sessions) => {
+                for (name, session) in sessions {
+                    let created_at = chrono::DateTime::parse_from_rfc3339(&session.created_at)
+                        .map(|created_at| created_at.format("%Y-%m-%d %H:%M").to_string())
+                        .unwrap_or(session.created_at);
+                    println!("{}\t{}\t{}\t{} messages\t{}", name, created_at, session.model, session.history.len(), session.title.as_deref().unwrap_or("(untitled)"));
+                }
+            }
+            Err(e) => println!("Error: failed to list sessions: {}", e),
+        },
+        _ => println!("Usage: /session save <name> | /session load <name> | /session list"),
+    }
+}
+
+/// REPLの入力を処理した結果
+enum CommandOutcome {
+    /// コマンドとして処理した
+    Handled,
+    /// 終了する
+    Exit,
+    /// モデルへの入力として扱う（コマンドではない入力、または`/retry`で再送信する発言）
+    Prompt(String),
+}
+
+/// 入力がコマンドであれば実行します。
+async fn handle_command(chat: &mut chat::Chat, mcp: &mut mcp::Mcp, args: &Args, line: &str) -> CommandOutcome {
+    if line == "exit" {
+        return CommandOutcome::Exit;
+    }
+    else if line.is_empty() {
+        return CommandOutcome::Handled;
+    }
+    else if line == "/clear" || line == "/clear -f" {
+        let count = chat.get_history().len();
+        if line == "/clear" && count > args.clear_confirm_threshold {
+            let title = chat.get_title().unwrap_or("(untitled)");
+            if !confirm(&format!("Clear {} messages of \"{}\"?", count, title)).await {
+                println!("Canceled.");
+                return CommandOutcome::Handled;
+            }
+        }
+        chat.clear_history();
+        println!("History cleared.");
+    }
+    else if line == "/clear-tools-cache" {
+        mcp.clear_tools_cache();
+        *mcp = mcp::Mcp::new();
+        mcp.set_cache_path(args.tools_cache.clone());
+        mcp.set_connect_timeout(std::time::Duration::from_secs(args.mcp_timeout_secs));
+        let summary = mcp.load_from_default_locations(args.max_mcp_concurrency).await;
+        chat.set_mcp_tools(mcp.tools.clone());
+        println!("Rediscovered {} MCP tools.", summary.tools);
+    }
+    else if line == "/config" {
+        show_config(args, chat);
+    }
+    else if line == "/tools" {
+        show_tools(chat);
+    }
+    else if let Some(rest) = command_args(line, "/call") {
+        call_mcp_tool(mcp, rest).await;
+    }
+    else if line == "/stats" {
+        show_stats(chat);
+    }
+    else if line == "/thinking" {
+        match chat.get_last_thinking() {
+            Some(thinking) => println!("{}", color::thinking(thinking)),
+            None => println!("(no thinking)"),
+        }
+    }
+    else if let Some(rest) = command_args(line, "/save-code") {
+        save_code(chat, rest);
+    }
+    else if let Some(rest) = command_args(line, "/search") {
+        search_history(chat, rest);
+    }
+    else if let Some(rest) = command_args(line, "/system") {
+        if rest == "show" {
+            match chat.get_system_prompt() {
+                Some(system_prompt) => println!("{}", system_prompt),
+                None => println!("(no system prompt)"),
+            }
+        } else {
+            println!("Usage: /system show");
+        }
+    }
+    else if let Some(rest) = command_args(line, "/fim") {
+        fill_in_the_middle(chat, args, rest).await;
+    }
+    else if let Some(rest) = command_args(line, "/image") {
+        let Some((path, prompt)) = rest.split_once(char::is_whitespace) else {
+            println!("Usage: /image <path> <prompt>");
+            return CommandOutcome::Handled;
+        };
+        chat.generate_vision_response(std::path::Path::new(path), prompt.trim()).await;
+    }
+    else if let Some(rest) = command_args(line, "/model") {
+        if rest.is_empty() {
+            println!("model: {}", chat.get_tool_model());
+            return CommandOutcome::Handled;
+        }
+        // 一覧を取得できない場合や一覧にない場合も、警告のみで切り替える
+        match chat.list_models().await {
+            Ok(models) if !model_exists(&models, rest) => println!("Warning: model \"{}\" is not available on the server.", rest),
+            Ok(_) => {}
+            Err(e) => println!("Warning: failed to list models: {}", e),
+        }
+        chat.set_tool_model(rest.to_string());
+        println!("Switched model to {}.", rest);
+    }
+    else if line == "models" {
+        match chat.list_models().await {
+            Ok(models) => models.iter().for_each(|model| println!("{}", model)),
+            Err(e) => println!("Error: {}", e),
+        }
+    }
+    else if line == "title" {
+        let title = chat.generate_title().await;
+        println!("title: {}", title);
+    }
+    else if line == "/help" {
+        show_commands();
+    }
+    else if let Some(rest) = command_args(line, "/save") {
+        if rest.is_empty() {
+            println!("Usage: /save <file>");
+        } else if let Err(e) = chat.save_history(std::path::Path::new(rest)) {
+            println!("Error: failed to save {}: {}", rest, e);
+        } else {
+            println!("Saved {} messages to {}.", chat.get_history().len(), rest);
+        }
+    }
+    else if let Some(rest) = command_args(line, "/export-json") {
+        if rest.is_empty() {
+            println!("Usage: /export-json <file>");
+        } else if let Err(e) = chat.transcript().save(std::path::Path::new(rest)) {
+            println!("Error: failed to export {}: {}", rest, e);
+        } else {
+            println!("Exported {} messages to {}.", chat.get_history().len(), rest);
+        }
+    }
+    else if let Some(rest) = command_args(line, "/import-json") {
+        if rest.is_empty() {
+            println!("Usage: /import-json <file>");
+        } else {
+            match transcript::Transcript::load(std::path::Path::new(rest)) {
+                Ok(transcript) => {
+                    chat.restore_transcript(transcript);
+                    println!("Imported {} messages from {}.", chat.get_history().len(), rest);
+                }
+                Err(e) => println!("Error: failed to import {}: {}", rest, e),
+            }
+        }
+    }
+    else if let Some(rest) = command_args(line, "/export") {
+        if rest.is_empty() {
+            println!("Usage: /export <file.md>");
+        } else if let Err(e) = std::fs::write(rest, chat.export_markdown()) {
+            println!("Error: failed to export {}: {}", rest, e);
+        } else {
+            println!("Exported {} messages to {}.", chat.get_history().len(), rest);
+        }
+    }
+    else if let Some(rest) = command_args(line, "/load") {
+        if rest.is_empty() {
+            println!("Usage: /load <file>");
+        } else if !std::path::Path::new(rest).exists() {
+            println!("Error: no such file: {}", rest);
+        } else if let Err(e) = chat.load_history(std::path::Path::new(rest)) {
+            println!("Error: failed to load {}: {}", rest, e);
+        } else {
+            println!("Loaded {} messages from {}.", chat.get_history().len(), rest);
+        }
+    }
+    else if let Some(rest) = command_args(line, "/session") {
+        manage_session(chat, args, rest).await;
+    }
+    else if let Some(rest) = command_args(line, "/compress") {
+        const DEFAULT_KEEP_TURNS: usize = 2;
+
+        let keep_turns = if rest.is_empty() { Ok(DEFAULT_KEEP_TURNS) } else { rest.parse::<usize>() };
+        let Ok(keep_turns) = keep_turns else {
+            println!("Usage: /compress [turns]");
+            return CommandOutcome::Handled;
+        };
+        match chat.compress_history(keep_turns).await {
+            Ok(Some((count, saved))) => println!("Compressed {} messages into a summary, saving ~{} tokens.", count, saved),
+            Ok(None) => println!("Nothing to compress."),
+            Err(e) => println!("Error: failed to compress history: {}", e),
+        }
+    }
+    else if line == "/retry" {
+        match chat.pop_last_turn() {
+            Some(prompt) => return CommandOutcome::Prompt(prompt),
+            None => println!("Nothing to retry."),
+        }
+    }
+    else if line.starts_with('/') {
+        let command = line.split_whitespace().next().unwrap_or(line);
+        match suggest_command(command) {
+            Some(suggestion) => println!("Unknown command {}. Did you mean {}?", command, suggestion),
+            None => println!("Unknown command {}.", command),
+        }
+    }
+    else {
+        return CommandOutcome::Prompt(line.to_string());
+    }
+    CommandOutcome::Handled
+}
+
+/// Ctrl-Cを受け取るタスクを起動します。
+///
+/// 生成中に押された場合は返したチャンネルへ通知し、それ以外の場合はプロセスを終了します。
+fn spawn_interrupt_handler(generating: Arc<AtomicBool>) -> tokio::sync::mpsc::UnboundedReceiver<()> {
+    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            if !generating.swap(false, Ordering::SeqCst) || sender.send(()).is_err() {
+                println!();
+                std::process::exit(130);
+            }
+        }
+    });
+    receiver
+}
+
+/// 接続先のURLを返します。`--base-url`がない場合はhostとportから作成します。
+fn server_url(args: &Args) -> Result<reqwest::Url, String> {
+    match args.base_url.as_ref() {
+        Some(base_url) => Ok(base_url.clone()),
+        None => client::parse_base_url(&format!("http://{}:{}", args.host, args.port)),
+    }
+}
+
+/// `--header`と`--auth-token`から、Ollamaへのリクエストに付与するヘッダーを作成します。
+fn request_headers(args: &Args) -> Result<reqwest::header::HeaderMap, String> {
+    let mut headers = reqwest::header::HeaderMap::new();
+    for (name, value) in &args.headers {
+        headers.append(name.clone(), value.clone());
+    }
+    if let Some(token) = args.auth_token.as_ref() {
+        let mut value = reqwest::header::HeaderValue::from_str(&format!("Bearer {}", token.trim()))
+            .map_err(|_| "--auth-token contains characters that cannot be used in an HTTP header".to_string())?;
+        // ログやデバッグ出力にトークンを表示しない
+        value.set_sensitive(true);
+        headers.insert(reqwest::header::AUTHORIZATION, value);
+    }
+    Ok(headers)
+}
+
+/// 引数の設定を反映したChatを作成します。出力先は呼び出し側で設定します。
+pub fn build_chat(args: &Args) -> Result<chat::Chat, String> {
+    let mut chat = chat::Chat::new(&server_url(args)?, &args.tool_model, &args.vision_model);
+    chat.set_performance_options(args.num_thread, args.num_gpu);
+    chat.set_num_ctx(args.num_ctx);
+    chat.set_sampling_options(args.temperature, args.top_p, args.top_k);
+    if let Some(seed) = args.seed {
+        chat.set_seed(seed);
+    }
+    chat.set_model_load_timeout(std::time::Duration::from_secs(args.model_load_timeout));
+    chat.set_tool_timeout(std::time::Duration::from_secs(args.tool_timeout));
+    chat.set_max_tool_rounds(args.max_tool_rounds);
+    chat.set_tool_policies(args.tool_policy.iter().cloned().collect());
+    chat.set_retry_empty(args.retry_empty);
+    chat.set_compare_models(args.compare.clone().unwrap_or_default().into_iter().filter(|model| !model.trim().is_empty()).collect());
+    chat.set_memory_file(match (args.memory_file.as_ref(), args.session_file.as_ref()) {
+        (Some(memory_file), _) => Some(std::path::PathBuf::from(memory_file)),
+        (None, Some(session_file)) => Some(std::path::Path::new(session_file).with_extension("memory.json")),
+        (None, None) => None,
+    });
+    chat.set_max_retries(args.max_retries);
+    chat.set_stop_on_tool_call(args.stop_on_tool_call);
+    chat.set_keep_alive(args.keep_alive.clone());
+    chat.set_api_flavor(args.api_flavor);
+    chat.set_headers(request_headers(args)?);
+    chat.set_timeouts(
+        std::time::Duration::from_secs(args.connect_timeout_secs),
+        std::time::Duration::from_secs(args.timeout_secs),
+        std::time::Duration::from_secs(args.stream_timeout_secs),
+    );
+    let system_prompt = system_prompt::compose_system_prompt(args.persona.as_deref(), args.system_file.as_deref(), args.system_prompt.as_deref())?;
+    chat.set_system_prompt(system_prompt);
+    chat.set_audit_log(args.audit_log.as_deref().map(|path| audit::AuditLog::new(path, &args.audit_redact)));
+    chat.set_precise_calculator(args.precise_calculator);
+    if args.no_builtin_tools {
+        chat.set_enabled_tools(Vec::new());
+    } else if let Some(tools) = args.tools.as_ref() {
+        let tools: Vec<String> = tools.iter().map(|tool| tool.trim().to_string()).filter(|tool| !tool.is_empty()).collect();
+        for tool in tools.iter().filter(|tool| !chat::BUILTIN_TOOL_NAMES.contains(&tool.as_str())) {
+            println!("Warning: unknown built-in tool \"{}\". Available: {}", tool, chat::BUILTIN_TOOL_NAMES.join(", "));
+        }
+        chat.set_enabled_tools(tools);
+    }
+    chat.set_fetch_options(args.fetch_max_bytes as usize, args.fetch_allow_private);
+    chat.set_title_max_len(args.title_max_len as usize);
+    chat.set_title_model(args.title_model.clone());
+    chat.set_echo_prompt(args.echo_prompt);
+    chat.set_stream(!args.no_stream);
+    chat.set_hide_thinking(args.hide_thinking);
+    chat.set_think_tags(render::ThinkTags { open: args.think_open.clone(), close: args.think_close.clone() });
+    chat.set_dry_run(args.dry_run);
+    chat.set_strip_system_echo(args.strip_system_echo);
+    chat.set_auto_compact(args.auto_compact, args.context_budget);
+    if let Some(context_tokens) = args.context_tokens {
+        chat.set_context_budget(context_tokens);
+    }
+    chat.set_external_tools(external::load_external_tools(&args.tool_mapping)?);
+    Ok(chat)
+}
+
+/// 会話を開始できない設定やファイルの誤りがある場合は、エラーを表示して終了コード1で終了します。
+#[tokio::main]
+async fn main() -> ExitCode {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    match config::Config::load(args.config.as_deref()) {
+        Ok(Some((path, config))) => {
+            config.apply(&mut args, &matches);
+            args.config = Some(path.display().to_string());
+        }
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    }
+    color::init(args.no_color);
+    verbosity::init(if args.quiet { verbosity::Verbosity::Quiet } else { verbosity::Verbosity::Normal });
+    if let Err(e) = logger::init(args.log_file.as_deref(), args.verbose) {
+        eprintln!("Warning: failed to open log file: {}", e);
+    }
+
+    if let Some(addr) = args.serve_ws.clone() {
+        let local = tokio::task::LocalSet::new();
+        if let Err(e) = local.run_until(server::serve_websocket(&addr, std::rc::Rc::new(args))).await {
+            eprintln!("Error: {}", e);
+            return ExitCode::FAILURE;
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    let mut chat = match build_chat(&args) {
+        Ok(chat) => chat,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Some(session_file) = args.session_file.as_ref()
+        && let Err(e) = chat.load_history(std::path::Path::new(session_file)) {
+        eprintln!("Error: failed to load session {}: {}", session_file, e);
+        return ExitCode::FAILURE;
+    }
+    chat.set_renderer(match args.output {
+        OutputFormat::Terminal if args.no_stream && args.render == RenderMode::Markdown => Box::new(render::MarkdownRenderer::default()),
+        OutputFormat::Terminal => match args.typewriter {
+            // ストリーミングする場合は受け取った分をそのまま表示する
+            Some(delay) if args.no_stream && std::io::stdout().is_terminal() => Box::new(render::TypewriterRenderer::new(Box::new(render::TerminalRenderer), std::time::Duration::from_millis(delay))),
+            _ => Box::new(render::TerminalRenderer),
+        },
+        OutputFormat::Json => Box::new(render::JsonRenderer::new(std::io::stdout())),
+        OutputFormat::JsonTurn => Box::new(render::TurnJsonRenderer::new(std::io::stdout())),
+        OutputFormat::None => Box::new(render::NullRenderer),
+    });
 
+    // dry-runではOllamaへ接続しない
+    if !args.dry_run {
+        check_models(&chat, args.pull).await;
+        if args.warmup {
+            warmup_models(&chat, args.warmup_vision).await;
+        }
+    }
+
+    let mut mcp = mcp::Mcp::new();
+    mcp.set_cache_path(args.tools_cache.clone());
+    mcp.set_connect_timeout(std::time::Duration::from_secs(args.mcp_timeout_secs));
+    mcp.load_from_default_locations(args.max_mcp_concurrency).await;
+    chat.set_mcp_tools(mcp.tools.clone());
+
+    // 発言を引数で指定した場合やパイプで入力した場合は、REPLを開始せずに1回だけ応答する
+    let single_shot_input = match args.prompt.clone() {
+        Some(prompt) => Some(prompt),
+        None if !std::io::stdin().is_terminal() => {
+            let mut input = String::new();
+            if let Err(e) = std::io::stdin().read_to_string(&mut input) {
+                println!("Error: failed to read input: {}", e);
+                return ExitCode::FAILURE;
+            }
+            Some(input)
+        }
+        None => None,
+    };
+    if let Some(input) = single_shot_input {
+        let input = input.trim();
+        if input.is_empty() {
+            eprintln!("Error: no input.");
+            return ExitCode::FAILURE;
+        }
+        if !check_input_length(input, args.max_input_length).await {
+            return ExitCode::FAILURE;
+        }
         chat.generate_response(input).await;
+        if let Some(session_file) = args.session_file.as_ref()
+            && let Err(e) = chat.save_history(std::path::Path::new(session_file)) {
+            eprintln!("Error: failed to save session {}: {}", session_file, e);
+            return ExitCode::FAILURE;
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    if !args.no_banner && !args.quiet {
+        show_banner(&args, &chat, mcp.tools.len());
     }
 
+    let generating = Arc::new(AtomicBool::new(false));
+    let mut interrupt_receiver = spawn_interrupt_handler(generating.clone());
+    let mut editor = line_editor::LineEditor::new(!args.no_readline);
+
+    loop {
+        status!("{}", color::user("user:"));
+        // 読み込めない入力（UTF-8でない文字列など）は破棄して、次の入力を待つ
+        let input = match read_user_input(&mut editor, args.single_line) {
+            Ok(Some(input)) => input,
+            Ok(None) => break,
+            Err(e) => {
+                println!("Error: failed to read input: {}", e);
+                continue;
+            }
+        };
+        let input = match handle_command(&mut chat, &mut mcp, &args, input.trim()).await {
+            CommandOutcome::Handled => continue,
+            CommandOutcome::Exit => break,
+            CommandOutcome::Prompt(input) => input,
+        };
+        let input = input.as_str();
+
+        if !check_input_length(input, args.max_input_length).await {
+            continue;
+        }
+        if matches!(args.output, OutputFormat::Terminal) {
+            status!("{}", color::assistant("assistant:"));
+        }
+        // 生成中のCtrl-Cでは生成を中断し、Chatがそれまでの応答を履歴に残してターンを終えてからプロンプトに戻る
+        while interrupt_receiver.try_recv().is_ok() {}
+        let cancel = tokio_util::sync::CancellationToken::new();
+        chat.set_cancellation_token(cancel.clone());
+        generating.store(true, Ordering::SeqCst);
+        let interrupted = {
+            let generation = chat.generate_response(input);
+            tokio::pin!(generation);
+            let interrupted = tokio::select! {
+                _ = &mut generation => false,
+                _ = interrupt_receiver.recv() => true,
+            };
+            if interrupted {
+                cancel.cancel();
+                generation.await;
+            }
+            interrupted
+        };
+        generating.store(false, Ordering::SeqCst);
+        if interrupted {
+            status!("\nInterrupted. Press Ctrl-C again to exit.");
+        }
+        // 出力先のパイプが閉じられた場合は、以降の出力ができないため終了する
+        if render::output_closed() {
+            break;
+        }
+    }
+    editor.save_history();
+
+    if let Some(session_file) = args.session_file.as_ref()
+        && let Err(e) = chat.save_history(std::path::Path::new(session_file)) {
+        eprintln!("Error: failed to save session {}: {}", session_file, e);
+    }
+
+    if render::output_closed() || args.quiet {
+        return ExitCode::SUCCESS;
+    }
     println!("\nhistory:");
     chat.get_history().iter().for_each(|message| {
-        println!("{:?}:", message.role);
+        let label = format!("{:?}:", message.role);
+        let label = match message.role {
+            MessageRole::User => color::user(&label),
+            MessageRole::Assistant => color::assistant(&label),
+            MessageRole::Tool => color::tool(&label),
+            MessageRole::System => label,
+        };
+        println!("{}", label);
         println!("    {}", message.content);
     });
+    ExitCode::SUCCESS
 }
@@ -0,0 +1,324 @@
+//! Markdownの端末向け整形
+//!
+//! 見出しや強調を太字などのANSIエスケープシーケンスで、箇条書きを記号付きで、表を罫線で揃えて表示します。
+//! コードブロックは言語名に対応する構文があれば色付けします。
+
+use std::sync::OnceLock;
+use pulldown_cmark::{CodeBlockKind, Event, HeadingLevel, Options, Parser, Tag, TagEnd};
+use syntect::{easy::HighlightLines, highlighting::{Theme, ThemeSet}, parsing::SyntaxSet, util::as_24_bit_terminal_escaped};
+
+
+const BOLD: &str = "\x1b[1m";
+const ITALIC: &str = "\x1b[3m";
+const UNDERLINE: &str = "\x1b[4m";
+const STRIKE: &str = "\x1b[9m";
+const CODE: &str = "\x1b[35m";
+const DIM: &str = "\x1b[90m";
+const RESET: &str = "\x1b[0m";
+
+
+/// Markdownの文字列を、端末に表示するための文字列に変換します。
+///
+/// 色付けが無効な場合は変換せずにそのまま返します。
+pub fn render(markdown: &str) -> String {
+    if !crate::color::enabled() {
+        return markdown.to_string();
+    }
+
+    let mut writer = Writer::default();
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+    for event in Parser::new_ext(markdown, options) {
+        writer.event(event);
+    }
+    writer.output.trim_end().to_string()
+}
+
+
+#[derive(Default)]
+struct Writer {
+    output: String,
+    /// 入れ子になった箇条書き。番号付きの場合は次の番号を保持する
+    lists: Vec<Option<u64>>,
+    /// 現在のスタイル。入れ子の要素を閉じた時に外側のスタイルを戻すために使う
+    styles: Vec<&'static str>,
+    code_block: Option<(String, String)>,
+    table: Option<Table>,
+    /// リンクの閉じタグで表示するURL
+    links: Vec<String>,
+}
+
+#[derive(Default)]
+struct Table {
+    rows: Vec<Vec<String>>,
+    /// 見出し行の数（0または1）
+    header_rows: usize,
+}
+
+
+impl Writer {
+    fn event(&mut self, event: Event) {
+        match event {
+            Event::Start(tag) => self.start(tag),
+            Event::End(tag) => self.end(tag),
+            Event::Text(text) => self.text(&text),
+            Event::Code(code) => {
+                if self.table.is_some() {
+                    self.text(&code);
+                } else {
+                    self.styled(CODE, &code);
+                }
+            }
+            Event::InlineMath(math) | Event::DisplayMath(math) => self.text(&math),
+            Event::Html(html) | Event::InlineHtml(html) => self.text(&html),
+            Event::FootnoteReference(name) => self.text(&format!("[^{}]", name)),
+            Event::SoftBreak | Event::HardBreak => self.text("\n"),
+            Event::Rule => {
+                self.block_break();
+                self.styled(DIM, &"─".repeat(40));
+                self.output.push_str("\n\n");
+            }
+            Event::TaskListMarker(checked) => self.text(if checked { "[x] " } else { "[ ] " }),
+        }
+    }
+
+    fn start(&mut self, tag: Tag) {
+        match tag {
+            Tag::Heading { level, .. } => {
+                self.block_break();
+                self.push_style(if level == HeadingLevel::H1 { "\x1b[1;4m" } else { BOLD });
+            }
+            Tag::BlockQuote(_) => {
+                self.block_break();
+                self.push_style(DIM);
+            }
+            Tag::CodeBlock(kind) => {
+                let lang = match kind {
+                    CodeBlockKind::Fenced(info) => info.split_whitespace().next().unwrap_or_default().to_string(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                self.block_break();
+                self.code_block = Some((lang, String::new()));
+            }
+            Tag::List(start) => {
+                if self.lists.is_empty() {
+                    self.block_break();
+                } else {
+                    self.line_break();
+                }
+                self.lists.push(start);
+            }
+            Tag::Item => {
+                self.line_break();
+                let depth = self.lists.len().saturating_sub(1);
+                let marker = match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}. ", *number - 1)
+                    }
+                    _ => "• ".to_string(),
+                };
+                self.output.push_str(&"  ".repeat(depth));
+                self.output.push_str(&marker);
+            }
+            Tag::Table(_) => {
+                self.block_break();
+                self.table = Some(Table::default());
+            }
+            Tag::TableHead | Tag::TableRow => {
+                if let Some(table) = self.table.as_mut() {
+                    table.rows.push(Vec::new());
+                }
+            }
+            Tag::TableCell => {
+                if let Some(row) = self.table.as_mut().and_then(|table| table.rows.last_mut()) {
+                    row.push(String::new());
+                }
+            }
+            Tag::Emphasis => self.push_style(ITALIC),
+            Tag::Strong => self.push_style(BOLD),
+            Tag::Strikethrough => self.push_style(STRIKE),
+            Tag::Link { dest_url, .. } => {
+                self.links.push(dest_url.to_string());
+                self.push_style(UNDERLINE);
+            }
+            _ => {}
+        }
+    }
+
+    fn end(&mut self, tag: TagEnd) {
+        match tag {
+            TagEnd::Paragraph => {
+                if self.lists.is_empty() {
+                    self.output.push_str("\n\n");
+                } else {
+                    self.line_break();
+                }
+            }
+            TagEnd::Heading(_) => {
+                self.pop_style();
+                self.output.push_str("\n\n");
+            }
+            TagEnd::BlockQuote(_) => {
+                // スタイルを戻してから空行を入れる
+                self.output.truncate(self.output.trim_end_matches('\n').len());
+                self.pop_style();
+                self.output.push_str("\n\n");
+            }
+            TagEnd::CodeBlock => {
+                if let Some((lang, code)) = self.code_block.take() {
+                    self.output.push_str(&highlight(&lang, &code));
+                    self.line_break();
+                    self.output.push('\n');
+                }
+            }
+            TagEnd::List(_) => {
+                self.lists.pop();
+                if self.lists.is_empty() {
+                    self.line_break();
+                    self.output.push('\n');
+                }
+            }
+            TagEnd::Item => self.line_break(),
+            TagEnd::Table => {
+                if let Some(table) = self.table.take() {
+                    self.output.push_str(&table.render());
+                    self.output.push('\n');
+                }
+            }
+            TagEnd::TableHead => {
+                if let Some(table) = self.table.as_mut() {
+                    table.header_rows = table.rows.len();
+                }
+            }
+            TagEnd::Emphasis | TagEnd::Strong | TagEnd::Strikethrough => self.pop_style(),
+            TagEnd::Link => {
+                self.pop_style();
+                if let Some(url) = self.links.pop() {
+                    self.styled(DIM, &format!(" ({})", url));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        if let Some((_, code)) = self.code_block.as_mut() {
+            code.push_str(text);
+        } else if let Some(table) = self.table.as_mut() {
+            // 表のセルは列幅を揃えるため、スタイルを付けずに文字列のみを保持する
+            if let Some(cell) = table.rows.last_mut().and_then(|row| row.last_mut()) {
+                cell.push_str(text);
+            }
+        } else {
+            self.output.push_str(text);
+        }
+    }
+
+    fn styled(&mut self, style: &str, text: &str) {
+        if self.table.is_some() {
+            self.text(text);
+            return;
+        }
+        self.output.push_str(style);
+        self.output.push_str(text);
+        self.output.push_str(RESET);
+        self.output.push_str(&self.styles.concat());
+    }
+
+    fn push_style(&mut self, style: &'static str) {
+        self.styles.push(style);
+        if self.table.is_none() {
+            self.output.push_str(style);
+        }
+    }
+
+    fn pop_style(&mut self) {
+        self.styles.pop();
+        if self.table.is_none() {
+            self.output.push_str(RESET);
+            self.output.push_str(&self.styles.concat());
+        }
+    }
+
+    /// 行の途中であれば改行する
+    fn line_break(&mut self) {
+        if !self.output.is_empty() && !self.output.ends_with('\n') {
+            self.output.push('\n');
+        }
+    }
+
+    /// ブロックの前に空行を入れる
+    fn block_break(&mut self) {
+        if self.output.is_empty() || self.output.ends_with("\n\n") {
+            return;
+        }
+        self.line_break();
+        if self.lists.is_empty() {
+            self.output.push('\n');
+        }
+    }
+}
+
+
+impl Table {
+    fn render(&self) -> String {
+        let columns = self.rows.iter().map(|row| row.len()).max().unwrap_or(0);
+        let widths: Vec<usize> = (0..columns)
+            .map(|i| self.rows.iter().filter_map(|row| row.get(i)).map(|cell| display_width(cell)).max().unwrap_or(0))
+            .collect();
+
+        let mut output = String::new();
+        for (i, row) in self.rows.iter().enumerate() {
+            let cells: Vec<String> = widths.iter().enumerate().map(|(j, width)| {
+                let cell = row.get(j).map(|cell| cell.as_str()).unwrap_or_default();
+                format!("{}{}", cell, " ".repeat(width - display_width(cell)))
+            }).collect();
+            let line = cells.join(" │ ");
+            if i < self.header_rows {
+                output.push_str(&format!("{}{}{}\n", BOLD, line, RESET));
+            } else {
+                output.push_str(&format!("{}\n", line));
+            }
+            if i + 1 == self.header_rows {
+                let separator: Vec<String> = widths.iter().map(|width| "─".repeat(*width)).collect();
+                output.push_str(&format!("{}\n", separator.join("─┼─")));
+            }
+        }
+        output
+    }
+}
+
+
+/// 端末での表示幅。全角文字は2文字分として数える
+fn display_width(text: &str) -> usize {
+    text.chars().map(|c| {
+        let c = c as u32;
+        let wide = matches!(c,
+            0x1100..=0x115F | 0x2E80..=0x303E | 0x3041..=0x33FF | 0x3400..=0x4DBF | 0x4E00..=0x9FFF
+            | 0xA000..=0xA4CF | 0xAC00..=0xD7A3 | 0xF900..=0xFAFF | 0xFE30..=0xFE4F | 0xFF00..=0xFF60
+            | 0xFFE0..=0xFFE6 | 0x1F300..=0x1F64F | 0x1F900..=0x1F9FF | 0x20000..=0x3FFFD);
+        if wide { 2 } else { 1 }
+    }).sum()
+}
+
+
+/// コードブロックを言語名に対応する構文で色付けする。対応する構文がない場合は色付けせずに返す
+fn highlight(lang: &str, code: &str) -> String {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    static THEME: OnceLock<Theme> = OnceLock::new();
+    let syntaxes = SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines);
+    let theme = THEME.get_or_init(|| ThemeSet::load_defaults().themes.remove("base16-ocean.dark").unwrap_or_default());
+
+    let Some(syntax) = (!lang.is_empty()).then(|| syntaxes.find_syntax_by_token(lang)).flatten() else {
+        return code.to_string();
+    };
+    let mut highlighter = HighlightLines::new(syntax, theme);
+    let mut output = String::new();
+    for line in syntect::util::LinesWithEndings::from(code) {
+        match highlighter.highlight_line(line, syntaxes) {
+            Ok(ranges) => output.push_str(&as_24_bit_terminal_escaped(&ranges, false)),
+            Err(_) => output.push_str(line),
+        }
+    }
+    format!("{}{}\n", output.trim_end_matches('\n'), RESET)
+}
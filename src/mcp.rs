@@ -1,11 +1,27 @@
 use rmcp::transport::TokioChildProcess;
 use serde::{Deserialize, Serialize};
 use tokio::process::Command;
-use std::{collections::HashMap, io::BufRead};
-use rmcp::model::{ClientCapabilities, ClientInfo, Implementation};
-use rmcp::{ServiceExt, transport::SseTransport};
+use tokio::sync::{Mutex, Semaphore};
+use tokio::task::JoinSet;
+use std::{collections::{BTreeMap, HashMap}, path::{Path, PathBuf}, sync::Arc, time::Duration};
+use ollama_rs::generation::images::Image;
+use rmcp::model::{CallToolRequestParam, ClientCapabilities, ClientInfo, Content, Implementation, RawContent, ResourceContents};
+use crate::tools::{ToolDefinition, ToolFuture, ToolHandler, ToolOutput, ToolPolicy, ToolResult};
+use crate::verbosity::status;
+use rmcp::{Peer, RoleClient, ServiceError, ServiceExt, transport::SseTransport};
 
 
+/// MCPサーバーの接続設定
+///
+/// stdioの`command`、`args`、`env`の値に含まれる`${VAR}`は、起動時に環境変数の値に置き換えます。
+/// APIキーなどを設定ファイルに直接記述せずに渡せます。
+///
+/// `policy`でこのサーバーのツールを呼び出された時の扱い（`auto`、`confirm`、`deny`）を、
+/// `tool_policy`でツールごとの扱いを指定できます。
+///
+/// ```json
+/// { "filesystem": { "type": "stdio", "command": "mcp-fs", "policy": "confirm", "tool_policy": { "read_file": "auto" } } }
+/// ```
 #[derive(Debug, Serialize, Deserialize)]
 struct McpSetting {
     name: String,
@@ -14,10 +30,78 @@ struct McpSetting {
     url: Option<String>,
     command: Option<String>,
     args: Option<Vec<String>>,
+    /// stdioサーバーの起動時に設定する環境変数
+    ///
+    /// このプロセスの環境変数を引き継いだ上で追加し、同じ名前の環境変数は上書きします。
+    env: Option<BTreeMap<String, String>>,
+    /// このサーバーのツールの既定の扱い
+    policy: ToolPolicy,
+    /// ツールごとの扱い
+    tool_policy: BTreeMap<String, ToolPolicy>,
 }
 
+/// 設定ファイルのMCPサーバーへの接続と、それらが提供するツール
+///
+/// 各ツールは提供元サーバーとの接続（`ServerHandle`）を共有して保持しているため、
+/// 読み込み後は`ToolHandler::call`でそのままサーバーのツールを呼び出せます。
 pub struct Mcp {
-    pub tools: Vec<rmcp::model::Tool>,
+    pub tools: Vec<McpTool>,
+    /// ツール名と、そのツールを提供するサーバー名の対応
+    tool_servers: HashMap<String, String>,
+    cache_path: Option<String>,
+    /// サーバーごとの、接続からツール一覧の取得までのタイムアウト
+    connect_timeout: Duration,
+}
+
+
+/// 設定ファイルのサーバーへの接続結果
+#[derive(Debug, Default, Clone, Copy)]
+pub struct McpSummary {
+    pub servers: usize,
+    pub connected: usize,
+    pub tools: usize,
+}
+
+impl std::fmt::Display for McpSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MCPサーバーに接続しました: {}/{}台、ツール{}個", self.connected, self.servers, self.tools)
+    }
+}
+
+
+/// 接続中のMCPサーバーが提供するツール
+#[derive(Debug, Clone)]
+pub struct McpTool {
+    server: String,
+    tool: rmcp::model::Tool,
+    policy: ToolPolicy,
+    /// 同じサーバーのツールで共有する接続
+    handle: Arc<Mutex<ServerHandle>>,
+}
+
+
+/// 接続中のMCPサーバー
+///
+/// stdioサーバーのプロセスが終了した場合などに接続し直せるよう、接続設定を保持します。
+#[derive(Debug)]
+pub struct ServerHandle {
+    setting: McpSetting,
+    peer: Peer<RoleClient>,
+    connect_timeout: Duration,
+}
+
+
+/// サーバーごとのツール一覧のキャッシュ
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ToolsCache {
+    servers: HashMap<String, CachedTools>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedTools {
+    /// 接続設定のハッシュ。設定が変わった場合はキャッシュを使用しない
+    config_hash: String,
+    tools: Vec<rmcp::model::Tool>,
 }
 
 
@@ -25,150 +109,569 @@ impl Mcp {
     pub fn new() -> Self {
         Mcp {
             tools: Vec::new(),
+            tool_servers: HashMap::new(),
+            cache_path: None,
+            connect_timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// サーバーごとに、接続してツール一覧を取得するまでのタイムアウトを設定します。
+    ///
+    /// 時間内に応答しないサーバーはスキップし、他のサーバーの読み込みを続けます。
+    pub fn set_connect_timeout(&mut self, timeout: Duration) {
+        self.connect_timeout = timeout;
+    }
+
+    /// ツール一覧をキャッシュするファイルを設定します。
+    pub fn set_cache_path(&mut self, cache_path: Option<String>) {
+        self.cache_path = cache_path;
+    }
+
+    /// ツール一覧のキャッシュを削除します。
+    pub fn clear_tools_cache(&self) {
+        if let Some(cache_path) = self.cache_path.as_ref()
+            && std::path::Path::new(cache_path).exists()
+            && let Err(e) = std::fs::remove_file(cache_path) {
+            status!("キャッシュを削除できません: {} {}", cache_path, e);
         }
     }
+
+    /// 名前を指定して、そのツールを提供するサーバーのツールを呼び出します。
+    pub async fn call_tool(&mut self, name: &str, arguments: serde_json::Value) -> ToolResult {
+        let Some(tool) = self.tools.iter_mut().find(|tool| tool.tool.name == name) else {
+            return Err(format!("MCPツールが見つかりません: {}", name).into());
+        };
+        tool.call(arguments).await
+    }
 }
 
 
 impl Mcp {
-    pub async fn load_setting(&mut self, file_path: &str) {
-        let mcp_settings = load_setting_file(file_path);
-        for mcp_setting in mcp_settings {
-            if mcp_setting.connection_type.to_lowercase() == "sse" {
-                if mcp_setting.url.is_none() {
-                    println!("SSEのURLが指定されていません: {}", mcp_setting.name);
-                    continue;
-                }
+    /// 既定の場所にある設定ファイルを全て読み込みます。
+    ///
+    /// 優先度の低い順に`~/.config/brain/mcp.json`、カレントディレクトリの`mcp.json`、
+    /// 環境変数`BRAIN_MCP_CONFIG`のパスを読み込み、同じ名前のサーバーは後のファイルの定義で上書きします。
+    ///
+    /// サーバーは設定ファイルに記述された順に登録します。
+    /// 複数のサーバーが同じ名前のツールを提供する場合は、先に記述されたサーバーのツールのみを登録し、警告を表示します。
+    pub async fn load_from_default_locations(&mut self, max_concurrency: usize) -> McpSummary {
+        self.load_setting(&default_setting_paths(), max_concurrency).await
+    }
+
+    /// 設定ファイルのサーバーに接続してツールを読み込み、接続できたサーバー数とツール数を返します。
+    pub async fn load_setting(&mut self, file_paths: &[PathBuf], max_concurrency: usize) -> McpSummary {
+        let mcp_settings = load_setting_files(file_paths);
+        let mut cache = self.cache_path.as_deref().map(load_tools_cache);
 
-                self.add_mcp_server_sse(&mcp_setting.name, &mcp_setting.url.unwrap()).await;
+        // 同時に接続するサーバー数をセマフォで制限する
+        let semaphore = Arc::new(Semaphore::new(max_concurrency.max(1)));
+        let mut join_set = JoinSet::new();
+        for (index, mcp_setting) in mcp_settings.into_iter().enumerate() {
+            let semaphore = semaphore.clone();
+            let connect_timeout = self.connect_timeout;
+            let config_hash = mcp_setting.config_hash();
+            let cached_tools = cache.as_mut()
+                .and_then(|cache| cache.servers.remove(&mcp_setting.name))
+                .filter(|cached| cached.config_hash == config_hash)
+                .map(|cached| cached.tools);
+            join_set.spawn(async move {
+                let _permit = semaphore.acquire_owned().await.ok();
+                let name = mcp_setting.name.clone();
+                // 応答しないサーバーで起動全体が止まらないよう、接続とツール一覧の取得に時間制限を設ける
+                let tools = match tokio::time::timeout(connect_timeout, connect_mcp_server(&mcp_setting, cached_tools)).await {
+                    Ok(tools) => tools,
+                    Err(_) => {
+                        status!("MCPサーバーが応答しないためスキップしました: {} ({}秒)", name, connect_timeout.as_secs());
+                        None
+                    }
+                };
+                (index, name, config_hash, mcp_setting, tools)
+            });
+        }
+
+        let mut results = Vec::new();
+        while let Some(result) = join_set.join_next().await {
+            if let Ok(result) = result {
+                results.push(result);
+            }
+        }
 
-            } else if mcp_setting.connection_type.to_lowercase() == "stdio" {
-                if mcp_setting.command.is_none() {
-                    println!("stdioのコマンドが指定されていません: {}", mcp_setting.name);
+        // 接続の完了順ではなく、設定ファイルの順にツールを登録する
+        results.sort_by_key(|(index, _, _, _, _)| *index);
+        let mut summary = McpSummary { servers: results.len(), ..Default::default() };
+        let mut new_cache = ToolsCache::default();
+        let mut collisions = Vec::new();
+        for (_, name, config_hash, setting, tools) in results {
+            let Some((peer, tools)) = tools else {
+                continue;
+            };
+            summary.connected += 1;
+            let policy_of = |tool: &str| setting.tool_policy.get(tool).copied().unwrap_or(setting.policy);
+            let policies: Vec<ToolPolicy> = tools.iter().map(|tool| policy_of(tool.name.as_ref())).collect();
+            let handle = Arc::new(Mutex::new(ServerHandle { setting, peer, connect_timeout: self.connect_timeout }));
+            for (tool, policy) in tools.iter().zip(policies) {
+                // 同じ名前のツールはモデルが区別できないため、先に登録したサーバーのツールのみを使用する
+                if let Some(owner) = self.tool_servers.get(tool.name.as_ref()) {
+                    collisions.push(format!("{} ({}, {})", tool.name, owner, name));
                     continue;
                 }
+                self.tool_servers.insert(tool.name.to_string(), name.clone());
+                self.tools.push(McpTool {
+                    server: name.clone(),
+                    tool: tool.clone(),
+                    policy,
+                    handle: handle.clone(),
+                });
+                summary.tools += 1;
+            }
+            new_cache.servers.insert(name, CachedTools { config_hash, tools });
+        }
 
-                self.add_mcp_server_stdio(&mcp_setting.name, &mcp_setting.command.unwrap(), &mcp_setting.args).await;
-
-            } else {
-                println!("この接続方式はサポートしていません: {}", mcp_setting.connection_type);
+        if let Some(cache_path) = self.cache_path.as_ref() {
+            save_tools_cache(cache_path, &new_cache);
+        }
 
-            }
+        if !collisions.is_empty() {
+            status!("ツール名が重複しているため、先に定義されたサーバーのツールのみを使用します: {}", collisions.join(", "));
+        }
+        if summary.servers > 0 {
+            status!("{}", summary);
         }
+        summary
     }
+}
 
-    pub async fn add_mcp_server_sse(&mut self, name: &str, url: &str) {
-        let transport = SseTransport::start(url).await;
-        if transport.is_err() {
-            println!("SSEサーバーに接続できません: {} {}", name, url);
-            return;
+
+impl ToolHandler for McpTool {
+    fn definition(&self) -> ToolDefinition {
+        ToolDefinition {
+            name: self.tool.name.to_string(),
+            description: self.tool.description.to_string(),
+            parameters: serde_json::Value::Object(self.tool.input_schema.as_ref().clone()),
         }
-        let transport = transport.unwrap();
-
-        let client_info = ClientInfo {
-            protocol_version: Default::default(),
-            capabilities: ClientCapabilities::default(),
-            client_info: Implementation {
-                name: name.to_string(),
-                version: "0.0.1".to_string(),
-            },
+    }
+
+    fn source(&self) -> String {
+        format!("mcp:{}", self.server)
+    }
+
+    fn policy(&self) -> ToolPolicy {
+        self.policy
+    }
+
+    fn call(&mut self, arguments: serde_json::Value) -> ToolFuture<'_> {
+        Box::pin(async move {
+            let request = CallToolRequestParam {
+                name: self.tool.name.clone(),
+                arguments: arguments.as_object().cloned(),
+            };
+            let peer = self.handle.lock().await.peer.clone();
+            let result = match peer.call_tool(request.clone()).await {
+                // 接続が切れている場合は、接続し直して1回だけ再試行する
+                Err(ServiceError::Transport(e)) => {
+                    log::warn!("MCP server {} disconnected: {}", self.server, e);
+                    let peer = self.handle.lock().await.reconnect().await?;
+                    peer.call_tool(request).await?
+                }
+                result => result?,
+            };
+
+            let output = tool_output_from_content(&result.content);
+            if result.is_error.unwrap_or(false) {
+                return Err(format!("MCPツールが失敗しました: {} {}", self.tool.name, output.text).into());
+            }
+            Ok(output)
+        })
+    }
+}
+
+
+impl ServerHandle {
+    /// サーバーに接続し直し、新しい接続を返します。
+    async fn reconnect(&mut self) -> Result<Peer<RoleClient>, String> {
+        status!("MCPサーバーとの接続が切れたため、再接続します: {}", self.setting.name);
+        log::info!("reconnecting to MCP server {}", self.setting.name);
+
+        // ツール一覧は取得済みのため、接続のみを行う
+        let connection = tokio::time::timeout(self.connect_timeout, connect_mcp_server(&self.setting, Some(Vec::new()))).await;
+        let Ok(Some((peer, _))) = connection else {
+            log::warn!("failed to reconnect to MCP server {}", self.setting.name);
+            return Err(format!("MCPサーバーに再接続できません: {}", self.setting.name));
         };
+        self.peer = peer.clone();
+        Ok(peer)
+    }
+}
 
-        let client = client_info.serve(transport).await;
-        if client.is_err() {
-            println!("クライアントが作成できません: {}", name);
-            return;
-        }
-        let client = client.unwrap();
 
-        let tool_list = client.list_tools(Default::default()).await;
-        if tool_list.is_err() {
-            println!("ツールの取得に失敗しました: {}", name);
-            return;
+/// MCPのツール結果（複数パートのコンテンツ）を、テキストと画像に変換する
+pub fn tool_output_from_content(content: &[Content]) -> ToolOutput {
+    let mut texts = Vec::new();
+    let mut images = Vec::new();
+    for part in content {
+        match &part.raw {
+            RawContent::Text(text) => texts.push(text.text.clone()),
+            RawContent::Image(image) => images.push(Image::from_base64(image.data.clone())),
+            RawContent::Resource(resource) => match &resource.resource {
+                ResourceContents::TextResourceContents { text, .. } => texts.push(text.clone()),
+                ResourceContents::BlobResourceContents { blob, mime_type, .. } => {
+                    if mime_type.as_deref().is_some_and(|mime_type| mime_type.starts_with("image/")) {
+                        images.push(Image::from_base64(blob.clone()));
+                    }
+                }
+            },
         }
-        let tool_list = tool_list.unwrap();
+    }
+    ToolOutput { text: texts.join("\n"), images }
+}
+
+
+impl McpSetting {
+    /// ツール一覧のキャッシュが使えるかを判断するための、接続先の設定のハッシュ
+    ///
+    /// キャッシュはRustのバージョンをまたいで使うため、固定のアルゴリズム（64ビットのFNV-1a）で計算する。
+    /// ツールの扱い（`policy`、`tool_policy`）はツール一覧に影響しないため含めない。
+    fn config_hash(&self) -> String {
+        const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const PRIME: u64 = 0x0000_0100_0000_01b3;
 
-        for tool in tool_list.tools {
-            self.tools.push(tool);
+        // オブジェクトのキーの順序に左右されないよう、配列にして文字列にする
+        let connection = serde_json::json!([self.connection_type, self.url, self.command, self.args, self.env]);
+        let hash = connection.to_string().bytes()
+            .fold(OFFSET_BASIS, |hash, byte| (hash ^ byte as u64).wrapping_mul(PRIME));
+        format!("{:016x}", hash)
+    }
+}
+
+
+/// サーバーに接続し、ツールを呼び出すための接続とツール一覧を返す。キャッシュがある場合は一覧の取得を省略する
+async fn connect_mcp_server(mcp_setting: &McpSetting, cached_tools: Option<Vec<rmcp::model::Tool>>) -> Option<(Peer<RoleClient>, Vec<rmcp::model::Tool>)> {
+    if mcp_setting.connection_type.to_lowercase() == "sse" {
+        let Some(url) = mcp_setting.url.as_ref() else {
+            status!("SSEのURLが指定されていません: {}", mcp_setting.name);
+            return None;
+        };
+
+        connect_sse(&mcp_setting.name, url, cached_tools).await
+
+    } else if mcp_setting.connection_type.to_lowercase() == "stdio" {
+        let Some(command) = mcp_setting.command.as_ref() else {
+            status!("stdioのコマンドが指定されていません: {}", mcp_setting.name);
+            return None;
+        };
+
+        connect_stdio(&mcp_setting.name, command, &mcp_setting.args, &mcp_setting.env, cached_tools).await
+
+    } else {
+        status!("この接続方式はサポートしていません: {}", mcp_setting.connection_type);
+        None
+    }
+}
+
+
+async fn connect_sse(name: &str, url: &str, cached_tools: Option<Vec<rmcp::model::Tool>>) -> Option<(Peer<RoleClient>, Vec<rmcp::model::Tool>)> {
+    let transport = SseTransport::start(url).await;
+    if transport.is_err() {
+        status!("SSEサーバーに接続できません: {} {}", name, url);
+        return None;
+    }
+    let transport = transport.unwrap();
+
+    let client_info = ClientInfo {
+        protocol_version: Default::default(),
+        capabilities: ClientCapabilities::default(),
+        client_info: Implementation {
+            name: name.to_string(),
+            version: "0.0.1".to_string(),
+        },
+    };
+
+    let client = client_info.serve(transport).await;
+    if client.is_err() {
+        status!("クライアントが作成できません: {}", name);
+        return None;
+    }
+    let client = client.unwrap();
+
+    if let Some(cached_tools) = cached_tools {
+        return Some((client.peer().clone(), cached_tools));
+    }
+
+    let tool_list = client.list_tools(Default::default()).await;
+    if tool_list.is_err() {
+        status!("ツールの取得に失敗しました: {}", name);
+        return None;
+    }
+    Some((client.peer().clone(), tool_list.unwrap().tools))
+}
+
+
+async fn connect_stdio(name: &str, command: &str, args: &Option<Vec<String>>, env: &Option<BTreeMap<String, String>>, cached_tools: Option<Vec<rmcp::model::Tool>>) -> Option<(Peer<RoleClient>, Vec<rmcp::model::Tool>)> {
+    let mut command = match build_command(command, args, env) {
+        Ok(command) => command,
+        Err(e) => {
+            status!("stdioサーバーを起動できません: {} {}", name, e);
+            return None;
         }
+    };
+
+    let transport = TokioChildProcess::new(&mut command);
+    if transport.is_err() {
+        status!("stdioサーバーに接続できません: {}", name);
+        return None;
+    }
+    let transport = transport.unwrap();
+
+    let service = ().serve(transport).await;
+    if service.is_err() {
+        status!("サービスに接続できません: {}", name);
+        return None;
+    }
+    let service = service.unwrap();
+
+    if let Some(cached_tools) = cached_tools {
+        return Some((service.peer().clone(), cached_tools));
     }
 
-    pub async fn add_mcp_server_stdio(&mut self, name: &str, command: &str, args: &Option<Vec<String>>) {
-        let mut command = Command::new(command);
-        if let Some(args) = args.as_ref() {
-            for arg in args {
-                command.arg(arg);
+    // List tools
+    let tool_list = service.list_tools(Default::default()).await;
+    if tool_list.is_err() {
+        status!("ツールの取得に失敗しました: {}", name);
+        return None;
+    }
+    Some((service.peer().clone(), tool_list.unwrap().tools))
+}
+
+
+/// 環境変数を展開して、stdioサーバーを起動するコマンドを作成する
+fn build_command(command: &str, args: &Option<Vec<String>>, env: &Option<BTreeMap<String, String>>) -> Result<Command, String> {
+    let mut command = Command::new(expand_env(command)?);
+    for arg in args.iter().flatten() {
+        command.arg(expand_env(arg)?);
+    }
+    let env = env.iter().flatten()
+        .map(|(key, value)| Ok((key, expand_env(value)?)))
+        .collect::<Result<Vec<_>, String>>()?;
+    command.envs(env);
+    Ok(command)
+}
+
+
+/// `${VAR}`を環境変数の値に置き換える。設定されていない環境変数がある場合はエラーを返す
+fn expand_env(text: &str) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let placeholder = &rest[start + 2..];
+        let end = placeholder.find('}').ok_or_else(|| format!("プレースホルダーが閉じられていません: {}", &rest[start..]))?;
+        let name = &placeholder[..end];
+        let value = std::env::var(name).map_err(|_| format!("環境変数が設定されていません: ${{{}}}", name))?;
+        expanded.push_str(&value);
+        rest = &placeholder[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+
+fn load_tools_cache(cache_path: &str) -> ToolsCache {
+    std::fs::read_to_string(cache_path).ok()
+        .and_then(|json_data| serde_json::from_str(&json_data).ok())
+        .unwrap_or_default()
+}
+
+
+fn save_tools_cache(cache_path: &str, cache: &ToolsCache) {
+    let result = serde_json::to_string_pretty(cache)
+        .map_err(|e| e.to_string())
+        .and_then(|json_data| std::fs::write(cache_path, json_data).map_err(|e| e.to_string()));
+    if let Err(e) = result {
+        status!("キャッシュを保存できません: {} {}", cache_path, e);
+    }
+}
+
+
+/// 重複したキーも含めて、JSONオブジェクトの全てのエントリを記述された順に保持する
+///
+/// 外部ツールの設定ファイルも、ツールの順序を保つためにこの形式で読み込む。
+pub struct SettingEntries(pub Vec<(String, serde_json::Value)>);
+
+impl<'de> Deserialize<'de> for SettingEntries {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EntriesVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for EntriesVisitor {
+            type Value = SettingEntries;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a map of settings")
             }
-        }
 
-        let transport = TokioChildProcess::new(&mut command);
-        if transport.is_err() {
-            println!("stdioサーバーに接続できません: {}", name);
-            return;
+            fn visit_map<A: serde::de::MapAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+                let mut entries = Vec::new();
+                while let Some(entry) = access.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(SettingEntries(entries))
+            }
         }
-        let transport = transport.unwrap();
 
-        let service = ().serve(transport).await;
-        if service.is_err() {
-            println!("サービスに接続できません: {}", name);
-            return;
-        }
-        let service = service.unwrap();
+        deserializer.deserialize_map(EntriesVisitor)
+    }
+}
+
 
-        // List tools
-        let tool_list = service.list_tools(Default::default()).await;
-        if tool_list.is_err() {
-            println!("ツールの取得に失敗しました: {}", name);
-            return;
+fn default_setting_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if let Some(home) = std::env::var_os("HOME") {
+        paths.push(Path::new(&home).join(".config").join("brain").join("mcp.json"));
+    }
+    paths.push(PathBuf::from("mcp.json"));
+    if let Some(path) = std::env::var_os("BRAIN_MCP_CONFIG") {
+        paths.push(PathBuf::from(path));
+    }
+    paths
+}
+
+
+/// 複数の設定ファイルを順に読み込み、同じ名前のサーバーは後の定義で上書きする
+///
+/// 1つのファイル内での重複も、ファイルをまたいだ重複も同じように扱う。
+/// サーバーは最初に定義された位置の順に並べる。上書きしても位置は変わらない。
+fn load_setting_files(file_paths: &[PathBuf]) -> Vec<McpSetting> {
+    let mut entries: Vec<(String, serde_json::Value, &Path)> = Vec::new();
+    for file_path in file_paths {
+        let file_entries = match load_setting_file(file_path) {
+            Ok(file_entries) => file_entries,
+            Err(e) => {
+                status!("{}", e);
+                continue;
+            }
+        };
+        for (name, value) in file_entries.0 {
+            match entries.iter_mut().find(|(entry_name, _, _)| *entry_name == name) {
+                Some(entry) => {
+                    status!("MCPサーバーの定義を上書きしました: {} ({} -> {})", name, entry.2.display(), file_path.display());
+                    entry.1 = value;
+                    entry.2 = file_path;
+                }
+                None => entries.push((name, value, file_path)),
+            }
         }
-        let tool_list = tool_list.unwrap();
+    }
 
-        for tool in tool_list.tools {
-            self.tools.push(tool);
+    let mut settings: Vec<McpSetting> = Vec::new();
+    for (name, value, file_path) in entries {
+        match parse_setting(&name, &value) {
+            Ok(setting) => {
+                status!("MCPサーバーの定義を読み込みました: {} ({})", name, file_path.display());
+                settings.push(setting);
+            }
+            // 不正な定義のサーバーのみを読み飛ばし、他のサーバーには接続する
+            Err(e) => status!("MCPサーバーの定義が不正なためスキップしました: {} ({}): {}", name, file_path.display(), e),
         }
     }
+    settings
+}
 
 
-    pub fn show_tools(&self) {
-        for tool in &self.tools {
-            println!("name: {}", tool.name);
-            println!("description: {}", tool.description);
-            println!();
-        }
+/// サーバーの定義を検証し、接続方式に必要な項目が揃っている場合のみ設定を返す
+fn parse_setting(name: &str, value: &serde_json::Value) -> Result<McpSetting, String> {
+    if !value.is_object() {
+        return Err("定義はオブジェクトで記述してください".to_string());
     }
+    let connection_type = value["type"].as_str().ok_or("typeが指定されていません")?.to_lowercase();
+    let url = value.get("url").map(|url| url.as_str().ok_or("urlは文字列で指定してください")).transpose()?;
+    let command = value.get("command").map(|command| command.as_str().ok_or("commandは文字列で指定してください")).transpose()?;
+    let args = match value.get("args") {
+        Some(args) => Some(args.as_array()
+            .and_then(|args| args.iter().map(|arg| arg.as_str().map(|arg| arg.to_string())).collect::<Option<Vec<_>>>())
+            .ok_or("argsは文字列の配列で指定してください")?),
+        None => None,
+    };
+    let env = match value.get("env") {
+        Some(env) => Some(env.as_object()
+            .and_then(|env| env.iter().map(|(key, value)| value.as_str().map(|value| (key.clone(), value.to_string()))).collect::<Option<BTreeMap<_, _>>>())
+            .ok_or("envは値が文字列のオブジェクトで指定してください")?),
+        None => None,
+    };
+    let policy = match value.get("policy") {
+        Some(policy) => ToolPolicy::deserialize(policy).map_err(|_| "policyはauto、confirm、denyのいずれかで指定してください")?,
+        None => ToolPolicy::Auto,
+    };
+    let tool_policy = match value.get("tool_policy") {
+        Some(tool_policy) => BTreeMap::deserialize(tool_policy)
+            .map_err(|_| "tool_policyはツール名とauto、confirm、denyのいずれかのオブジェクトで指定してください")?,
+        None => BTreeMap::new(),
+    };
+
+    match connection_type.as_str() {
+        "sse" if url.is_none() => return Err("sseの接続にはurlが必要です".to_string()),
+        "stdio" if command.is_none() => return Err("stdioの接続にはcommandが必要です".to_string()),
+        "sse" | "stdio" => {}
+        _ => return Err(format!("この接続方式はサポートしていません: {}", connection_type)),
+    }
+
+    Ok(McpSetting {
+        name: name.to_string(),
+        connection_type,
+        url: url.map(|url| url.to_string() + "/sse"),
+        command: command.map(|command| command.to_string()),
+        args,
+        env,
+        policy,
+        tool_policy,
+    })
 }
 
 
-fn load_setting_file(file_path: &str) -> Vec<McpSetting> {
-    if !std::path::Path::new(file_path).exists() {
-        return Vec::new();
+/// 設定ファイルのサーバーを、ファイルに記述された順に返す。同じ名前のサーバーもそのまま返す
+fn load_setting_file(file_path: &Path) -> Result<SettingEntries, String> {
+    if !file_path.exists() {
+        return Ok(SettingEntries(Vec::new()));
     }
 
-    let file = std::fs::File::open(file_path).unwrap();
-    let reader = std::io::BufReader::new(file);
-    let json_data: String = reader.lines().filter_map(Result::ok).collect();
-    let map: HashMap<String, serde_json::Value> = serde_json::from_str(&json_data).expect("Unable to parse settings file");
+    let json_data = std::fs::read_to_string(file_path)
+        .map_err(|e| format!("MCPの設定ファイルを読み込めません: {} {}", file_path.display(), e))?;
+    serde_json::from_str(&json_data)
+        .map_err(|e| format!("MCPの設定ファイルの形式が正しくありません: {} ({})", file_path.display(), e))
+}
 
-    let mut settings: Vec<McpSetting> = Vec::new();
-    for (name, value) in map {
-        let entry_type = value["type"].as_str().unwrap_or_default().to_string();
-        let url = value["url"].as_str().map(|s| s.to_string() + "/sse");
-        let command = value["command"].as_str().map(|s| s.to_string());
-        let args = value["args"].as_array().map(|arr| {
-            arr.iter()
-                .filter_map(|v| v.as_str())
-                .map(|s| s.to_string())
-                .collect()
-        });
-
-        let setting = McpSetting {
-            name: name.to_string(),
-            connection_type: entry_type,
-            url,
-            command,
-            args,
-        };
-        settings.push(setting);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_servers_are_merged_within_and_across_files() {
+        let dir = std::env::temp_dir().join(format!("brain-mcp-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let first = dir.join("first.json");
+        let second = dir.join("second.json");
+        std::fs::write(&first, r#"{
+            "a": { "type": "stdio", "command": "a1" },
+            "b": { "type": "stdio", "command": "b1" },
+            "a": { "type": "stdio", "command": "a2" }
+        }"#).unwrap();
+        std::fs::write(&second, r#"{ "c": { "type": "stdio", "command": "c1" }, "b": { "type": "stdio", "command": "b2" } }"#).unwrap();
+
+        let settings = load_setting_files(&[first, second]);
+        std::fs::remove_dir_all(&dir).ok();
+        let servers: Vec<(&str, &str)> = settings.iter().map(|setting| (setting.name.as_str(), setting.command.as_deref().unwrap())).collect();
+        // 最初に定義された位置のまま、後の定義で上書きする
+        assert_eq!(servers, [("a", "a2"), ("b", "b2"), ("c", "c1")]);
     }
-    settings
-}
\ No newline at end of file
+
+    #[test]
+    fn config_hash_ignores_policies() {
+        let setting = |value: serde_json::Value| parse_setting("fs", &value).unwrap();
+        let base = setting(serde_json::json!({ "type": "stdio", "command": "mcp-fs", "args": ["/tmp"] }));
+        let with_policy = setting(serde_json::json!({ "type": "stdio", "command": "mcp-fs", "args": ["/tmp"], "policy": "deny", "tool_policy": { "read": "auto" } }));
+        let other_args = setting(serde_json::json!({ "type": "stdio", "command": "mcp-fs", "args": ["/home"] }));
+        assert_eq!(base.config_hash(), with_policy.config_hash());
+        assert_ne!(base.config_hash(), other_args.config_hash());
+        // Rustのバージョンによって変わらない
+        assert_eq!(base.config_hash(), "800e93b260c17640");
+    }
+}
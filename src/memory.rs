@@ -0,0 +1,113 @@
+//! `remember`と`recall`ツールで使う、キーと値の記憶
+//!
+//! 会話の履歴とは別に、モデルが覚えておきたい事柄を保持します。
+//! ファイルを指定した場合はJSONのオブジェクトとして保存し、他のプロセスと同時に書き込まないよう
+//! `<file>.lock`をロックしてから読み書きします。ファイルを指定しない場合は、起動している間のみ保持します。
+
+use std::{collections::BTreeMap, fs::File, path::{Path, PathBuf}, sync::Mutex};
+
+
+type MemoryResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// 記憶できる項目の最大数
+const MAX_ENTRIES: usize = 200;
+const MAX_KEY_CHARS: usize = 100;
+const MAX_VALUE_CHARS: usize = 2000;
+
+
+pub struct MemoryStore {
+    /// 保存先のファイル。Noneの場合はファイルに保存しない
+    path: Option<PathBuf>,
+    /// 記憶している項目。ファイルに保存する場合は、同じプロセス内の読み書きを1つずつ行うためにも使う
+    entries: Mutex<BTreeMap<String, String>>,
+}
+
+
+impl MemoryStore {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        MemoryStore { path, entries: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// 値を記憶します。同じキーの値がある場合は上書きします。
+    pub fn remember(&self, key: &str, value: &str) -> MemoryResult<()> {
+        if key.is_empty() || key.chars().count() > MAX_KEY_CHARS {
+            return Err(format!("キーは1文字以上{}文字以内で指定してください", MAX_KEY_CHARS).into());
+        }
+        if value.chars().count() > MAX_VALUE_CHARS {
+            return Err(format!("値は{}文字以内で指定してください", MAX_VALUE_CHARS).into());
+        }
+
+        let mut entries = self.entries.lock().map_err(|_| "記憶を読み込めません")?;
+        let _lock = self.lock()?;
+        self.reload(&mut entries)?;
+        if !entries.contains_key(key) && entries.len() >= MAX_ENTRIES {
+            return Err(format!("記憶できる項目は{}件までです。既存のキーを上書きしてください", MAX_ENTRIES).into());
+        }
+        entries.insert(key.to_string(), value.to_string());
+        self.write(&entries)
+    }
+
+    /// 記憶している値を返します。
+    pub fn recall(&self, key: &str) -> MemoryResult<Option<String>> {
+        let mut entries = self.entries.lock().map_err(|_| "記憶を読み込めません")?;
+        let _lock = self.lock()?;
+        self.reload(&mut entries)?;
+        Ok(entries.get(key).cloned())
+    }
+
+    /// 記憶しているキーの一覧を返します。
+    pub fn keys(&self) -> MemoryResult<Vec<String>> {
+        let mut entries = self.entries.lock().map_err(|_| "記憶を読み込めません")?;
+        let _lock = self.lock()?;
+        self.reload(&mut entries)?;
+        Ok(entries.keys().cloned().collect())
+    }
+
+    /// 他のプロセスと同時に読み書きしないよう、ロック用のファイルをロックする。ロックは戻り値を破棄すると解除される
+    fn lock(&self) -> MemoryResult<Option<File>> {
+        let Some(path) = self.path.as_ref() else {
+            return Ok(None);
+        };
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = File::options().create(true).truncate(false).write(true).open(lock_path(path))?;
+        file.lock()?;
+        Ok(Some(file))
+    }
+
+    /// ファイルに保存している場合は、他のプロセスが書き込んだ内容を読み込み直す
+    fn reload(&self, entries: &mut BTreeMap<String, String>) -> MemoryResult<()> {
+        let Some(path) = self.path.as_ref() else {
+            return Ok(());
+        };
+        *entries = if path.exists() {
+            serde_json::from_str(&std::fs::read_to_string(path)?)?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(())
+    }
+
+    /// 書き込みの途中で終了しても壊れないよう、一時ファイルに書き込んでから置き換える
+    fn write(&self, entries: &BTreeMap<String, String>) -> MemoryResult<()> {
+        let Some(path) = self.path.as_ref() else {
+            return Ok(());
+        };
+        let temp_path = path.with_extension("tmp");
+        std::fs::write(&temp_path, serde_json::to_string_pretty(entries)?)?;
+        std::fs::rename(temp_path, path)?;
+        Ok(())
+    }
+}
+
+
+fn lock_path(path: &Path) -> PathBuf {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    PathBuf::from(lock_path)
+}
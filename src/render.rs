@@ -0,0 +1,728 @@
+use std::{collections::VecDeque, io::{IsTerminal, Write}, sync::atomic::{AtomicBool, Ordering}, time::Duration};
+use serde::Serialize;
+use serde_json::Value;
+use crate::verbosity::is_quiet;
+
+
+/// 出力先のパイプが閉じられたか（`brain ... | head`で`head`が終了した場合など）
+static OUTPUT_CLOSED: AtomicBool = AtomicBool::new(false);
+
+/// 出力先が閉じられたかを返します。閉じられた場合、ストリーミングを打ち切って正常に終了します。
+pub fn output_closed() -> bool {
+    OUTPUT_CLOSED.load(Ordering::SeqCst)
+}
+
+/// 書き込みの結果を確認し、パイプが閉じられていれば記録する。それ以外のエラーは無視する
+fn check_write(result: std::io::Result<()>) {
+    if let Err(e) = result
+        && e.kind() == std::io::ErrorKind::BrokenPipe {
+        log::info!("output closed: {}", e);
+        OUTPUT_CLOSED.store(true, Ordering::SeqCst);
+    }
+}
+
+/// 標準出力に書き込む。パイプが閉じられた後は何もしない
+fn write_stdout(text: &str) {
+    if output_closed() {
+        return;
+    }
+    let mut stdout = std::io::stdout().lock();
+    check_write(stdout.write_all(text.as_bytes()).and_then(|_| stdout.flush()));
+}
+
+/// 実行中のツールの表示を切り替える間隔
+pub const TOOL_PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+const SPINNER_FRAMES: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+/// ツールの結果を1行で表示する時の最大文字数
+const TOOL_RESULT_PREVIEW_CHARS: usize = 80;
+
+/// ツールの結果の最初の行を、表示用に短くする
+fn tool_result_preview(result: &str) -> String {
+    let first_line = result.lines().map(str::trim).find(|line| !line.is_empty()).unwrap_or_default();
+    let mut preview: String = first_line.chars().take(TOOL_RESULT_PREVIEW_CHARS).collect();
+    if first_line.chars().count() > TOOL_RESULT_PREVIEW_CHARS || result.trim().lines().count() > 1 {
+        preview.push('…');
+    }
+    preview
+}
+
+
+/// 生成結果の出力先
+///
+/// 他の出力先を包む場合は`inner`で包んだ出力先を返すと、実装していないメソッドはそのまま包んだ出力先へ渡します。
+/// 何も包まない場合、実装していないメソッドは何もしません。
+pub trait OutputRenderer {
+    /// 包んでいる出力先を返します。
+    fn inner(&mut self) -> Option<&mut dyn OutputRenderer> {
+        None
+    }
+    /// 送信するユーザーの入力を受け取ります。記録を自己完結させるための出力で、端末への表示は不要です。
+    fn on_user_prompt(&mut self, prompt: &str) {
+        if let Some(inner) = self.inner() {
+            inner.on_user_prompt(prompt);
+        }
+    }
+    /// 応答本文の一部（ストリーミングしない場合は全体）を受け取ります。
+    fn on_content_chunk(&mut self, chunk: &str) {
+        if let Some(inner) = self.inner() {
+            inner.on_content_chunk(chunk);
+        }
+    }
+    /// thinkingモデルの思考過程（`<think>`タグの中身）の一部を受け取ります。
+    fn on_thinking_chunk(&mut self, chunk: &str) {
+        if let Some(inner) = self.inner() {
+            inner.on_thinking_chunk(chunk);
+        }
+    }
+    fn on_tool_call(&mut self, name: &str, arguments: &Value) {
+        if let Some(inner) = self.inner() {
+            inner.on_tool_call(name, arguments);
+        }
+    }
+    fn on_tool_result(&mut self, name: &str, result: &str) {
+        if let Some(inner) = self.inner() {
+            inner.on_tool_result(name, result);
+        }
+    }
+    /// ツールの実行中に一定の間隔で呼ばれます。`elapsed`はツールを呼び出してからの時間です。
+    fn on_tool_progress(&mut self, name: &str, elapsed: Duration) {
+        if let Some(inner) = self.inner() {
+            inner.on_tool_progress(name, elapsed);
+        }
+    }
+    /// 読み込み待ちや履歴の圧縮など、応答以外の通知を受け取ります。
+    fn on_notice(&mut self, message: &str) {
+        if let Some(inner) = self.inner() {
+            inner.on_notice(message);
+        }
+    }
+    fn on_error(&mut self, error: &str) {
+        if let Some(inner) = self.inner() {
+            inner.on_error(error);
+        }
+    }
+    /// 応答が完了した時に呼ばれます。
+    fn on_done(&mut self) {
+        if let Some(inner) = self.inner() {
+            inner.on_done();
+        }
+    }
+    /// 表示を遅らせている出力を1つ表示し、次を表示するまで待つ時間を返します。
+    ///
+    /// 遅らせている出力がない場合はNoneを返します。待つのは呼び出し側で、非同期に待つことで生成の中断を妨げません。
+    fn next_delay(&mut self) -> Option<Duration> {
+        self.inner().and_then(|inner| inner.next_delay())
+    }
+}
+
+
+/// 端末に人が読める形式で出力する
+///
+/// `--quiet`の場合は応答本文のみを出力し、エラーは標準エラー出力に出力します。
+pub struct TerminalRenderer;
+
+impl OutputRenderer for TerminalRenderer {
+    fn on_content_chunk(&mut self, chunk: &str) {
+        write_stdout(chunk);
+    }
+
+    fn on_thinking_chunk(&mut self, chunk: &str) {
+        if is_quiet() {
+            return;
+        }
+        // 思考過程は応答本文と区別できるよう灰色で表示する
+        write_stdout(&crate::color::thinking(chunk));
+    }
+
+    fn on_tool_call(&mut self, name: &str, arguments: &Value) {
+        if is_quiet() {
+            return;
+        }
+        write_stdout(&format!("{} {} {}\n", crate::color::tool("tool:"), name, arguments));
+    }
+
+    fn on_tool_result(&mut self, name: &str, result: &str) {
+        // 端末の場合は、実行中の表示を結果の1行目で置き換える
+        if std::io::stdout().is_terminal() && !is_quiet() {
+            write_stdout(&format!("\r\x1b[2K{} {} {}\n", crate::color::tool("result:"), name, tool_result_preview(result)));
+        }
+    }
+
+    fn on_tool_progress(&mut self, name: &str, elapsed: Duration) {
+        // 端末以外では、制御文字が出力に残らないよう表示しない
+        if !std::io::stdout().is_terminal() || is_quiet() {
+            return;
+        }
+        let frame = SPINNER_FRAMES[(elapsed.as_millis() / TOOL_PROGRESS_INTERVAL.as_millis()) as usize % SPINNER_FRAMES.len()];
+        write_stdout(&format!("\r\x1b[2K{} running {}... ({:.1}s)", crate::color::tool(&frame.to_string()), name, elapsed.as_secs_f64()));
+    }
+
+    fn on_notice(&mut self, message: &str) {
+        if is_quiet() {
+            return;
+        }
+        write_stdout(&format!("{}\n", message));
+    }
+
+    fn on_error(&mut self, error: &str) {
+        // 応答本文のみを取り込めるよう、エラーは標準エラー出力に分ける
+        if is_quiet() {
+            eprintln!("Error: {}", error);
+            return;
+        }
+        write_stdout(&format!("Error: {}\n", error));
+    }
+
+    fn on_done(&mut self) {
+        write_stdout("\n");
+    }
+}
+
+
+/// 応答本文をMarkdownとして整形し、端末に出力する
+///
+/// 整形には本文の全体が必要なため、応答が完了した時（またはツールを呼び出す前）にまとめて出力します。
+#[derive(Default)]
+pub struct MarkdownRenderer {
+    content: String,
+}
+
+impl MarkdownRenderer {
+    fn flush_content(&mut self) {
+        if !self.content.is_empty() {
+            write_stdout(&format!("{}\n", crate::markdown::render(&self.content)));
+            self.content.clear();
+        }
+    }
+}
+
+impl OutputRenderer for MarkdownRenderer {
+    fn on_content_chunk(&mut self, chunk: &str) {
+        self.content.push_str(chunk);
+    }
+
+    fn on_thinking_chunk(&mut self, chunk: &str) {
+        TerminalRenderer.on_thinking_chunk(chunk);
+    }
+
+    fn on_tool_call(&mut self, name: &str, arguments: &Value) {
+        self.flush_content();
+        TerminalRenderer.on_tool_call(name, arguments);
+    }
+
+    fn on_tool_result(&mut self, name: &str, result: &str) {
+        TerminalRenderer.on_tool_result(name, result);
+    }
+
+    fn on_tool_progress(&mut self, name: &str, elapsed: Duration) {
+        TerminalRenderer.on_tool_progress(name, elapsed);
+    }
+
+    fn on_notice(&mut self, message: &str) {
+        TerminalRenderer.on_notice(message);
+    }
+
+    fn on_error(&mut self, error: &str) {
+        TerminalRenderer.on_error(error);
+    }
+
+    fn on_done(&mut self) {
+        self.flush_content();
+    }
+}
+
+
+/// 応答本文と思考過程を1語ずつ間隔を空けて出力先へ渡し、ストリーミングしているように表示する
+///
+/// `--no-stream`などで一括で受け取った応答を少しずつ表示するための、表示のみの機能です。
+/// 受け取った語は溜めておき、`next_delay`が呼ばれるたびに1語ずつ出力先へ渡します。
+/// 間隔は1文字あたりの時間で、語の文字数に応じて待ちます。
+pub struct TypewriterRenderer {
+    inner: Box<dyn OutputRenderer>,
+    delay: Duration,
+    /// まだ表示していない語と、思考過程か
+    pending: VecDeque<(String, bool)>,
+}
+
+impl TypewriterRenderer {
+    pub fn new(inner: Box<dyn OutputRenderer>, delay: Duration) -> Self {
+        TypewriterRenderer { inner, delay, pending: VecDeque::new() }
+    }
+
+    fn queue(&mut self, text: &str, thinking: bool) {
+        self.pending.extend(text.split_inclusive(char::is_whitespace).map(|word| (word.to_string(), thinking)));
+    }
+
+    fn emit(&mut self, word: &str, thinking: bool) {
+        if thinking {
+            self.inner.on_thinking_chunk(word);
+        } else {
+            self.inner.on_content_chunk(word);
+        }
+    }
+
+    /// 他の出力と順序が入れ替わらないよう、溜めている語を待たずに全て表示する
+    fn flush(&mut self) {
+        while let Some((word, thinking)) = self.pending.pop_front() {
+            self.emit(&word, thinking);
+        }
+    }
+}
+
+impl OutputRenderer for TypewriterRenderer {
+    fn on_user_prompt(&mut self, prompt: &str) {
+        // 中断した応答の残りは表示しない
+        self.pending.clear();
+        self.inner.on_user_prompt(prompt);
+    }
+
+    fn on_content_chunk(&mut self, chunk: &str) {
+        self.queue(chunk, false);
+    }
+
+    fn on_thinking_chunk(&mut self, chunk: &str) {
+        self.queue(chunk, true);
+    }
+
+    fn on_tool_call(&mut self, name: &str, arguments: &Value) {
+        self.flush();
+        self.inner.on_tool_call(name, arguments);
+    }
+
+    fn on_tool_result(&mut self, name: &str, result: &str) {
+        self.flush();
+        self.inner.on_tool_result(name, result);
+    }
+
+    fn on_tool_progress(&mut self, name: &str, elapsed: Duration) {
+        self.flush();
+        self.inner.on_tool_progress(name, elapsed);
+    }
+
+    fn on_notice(&mut self, message: &str) {
+        self.flush();
+        self.inner.on_notice(message);
+    }
+
+    fn on_error(&mut self, error: &str) {
+        self.flush();
+        self.inner.on_error(error);
+    }
+
+    fn on_done(&mut self) {
+        self.flush();
+        self.inner.on_done();
+    }
+
+    fn next_delay(&mut self) -> Option<Duration> {
+        if output_closed() {
+            self.pending.clear();
+            return None;
+        }
+        let (word, thinking) = self.pending.pop_front()?;
+        self.emit(&word, thinking);
+        Some(self.delay * word.chars().count() as u32)
+    }
+}
+
+
+/// JSON Lines形式で出力するイベント
+///
+/// 1行に1つのJSONオブジェクトを出力し、`type`でイベントの種類を表します。
+/// * `prompt`: ユーザーの入力（`--echo-prompt`指定時） `{"type":"prompt","role":"user","content":"..."}`
+/// * `content_delta`: 応答本文の差分 `{"type":"content_delta","content":"..."}`
+/// * `thinking_delta`: 思考過程の差分 `{"type":"thinking_delta","content":"..."}`
+/// * `tool_call_started`: ツールの呼び出し開始 `{"type":"tool_call_started","name":"...","arguments":{...}}`
+/// * `tool_result`: ツールの実行結果 `{"type":"tool_result","name":"...","result":"..."}`
+/// * `notice`: 応答以外の通知 `{"type":"notice","message":"..."}`
+/// * `error`: エラー `{"type":"error","error":"..."}`
+/// * `done`: 応答の完了 `{"type":"done"}`
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event<'a> {
+    Prompt { role: &'a str, content: &'a str },
+    ContentDelta { content: &'a str },
+    ThinkingDelta { content: &'a str },
+    ToolCallStarted { name: &'a str, arguments: &'a Value },
+    ToolResult { name: &'a str, result: &'a str },
+    Notice { message: &'a str },
+    Error { error: &'a str },
+    Done,
+}
+
+
+/// イベントをJSON Lines形式で書き出す
+pub struct JsonRenderer<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> JsonRenderer<W> {
+    pub fn new(writer: W) -> Self {
+        JsonRenderer { writer }
+    }
+
+    fn emit(&mut self, event: Event) {
+        if let Ok(line) = serde_json::to_string(&event) {
+            check_write(writeln!(self.writer, "{}", line).and_then(|_| self.writer.flush()));
+        }
+    }
+}
+
+impl<W: Write> OutputRenderer for JsonRenderer<W> {
+    fn on_user_prompt(&mut self, prompt: &str) {
+        self.emit(Event::Prompt { role: "user", content: prompt });
+    }
+
+    fn on_content_chunk(&mut self, chunk: &str) {
+        self.emit(Event::ContentDelta { content: chunk });
+    }
+
+    fn on_thinking_chunk(&mut self, chunk: &str) {
+        self.emit(Event::ThinkingDelta { content: chunk });
+    }
+
+    fn on_tool_call(&mut self, name: &str, arguments: &Value) {
+        self.emit(Event::ToolCallStarted { name, arguments });
+    }
+
+    fn on_tool_result(&mut self, name: &str, result: &str) {
+        self.emit(Event::ToolResult { name, result });
+    }
+
+    fn on_notice(&mut self, message: &str) {
+        self.emit(Event::Notice { message });
+    }
+
+    fn on_error(&mut self, error: &str) {
+        self.emit(Event::Error { error });
+    }
+
+    fn on_done(&mut self) {
+        self.emit(Event::Done);
+    }
+}
+
+
+/// 1回の応答の内容
+///
+/// `{"prompt":"...","thinking":"...","content":"...","tool_calls":[{"name":"...","arguments":{...},"result":"..."}]}`
+/// 失敗した場合は`error`、履歴の圧縮などの通知がある場合は`notices`が追加されます。
+#[derive(Debug, Default, Serialize)]
+pub struct Turn {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+    pub thinking: String,
+    pub content: String,
+    pub tool_calls: Vec<TurnToolCall>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub notices: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TurnToolCall {
+    pub name: String,
+    pub arguments: Value,
+    pub result: Option<String>,
+}
+
+
+/// 1回の応答ごとに`Turn`を1行のJSONとして書き出す
+pub struct TurnJsonRenderer<W: Write> {
+    writer: W,
+    turn: Turn,
+}
+
+impl<W: Write> TurnJsonRenderer<W> {
+    pub fn new(writer: W) -> Self {
+        TurnJsonRenderer { writer, turn: Turn::default() }
+    }
+
+    fn emit(&mut self) {
+        let turn = std::mem::take(&mut self.turn);
+        if let Ok(line) = serde_json::to_string(&turn) {
+            check_write(writeln!(self.writer, "{}", line).and_then(|_| self.writer.flush()));
+        }
+    }
+}
+
+impl<W: Write> OutputRenderer for TurnJsonRenderer<W> {
+    fn on_user_prompt(&mut self, prompt: &str) {
+        self.turn.prompt = Some(prompt.to_string());
+    }
+
+    fn on_content_chunk(&mut self, chunk: &str) {
+        self.turn.content.push_str(chunk);
+    }
+
+    fn on_thinking_chunk(&mut self, chunk: &str) {
+        self.turn.thinking.push_str(chunk);
+    }
+
+    fn on_tool_call(&mut self, name: &str, arguments: &Value) {
+        self.turn.tool_calls.push(TurnToolCall { name: name.to_string(), arguments: arguments.clone(), result: None });
+    }
+
+    fn on_tool_result(&mut self, name: &str, result: &str) {
+        if let Some(tool_call) = self.turn.tool_calls.iter_mut().rev().find(|tool_call| tool_call.name == name && tool_call.result.is_none()) {
+            tool_call.result = Some(result.to_string());
+        }
+    }
+
+    fn on_notice(&mut self, message: &str) {
+        self.turn.notices.push(message.to_string());
+    }
+
+    fn on_error(&mut self, error: &str) {
+        // 失敗した応答には完了の通知がないため、ここで出力する
+        self.turn.error = Some(error.to_string());
+        self.emit();
+    }
+
+    fn on_done(&mut self) {
+        self.emit();
+    }
+}
+
+
+/// `ThinkTagFilter`が振り分けた応答の断片
+#[derive(Debug, Clone, PartialEq)]
+pub enum ThinkSegment {
+    Content(String),
+    Thinking(String),
+}
+
+
+/// 思考過程の開始と終了を示すタグ
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThinkTags {
+    pub open: String,
+    pub close: String,
+}
+
+impl Default for ThinkTags {
+    fn default() -> Self {
+        ThinkTags { open: "<think>".to_string(), close: "</think>".to_string() }
+    }
+}
+
+
+/// ストリーミングの応答を`<think>`から`</think>`までの思考過程と本文に振り分ける
+///
+/// タグがチャンクの境界で分割されても正しく振り分けられるよう、タグの途中かもしれない末尾は
+/// 次のチャンクが届くまで保持します。`</think>`が届かないまま終了した場合は、残りを思考過程として扱います。
+/// タグは`with_tags`で変更できます。
+#[derive(Debug, Default)]
+pub struct ThinkTagFilter {
+    tags: ThinkTags,
+    pending: String,
+    in_thinking: bool,
+}
+
+impl ThinkTagFilter {
+    pub fn with_tags(tags: ThinkTags) -> Self {
+        ThinkTagFilter { tags, ..Self::default() }
+    }
+
+    /// チャンクを追加し、振り分けが確定した断片を返します。
+    pub fn feed(&mut self, chunk: &str) -> Vec<ThinkSegment> {
+        self.pending.push_str(chunk);
+
+        let mut segments = Vec::new();
+        loop {
+            let tag = if self.in_thinking { self.tags.close.clone() } else { self.tags.open.clone() };
+            if let Some(pos) = self.pending.find(&tag) {
+                let text = self.pending[..pos].to_string();
+                self.pending.drain(..pos + tag.len());
+                self.push_segment(&mut segments, text);
+                self.in_thinking = !self.in_thinking;
+                continue;
+            }
+
+            // タグの先頭と一致する末尾は、タグが分割されている可能性があるため保持する
+            let keep = (1..tag.len()).rev()
+                .find(|len| tag.is_char_boundary(*len) && self.pending.ends_with(&tag[..*len]))
+                .unwrap_or(0);
+            let text: String = self.pending.drain(..self.pending.len() - keep).collect();
+            self.push_segment(&mut segments, text);
+            return segments;
+        }
+    }
+
+    /// 応答の終了時に、保持している残りを返します。
+    pub fn finish(&mut self) -> Vec<ThinkSegment> {
+        let mut segments = Vec::new();
+        let text = std::mem::take(&mut self.pending);
+        self.push_segment(&mut segments, text);
+        self.in_thinking = false;
+        segments
+    }
+
+    fn push_segment(&self, segments: &mut Vec<ThinkSegment>, text: String) {
+        if text.is_empty() {
+            return;
+        }
+        segments.push(if self.in_thinking { ThinkSegment::Thinking(text) } else { ThinkSegment::Content(text) });
+    }
+}
+
+
+/// 応答本文のうち`<think>`から`</think>`までを思考過程として振り分け、出力先へ渡す
+///
+/// 思考過程を非表示にする場合は、その部分を出力しません。
+pub struct ThinkingSplitter<'a> {
+    inner: &'a mut dyn OutputRenderer,
+    hide_thinking: bool,
+    filter: ThinkTagFilter,
+}
+
+impl<'a> ThinkingSplitter<'a> {
+    pub fn new(inner: &'a mut dyn OutputRenderer, hide_thinking: bool, tags: ThinkTags) -> Self {
+        ThinkingSplitter { inner, hide_thinking, filter: ThinkTagFilter::with_tags(tags) }
+    }
+
+    fn emit(&mut self, segments: Vec<ThinkSegment>) {
+        for segment in segments {
+            match segment {
+                ThinkSegment::Content(text) => self.inner.on_content_chunk(&text),
+                ThinkSegment::Thinking(text) if !self.hide_thinking => self.inner.on_thinking_chunk(&text),
+                ThinkSegment::Thinking(_) => {}
+            }
+        }
+    }
+
+    /// 保持しているタグの途中かもしれない部分を出力します。
+    pub fn flush(&mut self) {
+        let segments = self.filter.finish();
+        self.emit(segments);
+    }
+}
+
+impl OutputRenderer for ThinkingSplitter<'_> {
+    fn inner(&mut self) -> Option<&mut dyn OutputRenderer> {
+        Some(self.inner)
+    }
+
+    fn on_content_chunk(&mut self, chunk: &str) {
+        let segments = self.filter.feed(chunk);
+        self.emit(segments);
+    }
+
+    fn on_thinking_chunk(&mut self, chunk: &str) {
+        if !self.hide_thinking {
+            self.inner.on_thinking_chunk(chunk);
+        }
+    }
+
+    fn on_done(&mut self) {
+        self.flush();
+        self.inner.on_done();
+    }
+}
+
+
+/// 出力を文字列として保持する
+///
+/// テストやGUIなど、端末を使わずに応答やツールの呼び出しを受け取る場合に使用します。
+#[allow(dead_code)]
+#[derive(Debug, Default)]
+pub struct StringRenderer {
+    pub content: String,
+    pub thinking: String,
+    /// ツール名と引数
+    pub tool_calls: Vec<(String, Value)>,
+    pub notices: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+impl OutputRenderer for StringRenderer {
+    fn on_content_chunk(&mut self, chunk: &str) {
+        self.content.push_str(chunk);
+    }
+
+    fn on_thinking_chunk(&mut self, chunk: &str) {
+        self.thinking.push_str(chunk);
+    }
+
+    fn on_tool_call(&mut self, name: &str, arguments: &Value) {
+        self.tool_calls.push((name.to_string(), arguments.clone()));
+    }
+
+    fn on_tool_result(&mut self, _name: &str, _result: &str) {}
+
+    fn on_notice(&mut self, message: &str) {
+        self.notices.push(message.to_string());
+    }
+
+    fn on_error(&mut self, error: &str) {
+        self.errors.push(error.to_string());
+    }
+
+    fn on_done(&mut self) {}
+}
+
+
+/// 何も出力しない
+pub struct NullRenderer;
+
+impl OutputRenderer for NullRenderer {
+    fn on_content_chunk(&mut self, _chunk: &str) {}
+    fn on_tool_call(&mut self, _name: &str, _arguments: &Value) {}
+    fn on_tool_result(&mut self, _name: &str, _result: &str) {}
+    fn on_notice(&mut self, _message: &str) {}
+    fn on_error(&mut self, _error: &str) {}
+    fn on_done(&mut self) {}
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 断片を1つの本文と思考過程にまとめる
+    fn collect(segments: Vec<ThinkSegment>, content: &mut String, thinking: &mut String) {
+        for segment in segments {
+            match segment {
+                ThinkSegment::Content(text) => content.push_str(&text),
+                ThinkSegment::Thinking(text) => thinking.push_str(&text),
+            }
+        }
+    }
+
+    #[test]
+    fn think_tags_split_into_single_characters() {
+        let mut filter = ThinkTagFilter::default();
+        let (mut content, mut thinking) = (String::new(), String::new());
+        for c in "前置き<think>考え中…</think>答えです".chars() {
+            collect(filter.feed(&c.to_string()), &mut content, &mut thinking);
+        }
+        collect(filter.finish(), &mut content, &mut thinking);
+        assert_eq!(content, "前置き答えです");
+        assert_eq!(thinking, "考え中…");
+    }
+
+    #[test]
+    fn unclosed_think_tag_is_thinking_at_end() {
+        let mut filter = ThinkTagFilter::default();
+        let (mut content, mut thinking) = (String::new(), String::new());
+        collect(filter.feed("<think>途中で"), &mut content, &mut thinking);
+        collect(filter.feed("終わった</thi"), &mut content, &mut thinking);
+        collect(filter.finish(), &mut content, &mut thinking);
+        assert_eq!(content, "");
+        assert_eq!(thinking, "途中で終わった</thi");
+    }
+
+    #[test]
+    fn custom_think_tags() {
+        let mut filter = ThinkTagFilter::with_tags(ThinkTags { open: "[[".to_string(), close: "]]".to_string() });
+        let (mut content, mut thinking) = (String::new(), String::new());
+        collect(filter.feed("a[[b]"), &mut content, &mut thinking);
+        collect(filter.feed("]c"), &mut content, &mut thinking);
+        collect(filter.finish(), &mut content, &mut thinking);
+        assert_eq!(content, "ac");
+        assert_eq!(thinking, "b");
+    }
+}
@@ -0,0 +1,120 @@
+//! WebSocketサーバー
+//!
+//! クライアントはテキストメッセージとしてプロンプトを送信し、応答は`render::Event`の
+//! JSONを1メッセージずつ受け取ります。会話履歴は接続ごとに保持されます。
+//! 生成中に送られたプロンプトは無視され、接続が閉じられた場合は生成を中断します。
+
+use std::{io::Write, rc::Rc};
+use futures_util::{SinkExt, StreamExt};
+use tokio::{net::{TcpListener, TcpStream}, sync::mpsc};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use crate::{render::JsonRenderer, verbosity::status, Args};
+
+
+/// 接続を受け付けられなかった場合に、次の受け付けまで待つ時間
+const ACCEPT_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(100);
+
+
+pub async fn serve_websocket(addr: &str, args: Rc<Args>) -> Result<(), Box<dyn std::error::Error>> {
+    let listener = TcpListener::bind(addr).await?;
+    status!("WebSocketサーバーを起動しました: ws://{}", listener.local_addr()?);
+
+    loop {
+        // ファイルディスクリプタの不足など一時的な失敗では、サーバーを終了せずに次の接続を待つ
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                log::warn!("failed to accept a connection: {}", e);
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let args = args.clone();
+        tokio::task::spawn_local(async move {
+            if let Err(e) = handle_connection(stream, args).await {
+                log::warn!("connection closed with an error: {} {}", peer, e);
+            }
+        });
+    }
+}
+
+
+async fn handle_connection(stream: TcpStream, args: Rc<Args>) -> Result<(), Box<dyn std::error::Error>> {
+    let websocket = tokio_tungstenite::accept_async(stream).await?;
+    let (mut write, mut read) = websocket.split();
+
+    let (sender, mut receiver) = mpsc::unbounded_channel::<String>();
+    let mut chat = crate::build_chat(&args)?;
+    // サーバーのコンソールで確認を待つとすべての接続が止まるため、確認が必要なツールは実行しない
+    chat.set_interactive(false);
+    chat.set_renderer(Box::new(JsonRenderer::new(ChannelWriter::new(sender))));
+
+    let forward = tokio::task::spawn_local(async move {
+        while let Some(line) = receiver.recv().await {
+            if write.send(Message::Text(line.into())).await.is_err() {
+                break;
+            }
+        }
+        write.close().await.ok();
+    });
+
+    while let Some(message) = read.next().await {
+        let prompt = match message? {
+            Message::Text(text) => text.to_string(),
+            Message::Close(_) => break,
+            _ => continue,
+        };
+
+        // 生成中に接続が閉じられた場合は、生成のFutureを破棄して中断する
+        let closed = tokio::select! {
+            _ = chat.generate_response(&prompt) => false,
+            _ = wait_for_close(&mut read) => true,
+        };
+        if closed {
+            break;
+        }
+    }
+
+    // Chatを破棄して送信側を閉じ、転送タスクを終了させる
+    drop(chat);
+    forward.await?;
+    Ok(())
+}
+
+
+async fn wait_for_close(read: &mut futures_util::stream::SplitStream<WebSocketStream<TcpStream>>) {
+    while let Some(message) = read.next().await {
+        if matches!(message, Ok(Message::Close(_)) | Err(_)) {
+            return;
+        }
+    }
+}
+
+
+/// 書き込まれた内容を1行ずつチャンネルへ送る
+struct ChannelWriter {
+    sender: mpsc::UnboundedSender<String>,
+    buffer: Vec<u8>,
+}
+
+impl ChannelWriter {
+    fn new(sender: mpsc::UnboundedSender<String>) -> Self {
+        ChannelWriter { sender, buffer: Vec::new() }
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        while let Some(pos) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line[..pos]).to_string();
+            self.sender.send(line).map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
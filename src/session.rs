@@ -0,0 +1,107 @@
+//! 名前を付けて保存する会話セッション
+//!
+//! セッションごとに`<name>.json`として、会話履歴と作成日時、モデル、タイトルを保存します。
+//! 保存先は`--session-dir`で変更でき、既定では`$XDG_DATA_HOME/brain/sessions`（未設定時は`~/.local/share/brain/sessions`）です。
+
+use std::path::{Path, PathBuf};
+use chrono::Local;
+use ollama_rs::generation::chat::ChatMessage;
+use serde::{Deserialize, Serialize};
+
+
+type SessionResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Session {
+    /// 最初に保存した日時（RFC 3339）。上書きしても変わらない
+    pub created_at: String,
+    pub model: String,
+    pub title: Option<String>,
+    pub history: Vec<ChatMessage>,
+}
+
+
+pub struct SessionStore {
+    dir: PathBuf,
+}
+
+
+impl SessionStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        SessionStore { dir: dir.into() }
+    }
+
+    /// 既定の保存先を返します。ホームディレクトリが分からない場合はNoneを返します。
+    pub fn default_dir() -> Option<PathBuf> {
+        let data_home = match std::env::var_os("XDG_DATA_HOME").filter(|dir| !dir.is_empty()) {
+            Some(dir) => PathBuf::from(dir),
+            None => Path::new(&std::env::var_os("HOME")?).join(".local").join("share"),
+        };
+        Some(data_home.join("brain").join("sessions"))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// セッションを保存します。同じ名前のセッションがある場合は、作成日時を引き継いで上書きします。
+    pub fn save(&self, name: &str, model: &str, title: Option<&str>, history: &[ChatMessage]) -> SessionResult<()> {
+        let path = self.path(name)?;
+        let created_at = match self.load(name) {
+            Ok(session) => session.created_at,
+            Err(_) => Local::now().to_rfc3339(),
+        };
+        let session = Session {
+            created_at,
+            model: model.to_string(),
+            title: title.map(|title| title.to_string()),
+            history: history.to_vec(),
+        };
+
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(path, serde_json::to_string_pretty(&session)?)?;
+        Ok(())
+    }
+
+    pub fn load(&self, name: &str) -> SessionResult<Session> {
+        let path = self.path(name)?;
+        if !path.exists() {
+            return Err(format!("セッションが見つかりません: {}", name).into());
+        }
+        let json_data = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json_data)?)
+    }
+
+    /// 保存されているセッションを、名前の順に返します。読み込めないファイルは除きます。
+    pub fn list(&self) -> SessionResult<Vec<(String, Session)>> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut sessions = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            match self.load(name) {
+                Ok(session) => sessions.push((name.to_string(), session)),
+                Err(e) => log::warn!("failed to read session {}: {}", path.display(), e),
+            }
+        }
+        sessions.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(sessions)
+    }
+
+    /// セッション名からファイルのパスを作成する。保存先の外を指す名前はエラーにする
+    fn path(&self, name: &str) -> SessionResult<PathBuf> {
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(format!("セッション名に使用できない文字が含まれています: {}", name).into());
+        }
+        Ok(self.dir.join(format!("{}.json", name)))
+    }
+}
@@ -0,0 +1,43 @@
+//! システムプロンプトの合成
+//!
+//! 複数の指定がある場合は、次の順に空行で区切って1つのシステムメッセージにまとめます。
+//! 1. `--persona` で選択したペルソナ
+//! 2. `--system-file` で指定したファイルの内容
+//! 3. `--system-prompt` で指定した文字列
+
+
+/// 組み込みのペルソナ（名前, プロンプト）
+pub const PERSONAS: &[(&str, &str)] = &[
+    ("assistant", "あなたは親切で正確なアシスタントです。分からないことは分からないと答えてください。"),
+    ("coder", "あなたは熟練したソフトウェアエンジニアです。簡潔に説明し、コードはフェンス付きコードブロックで示してください。"),
+    ("translator", "あなたは翻訳者です。与えられた文章を、意味とニュアンスを保ったまま自然に翻訳してください。"),
+];
+
+
+pub fn compose_system_prompt(persona: Option<&str>, system_file: Option<&str>, system_prompt: Option<&str>) -> Result<Option<String>, String> {
+    let mut parts = Vec::new();
+
+    if let Some(persona) = persona {
+        let Some((_, prompt)) = PERSONAS.iter().find(|(name, _)| *name == persona) else {
+            let names: Vec<&str> = PERSONAS.iter().map(|(name, _)| *name).collect();
+            return Err(format!("不明なペルソナです: {} (利用可能: {})", persona, names.join(", ")));
+        };
+        parts.push(prompt.to_string());
+    }
+
+    if let Some(system_file) = system_file {
+        let content = std::fs::read_to_string(system_file)
+            .map_err(|e| format!("システムプロンプトのファイルを読み込めません: {}: {}", system_file, e))?;
+        parts.push(content.trim().to_string());
+    }
+
+    if let Some(system_prompt) = system_prompt {
+        parts.push(system_prompt.trim().to_string());
+    }
+
+    parts.retain(|part| !part.is_empty());
+    if parts.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(parts.join("\n\n")))
+}
@@ -0,0 +1,233 @@
+use std::{collections::HashMap, future::Future, pin::Pin, time::{Duration, Instant}};
+use ollama_rs::generation::{images::Image, tools::Tool};
+use schemars::r#gen::SchemaSettings;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::audit::AuditLog;
+
+
+pub type ToolResult = Result<ToolOutput, Box<dyn std::error::Error + Send + Sync>>;
+pub type ToolFuture<'a> = Pin<Box<dyn Future<Output = ToolResult> + 'a>>;
+
+
+/// ツールの実行結果
+#[derive(Debug, Clone, Default)]
+pub struct ToolOutput {
+    pub text: String,
+    /// base64でエンコードされた画像
+    pub images: Vec<Image>,
+}
+
+impl From<String> for ToolOutput {
+    fn from(text: String) -> Self {
+        ToolOutput { text, images: Vec::new() }
+    }
+}
+
+
+/// モデルへ渡すツールの定義
+#[derive(Debug, Clone)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    pub parameters: Value,
+}
+
+impl ToolDefinition {
+    /// Ollamaの`tools`に渡す形式に変換します。
+    pub fn to_json(&self) -> Value {
+        json!({
+            "type": "function",
+            "function": {
+                "name": self.name,
+                "description": self.description,
+                "parameters": self.parameters,
+            },
+        })
+    }
+}
+
+
+/// モデルがツールを呼び出した時の扱い
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolPolicy {
+    /// 確認せずに実行する
+    #[default]
+    Auto,
+    /// 実行する前にユーザーに確認する
+    Confirm,
+    /// 実行せず、拒否したことをモデルに返す
+    Deny,
+}
+
+
+/// 実行時に登録できるツール
+pub trait ToolHandler {
+    fn definition(&self) -> ToolDefinition;
+    /// ツールの提供元（監査ログに記録する）
+    fn source(&self) -> String {
+        "builtin".to_string()
+    }
+    /// 設定で上書きしていない場合の、呼び出された時の扱い
+    fn policy(&self) -> ToolPolicy {
+        ToolPolicy::Auto
+    }
+    fn call(&mut self, arguments: Value) -> ToolFuture<'_>;
+}
+
+
+/// `ollama_rs::function`で定義したツールを`ToolHandler`として扱うためのラッパー
+pub struct BuiltinTool<T: Tool>(pub T);
+
+impl<T: Tool> ToolHandler for BuiltinTool<T> {
+    fn definition(&self) -> ToolDefinition {
+        let mut settings = SchemaSettings::draft07();
+        settings.inline_subschemas = true;
+        let schema = settings.into_generator().into_root_schema_for::<T::Params>();
+
+        ToolDefinition {
+            name: T::name().to_string(),
+            description: T::description().to_string(),
+            parameters: serde_json::to_value(schema).unwrap_or_default(),
+        }
+    }
+
+    fn call(&mut self, arguments: Value) -> ToolFuture<'_> {
+        Box::pin(async move {
+            let parameters = serde_json::from_value(arguments)?;
+            self.0.call(parameters).await.map(ToolOutput::from)
+        })
+    }
+}
+
+
+pub struct ToolRegistry {
+    tools: Vec<Box<dyn ToolHandler>>,
+    audit_log: Option<AuditLog>,
+    /// ツールごとの扱いの設定（`--tool-policy`）。ツール自身の既定値より優先する
+    policies: HashMap<String, ToolPolicy>,
+    /// ツールの実行を待つ最大時間。Noneの場合は終了するまで待つ
+    timeout: Option<Duration>,
+    /// ユーザーに実行の確認を求められるか。求められない場合、`confirm`のツールは実行しない
+    interactive: bool,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        ToolRegistry {
+            tools: Vec::new(),
+            audit_log: None,
+            policies: HashMap::new(),
+            timeout: None,
+            interactive: true,
+        }
+    }
+
+    pub fn audit_log(mut self, audit_log: Option<AuditLog>) -> Self {
+        self.audit_log = audit_log;
+        self
+    }
+
+    pub fn policies(mut self, policies: HashMap<String, ToolPolicy>) -> Self {
+        self.policies = policies;
+        self
+    }
+
+    pub fn interactive(mut self, interactive: bool) -> Self {
+        self.interactive = interactive;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn add<T: ToolHandler + 'static>(mut self, tool: T) -> Self {
+        self.tools.push(Box::new(tool));
+        self
+    }
+
+    pub fn definitions(&self) -> Vec<ToolDefinition> {
+        self.tools.iter().map(|tool| tool.definition()).collect()
+    }
+
+    /// 登録されているツールの定義と提供元を返します。
+    pub fn definitions_with_source(&self) -> Vec<(ToolDefinition, String)> {
+        self.tools.iter().map(|tool| (tool.definition(), tool.source())).collect()
+    }
+
+    /// ツールが呼び出された時の扱いを返します。
+    pub fn policy(&self, name: &str) -> ToolPolicy {
+        if let Some(policy) = self.policies.get(name) {
+            return *policy;
+        }
+        self.tools.iter().find(|tool| tool.definition().name == name).map(|tool| tool.policy()).unwrap_or_default()
+    }
+
+    /// 設定に従ってツールの実行を許可するかを判断し、許可しない場合はモデルに返すメッセージを返します。
+    ///
+    /// `confirm`のツールは、標準入力からユーザーの確認を得た場合のみ実行を許可します。
+    /// 確認を求められない場合（WebSocketサーバーなど）は、確認せずに実行を拒否します。
+    pub async fn permit(&self, name: &str, arguments: &Value) -> Result<(), String> {
+        let denied = match self.policy(name) {
+            ToolPolicy::Auto => return Ok(()),
+            ToolPolicy::Confirm if !self.interactive => format!("ツール{}の実行にはユーザーの確認が必要ですが、確認できないため実行しませんでした。", name),
+            ToolPolicy::Confirm if crate::confirm(&format!("ツールを実行しますか: {} {}", name, arguments)).await => return Ok(()),
+            ToolPolicy::Confirm => "ユーザーによってツールの実行が拒否されました。".to_string(),
+            ToolPolicy::Deny => format!("ツール{}の実行は設定により禁止されています。", name),
+        };
+        if let Some(audit_log) = self.audit_log.as_ref() {
+            let source = self.tools.iter().find(|tool| tool.definition().name == name).map(|tool| tool.source()).unwrap_or_else(|| "unknown".to_string());
+            audit_log.record(name, &source, arguments, Err(&denied), Default::default());
+        }
+        Err(denied)
+    }
+
+    pub async fn call(&mut self, name: &str, arguments: Value) -> ToolResult {
+        let Some(tool) = self.tools.iter_mut().find(|tool| tool.definition().name == name) else {
+            let error = format!("不明なツールです: {}", name);
+            if let Some(audit_log) = self.audit_log.as_ref() {
+                audit_log.record(name, "unknown", &arguments, Err(&error), Default::default());
+            }
+            return Err(error.into());
+        };
+
+        let start = Instant::now();
+        // タイムアウトした呼び出しも監査ログに残るよう、ここで時間を制限する
+        let result = match self.timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, tool.call(arguments.clone())).await {
+                Ok(result) => result,
+                Err(_) => {
+                    log::warn!("tool {} timed out after {}s", name, timeout.as_secs());
+                    Err(format!("ツール{}が{}秒以内に終了しませんでした", name, timeout.as_secs()).into())
+                }
+            },
+            None => tool.call(arguments.clone()).await,
+        };
+        let Some(audit_log) = self.audit_log.as_ref() else {
+            return result;
+        };
+        match result.as_ref() {
+            Ok(output) => audit_log.record(name, &tool.source(), &arguments, Ok(output.text.len()), start.elapsed()),
+            Err(e) => audit_log.record(name, &tool.source(), &arguments, Err(&e.to_string()), start.elapsed()),
+        }
+        result
+    }
+}
+
+
+/// `--tool-policy`の値（`name=auto|confirm|deny`）を読み込む
+pub fn parse_tool_policy(value: &str) -> Result<(String, ToolPolicy), String> {
+    let Some((name, policy)) = value.split_once('=') else {
+        return Err(format!("invalid tool policy \"{}\" (use name=auto|confirm|deny)", value));
+    };
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(format!("invalid tool policy \"{}\" (tool name is empty)", value));
+    }
+    let policy = <ToolPolicy as clap::ValueEnum>::from_str(policy.trim(), true)
+        .map_err(|_| format!("invalid tool policy \"{}\" (use auto, confirm or deny)", policy))?;
+    Ok((name.to_string(), policy))
+}
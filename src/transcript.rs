@@ -0,0 +1,78 @@
+//! 会話の記録（JSON）
+//!
+//! `/export-json`で書き出し、`/import-json`で読み込みます。会話履歴に加えて、思考過程、
+//! メッセージを追加した日時、応答の生成中に呼び出したツールの引数と結果を保存します。
+//! ツールの引数は文字列にせず、JSONのまま保存します。
+//!
+//! ```json
+//! {
+//!   "exported_at": "2025-01-01T12:00:00+09:00",
+//!   "model": "qwen3:30b-a3b",
+//!   "title": "天気の確認",
+//!   "messages": [
+//!     { "role": "user", "content": "東京の天気は？", "timestamp": "2025-01-01T11:59:00+09:00" },
+//!     {
+//!       "role": "assistant",
+//!       "content": "晴れです。",
+//!       "thinking": "天気を調べる必要がある。",
+//!       "timestamp": "2025-01-01T11:59:05+09:00",
+//!       "tool_calls": [{ "name": "get_weather", "arguments": { "city": "東京" }, "result": "晴れ" }]
+//!     }
+//!   ]
+//! }
+//! ```
+
+use std::path::Path;
+use ollama_rs::generation::chat::MessageRole;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+
+type TranscriptResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transcript {
+    /// 書き出した日時（RFC 3339）
+    pub exported_at: String,
+    pub model: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    pub messages: Vec<TranscriptMessage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TranscriptMessage {
+    pub role: MessageRole,
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thinking: Option<String>,
+    /// メッセージを追加した日時（RFC 3339）。ファイルから読み込んだ履歴など、分からない場合はNone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+    /// この応答を生成する間に呼び出したツール
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<TranscriptToolCall>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TranscriptToolCall {
+    pub name: String,
+    pub arguments: Value,
+    /// ツールの結果。結果を受け取る前に中断した場合はNone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<String>,
+}
+
+
+impl Transcript {
+    pub fn save(&self, path: &Path) -> TranscriptResult<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> TranscriptResult<Self> {
+        let json_data = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json_data)?)
+    }
+}
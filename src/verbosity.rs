@@ -0,0 +1,50 @@
+//! 出力の詳しさ
+//!
+//! `--quiet`を指定した場合は、最終的な応答本文のみを標準出力に出力します。
+//! 思考過程、ツール呼び出しの表示、モデルやMCPサーバーの状況の通知、REPLのラベル、終了時の履歴は出力せず、
+//! 応答の生成中のエラーは標準エラー出力に出力します。
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// 応答本文のみを出力する
+    Quiet,
+    /// 応答本文に加えて、思考過程、ツール呼び出し、通知を出力する
+    Normal,
+}
+
+
+static LEVEL: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+
+/// 出力の詳しさを設定します。
+pub fn init(verbosity: Verbosity) {
+    LEVEL.store(verbosity as u8, Ordering::Relaxed);
+}
+
+/// 設定されている出力の詳しさを返します。
+pub fn get() -> Verbosity {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => Verbosity::Quiet,
+        _ => Verbosity::Normal,
+    }
+}
+
+/// 応答本文以外を出力しない設定かを返します。
+pub fn is_quiet() -> bool {
+    get() == Verbosity::Quiet
+}
+
+
+/// 応答本文以外の通知を標準出力に表示する。`--quiet`では表示しない
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if $crate::verbosity::get() >= $crate::verbosity::Verbosity::Normal {
+            println!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use status;